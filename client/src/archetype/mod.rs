@@ -0,0 +1,160 @@
+//! Turns archetype IDs from the server into local components.
+//!
+//! The table lives in archetypes.json, so adding a new kind of
+//! entity on the client doesn't need any code.
+
+use std::collections::HashMap;
+use serialize::json;
+use shared::{ComponentStore, EntityComponent, EntityHandle};
+use shared::component::components::ArchetypeId;
+use interpolation::InterpolatedComponent;
+use renderer::RenderComponent;
+use sound::SoundEmitterComponent;
+
+static DEFAULT_TABLE: &'static str = include_str!("../../archetypes.json");
+
+#[deriving(Decodable)]
+pub struct ModelDef {
+    pub color: (f32, f32, f32),
+    pub scale: f32
+}
+
+#[deriving(Decodable)]
+pub struct ArchetypeDef {
+    pub id: ArchetypeId,
+    pub name: String,
+    pub model: Option<ModelDef>,
+    pub sound: Option<String>,
+    pub interpolate: bool
+}
+
+/// The client-only components an entity can have.
+pub struct LocalComponents {
+    pub renderables: ComponentStore<RenderComponent>,
+    pub sounds: ComponentStore<SoundEmitterComponent>,
+    pub interpolated: ComponentStore<InterpolatedComponent>
+}
+
+impl LocalComponents {
+    pub fn new() -> LocalComponents {
+        LocalComponents {
+            renderables: ComponentStore::new(),
+            sounds: ComponentStore::new(),
+            interpolated: ComponentStore::new()
+        }
+    }
+
+    /// Removes every local component belonging to `entity`.
+    pub fn remove_entity(&mut self, entity: EntityHandle) {
+        let renderables: Vec<_> = self.renderables.iter()
+            .filter(|&(_, renderable)| renderable.entity == entity)
+            .map(|(handle, _)| handle)
+            .collect();
+        for handle in renderables.into_iter() {
+            self.renderables.remove(handle);
+        }
+
+        let sounds: Vec<_> = self.sounds.iter()
+            .filter(|&(_, sound)| sound.entity == entity)
+            .map(|(handle, _)| handle)
+            .collect();
+        for handle in sounds.into_iter() {
+            self.sounds.remove(handle);
+        }
+
+        let interpolated: Vec<_> = self.interpolated.iter()
+            .filter(|&(_, interp)| interp.entity == entity)
+            .map(|(handle, _)| handle)
+            .collect();
+        for handle in interpolated.into_iter() {
+            self.interpolated.remove(handle);
+        }
+    }
+
+    /// Removes components whose entity is gone.
+    /// (The renderer cleans up renderables itself.)
+    pub fn remove_dead(&mut self, entities: &ComponentStore<EntityComponent>) {
+        let dead: Vec<_> = self.sounds.iter()
+            .filter(|&(_, sound)| entities.find(sound.entity).is_none())
+            .map(|(handle, _)| handle)
+            .collect();
+        for handle in dead.into_iter() {
+            self.sounds.remove(handle);
+        }
+
+        let dead: Vec<_> = self.interpolated.iter()
+            .filter(|&(_, interp)| entities.find(interp.entity).is_none())
+            .map(|(handle, _)| handle)
+            .collect();
+        for handle in dead.into_iter() {
+            self.interpolated.remove(handle);
+        }
+    }
+}
+
+pub struct ArchetypeTable {
+    archetypes: HashMap<ArchetypeId, ArchetypeDef>
+}
+
+impl ArchetypeTable {
+    /// The table that ships with the client.
+    pub fn load_default() -> ArchetypeTable {
+        match ArchetypeTable::from_json(DEFAULT_TABLE) {
+            Ok(table) => table,
+            Err(e) => fail!("Bad builtin archetype table: {}", e)
+        }
+    }
+
+    pub fn from_json(s: &str) -> Result<ArchetypeTable, String> {
+        let defs: Vec<ArchetypeDef> = match json::decode(s) {
+            Ok(defs) => defs,
+            Err(e) => return Err(format!("{}", e))
+        };
+        let mut archetypes = HashMap::new();
+        for def in defs.into_iter() {
+            if archetypes.contains_key(&def.id) {
+                return Err(format!("Archetype {} defined twice", def.id));
+            }
+            archetypes.insert(def.id, def);
+        }
+        Ok(ArchetypeTable { archetypes: archetypes })
+    }
+
+    pub fn get(&self, id: ArchetypeId) -> Option<&ArchetypeDef> {
+        self.archetypes.find(&id)
+    }
+
+    /// Gives a newly-replicated entity the components its archetype calls for,
+    /// replacing any it had from a previous archetype.
+    /// Unknown archetypes get drawn as a plain debug box, so they're at least visible.
+    pub fn spawn(&self, ent: &EntityComponent, local: &mut LocalComponents) {
+        local.remove_entity(ent.handle);
+
+        let def = match self.get(ent.archetype) {
+            Some(def) => def,
+            None => {
+                println!("Unknown archetype {}!", ent.archetype);
+                local.renderables.add(RenderComponent { entity: ent.handle, color: [1.0, 0.0, 1.0], scale: 1.0 });
+                local.interpolated.add(InterpolatedComponent { entity: ent.handle });
+                return;
+            }
+        };
+
+        match def.model {
+            Some(ref model) => {
+                let (r, g, b) = model.color;
+                local.renderables.add(RenderComponent { entity: ent.handle, color: [r, g, b], scale: model.scale });
+            },
+            None => ()
+        }
+        match def.sound {
+            Some(ref sound) => {
+                local.sounds.add(SoundEmitterComponent { entity: ent.handle, sound: sound.clone() });
+            },
+            None => ()
+        }
+        if def.interpolate {
+            local.interpolated.add(InterpolatedComponent { entity: ent.handle });
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use shared::TICK_LENGTH;
+use shared::network::commands_per_packet;
+
+/// If our estimate is off by more than this many ticks, jump instead of drifting.
+static SNAP_THRESHOLD: f64 = 32.;
+/// The most we'll speed up or slow down our clock while drifting toward the server's.
+static MAX_DRIFT: f64 = 0.05;
+/// Roughly how long (in seconds) we try to take to correct a clock error.
+static CORRECTION_TIME: f64 = 1.;
+
+/// Estimates which tick the server is on, and paces the client's
+/// fixed-tick command loop to match.
+///
+/// Small errors are corrected by running our clock slightly fast or slow,
+/// so the estimate never jumps backwards unless it's badly wrong.
+pub struct ServerClock {
+    /// Our estimate of the server's current tick, as of last_time.
+    estimated_tick: f64,
+    last_time: f64,
+    /// Ticks of server time per tick of local time.
+    rate: f64,
+    /// Smoothed difference between where the server is and where we think it is.
+    smoothed_error: f64,
+    synced: bool,
+
+    last_command_tick: u64,
+    /// How far behind we'll let our command ticks fall before skipping ahead.
+    /// As many as fit in a packet, so caught-up commands all get sent.
+    max_catchup: u64
+}
+
+impl ServerClock {
+    /// `command_interval` is the number of ticks between our command packets,
+    /// as the server agreed to at signon.
+    pub fn new(command_interval: u32) -> ServerClock {
+        ServerClock {
+            estimated_tick: 0.,
+            last_time: 0.,
+            rate: 1.,
+            smoothed_error: 0.,
+            synced: false,
+
+            last_command_tick: 0,
+            max_catchup: commands_per_packet(command_interval) as u64
+        }
+    }
+
+    fn advance(&mut self, now: f64) {
+        if self.synced {
+            self.estimated_tick += (now - self.last_time) / (TICK_LENGTH as f64) * self.rate;
+        }
+        self.last_time = now;
+    }
+
+    /// Takes a snapshot from the server, made on `tick`,
+    /// received at local time `now` (in seconds), with round-trip time `rtt`.
+    pub fn update(&mut self, tick: u64, now: f64, rtt: f64) {
+        self.advance(now);
+
+        // By the time we get it, the server's half a round trip further along.
+        let target = tick as f64 + (rtt / 2.) / (TICK_LENGTH as f64);
+        let error = target - self.estimated_tick;
+
+        if !self.synced || error.abs() > SNAP_THRESHOLD {
+            self.estimated_tick = target;
+            self.rate = 1.;
+            self.smoothed_error = 0.;
+            self.last_command_tick = target as u64;
+            self.synced = true;
+            return;
+        }
+
+        self.smoothed_error = 0.9 * self.smoothed_error + 0.1 * error;
+        let correction = self.smoothed_error * (TICK_LENGTH as f64) / CORRECTION_TIME;
+        self.rate = 1. + correction.max(-MAX_DRIFT).min(MAX_DRIFT);
+    }
+
+    /// Our estimate of the server's tick at local time `now`, with fractions.
+    pub fn current_tick(&mut self, now: f64) -> f64 {
+        self.advance(now);
+        self.estimated_tick
+    }
+
+    /// Returns the next tick a command is due for, if any.
+    /// Call it until it returns None each frame.
+    pub fn next_command_tick(&mut self, now: f64) -> Option<u64> {
+        if !self.synced {
+            return None;
+        }
+        let current = self.current_tick(now) as u64;
+        if current <= self.last_command_tick {
+            return None;
+        }
+        if current - self.last_command_tick > self.max_catchup {
+            // Hitched badly. Those ticks are gone.
+            self.last_command_tick = current - self.max_catchup;
+        }
+        self.last_command_tick += 1;
+        Some(self.last_command_tick)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use shared::TICK_LENGTH;
+    use shared::network::commands_per_packet;
+    use super::{ServerClock, SNAP_THRESHOLD, MAX_DRIFT};
+
+    fn seconds(ticks: f64) -> f64 {
+        ticks * TICK_LENGTH as f64
+    }
+
+    #[test]
+    fn snaps_when_far_off() {
+        let mut clock = ServerClock::new(1);
+        assert!(clock.next_command_tick(0.).is_none()); // not synced yet
+        clock.update(100, 0., 0.);
+        assert_eq!(clock.current_tick(0.), 100.);
+
+        // way ahead of where we thought: jump straight there
+        let far = 100 + SNAP_THRESHOLD as u64 * 2;
+        clock.update(far, 0., 0.);
+        assert_eq!(clock.current_tick(0.), far as f64);
+
+        // and half the round trip is accounted for
+        clock.update(1000, 0., seconds(20.));
+        assert_eq!(clock.current_tick(0.), 1010.);
+    }
+
+    #[test]
+    fn drifts_when_close() {
+        let mut clock = ServerClock::new(1);
+        clock.update(100, 0., 0.);
+        // the server's a couple of ticks ahead of us
+        let mut last = clock.current_tick(0.);
+        for i in range(1u64, 64) {
+            clock.update(100 + i + 2, seconds(i as f64), 0.);
+            let now = clock.current_tick(seconds(i as f64));
+            // never jumps, just runs a little fast
+            assert!(now > last);
+            assert!(now - last <= 1. + MAX_DRIFT + 1e-6);
+            last = now;
+        }
+        assert!(last > 100. + 63.);
+        assert!(clock.rate > 1.);
+    }
+
+    #[test]
+    fn catches_up_a_packet_at_most() {
+        for &interval in [1u32, 4].iter() {
+            let mut clock = ServerClock::new(interval);
+            clock.update(100, 0., 0.);
+            assert!(clock.next_command_tick(0.).is_none());
+
+            // hitch for 50 ticks
+            let now = seconds(50.);
+            let mut ticks = Vec::new();
+            loop {
+                match clock.next_command_tick(now) {
+                    Some(tick) => ticks.push(tick),
+                    None => break
+                }
+            }
+            assert_eq!(ticks.len(), commands_per_packet(interval));
+            assert_eq!(*ticks.last().unwrap(), 150);
+        }
+    }
+}
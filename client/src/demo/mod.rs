@@ -0,0 +1,267 @@
+//! Playing back demos recorded by the client.
+//!
+//! Playback handles the recorded messages exactly like a live game
+//! would, so seeking backwards means starting over from the beginning
+//! and fast-forwarding.
+
+use cgmath::{Point3, Rotation3, rad};
+use shared::{EntityComponent, EntityHandle};
+use shared::component::components::PLAYER_ARCHETYPE;
+use shared::network::{decode_server_msg, Signon, Update};
+use shared::network::demo::DemoFrame;
+use shared::network::replication::ReplicationRegistry;
+use shared::world::World;
+use shared::TICK_LENGTH;
+use archetype::{ArchetypeTable, LocalComponents};
+use interpolation::Interpolator;
+
+pub struct DemoPlayer {
+    frames: Vec<DemoFrame>,
+    next_frame: uint,
+    time: f64,
+    /// 1 is normal speed.
+    pub speed: f64,
+    pub paused: bool,
+
+    archetypes: ArchetypeTable,
+    world: World,
+    replication: ReplicationRegistry,
+    local: LocalComponents,
+    interpolator: Interpolator,
+    /// Whoever recorded the demo.
+    viewpoint: Option<EntityHandle>,
+    /// The newest update's tick, and when it arrived.
+    last_update: Option<(u64, f64)>,
+    interpolation_delay: f64,
+    max_extrapolation: f64
+}
+
+impl DemoPlayer {
+    /// `interpolation_delay` is the least delay used; the snapshot rate the
+    /// demo was recorded at may call for more.
+    pub fn new(frames: Vec<DemoFrame>, interpolation_delay: f64, max_extrapolation: f64) -> DemoPlayer {
+        let mut player = DemoPlayer {
+            frames: frames,
+            next_frame: 0,
+            time: 0.,
+            speed: 1.,
+            paused: false,
+            archetypes: ArchetypeTable::load_default(),
+            world: World::new(),
+            replication: ReplicationRegistry::with_default_components(),
+            local: LocalComponents::new(),
+            interpolator: Interpolator::new(interpolation_delay, max_extrapolation),
+            viewpoint: None,
+            last_update: None,
+            interpolation_delay: interpolation_delay,
+            max_extrapolation: max_extrapolation
+        };
+        player.seek(0.);
+        player
+    }
+
+    /// Throws away everything, back to before the first frame.
+    fn restart(&mut self) {
+        self.next_frame = 0;
+        self.time = 0.;
+        self.world = World::new();
+        self.replication = ReplicationRegistry::with_default_components();
+        self.local = LocalComponents::new();
+        self.interpolator = Interpolator::new(self.interpolation_delay, self.max_extrapolation);
+        self.viewpoint = None;
+        self.last_update = None;
+    }
+
+    fn apply_frame(&mut self, idx: uint) {
+        let time = self.frames[idx].time;
+        match decode_server_msg(self.frames[idx].data.as_slice()) {
+            Some(Signon(signon)) => {
+                if self.viewpoint.is_none() {
+                    let viewpoint = EntityComponent::new(&mut self.world.entities, PLAYER_ARCHETYPE, Point3::new(0., 0., 0.),
+                        Rotation3::from_euler(rad(0.), rad(0.), rad(0.)));
+                    match signon.handle {
+                        Some(handle) => { self.replication.entity_handles().insert(handle, viewpoint); },
+                        None => ()
+                    }
+                    self.viewpoint = Some(viewpoint);
+                }
+                // Same as the live game: at least a couple of updates to interpolate between.
+                let snapshot_length = signon.snapshot_interval as f64 * TICK_LENGTH as f64;
+                self.interpolator = Interpolator::new(self.interpolation_delay.max(2. * snapshot_length), self.max_extrapolation);
+            },
+            Some(Update(update)) => {
+                match update.all_entities {
+                    Some(ref all) => self.replication.retain_entities(all.as_slice(), &mut self.world),
+                    None => ()
+                }
+                for handle in self.replication.apply_update(update.component_updates, &mut self.world).into_iter() {
+                    self.archetypes.spawn(self.world.entities.find(handle).unwrap(), &mut self.local);
+                }
+                self.local.remove_dead(&self.world.entities);
+                self.interpolator.record(update.tick, &self.local.interpolated, &self.world.entities);
+                self.last_update = Some((update.tick, time));
+            },
+            Some(_) => (),
+            None => println!("Garbage message in demo at {:.2}s", time)
+        }
+    }
+
+    /// Plays every frame up to the current time.
+    fn catch_up(&mut self) {
+        while self.next_frame < self.frames.len() && self.frames[self.next_frame].time <= self.time {
+            let idx = self.next_frame;
+            self.apply_frame(idx);
+            self.next_frame += 1;
+        }
+    }
+
+    /// Moves playback forward by `dt` real seconds.
+    pub fn advance(&mut self, dt: f64) {
+        if !self.paused {
+            let time = self.time + dt * self.speed;
+            self.time = time.min(self.get_length());
+            self.catch_up();
+        }
+    }
+
+    /// Jumps to `time` seconds into the demo.
+    pub fn seek(&mut self, time: f64) {
+        let time = time.max(0.).min(self.get_length());
+        if time < self.time {
+            self.restart();
+        }
+        self.time = time;
+        self.catch_up();
+    }
+
+    pub fn get_time(&self) -> f64 {
+        self.time
+    }
+
+    pub fn get_length(&self) -> f64 {
+        self.frames.last().map(|frame| frame.time).unwrap_or(0.)
+    }
+
+    pub fn get_viewpoint(&self) -> Option<EntityHandle> {
+        self.viewpoint
+    }
+
+    pub fn get_local_mut(&mut self) -> &mut LocalComponents {
+        &mut self.local
+    }
+
+    /// Where the server would have been when the demo was at this point.
+    fn current_tick(&self) -> f64 {
+        match self.last_update {
+            Some((tick, time)) => tick as f64 + (self.time - time) / (TICK_LENGTH as f64),
+            None => 0.
+        }
+    }
+
+    /// Puts what should be drawn right now into `display`.
+    pub fn display(&self, display: &mut World) {
+        display.clone_from(&self.world);
+        self.interpolator.apply(self.current_tick(), &mut display.entities);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cgmath::{Point3, Quaternion};
+    use shared::EntityComponent;
+    use shared::component::components::{BLOCK_ARCHETYPE, PLAYER_ARCHETYPE};
+    use shared::network::{encode_server_msg, ServerToClient, Signon, SignonPacket, Update, UpdatePacket};
+    use shared::network::delta::RelevanceHistory;
+    use shared::network::demo::DemoFrame;
+    use shared::network::replication::{ReplicationRegistry, MAX_STATES};
+    use shared::world::World;
+    use super::DemoPlayer;
+
+    fn frame(time: f64, msg: &ServerToClient) -> DemoFrame {
+        DemoFrame { time: time, data: encode_server_msg(msg) }
+    }
+
+    /// Two seconds of a block moving one unit every tenth of a second.
+    fn demo() -> Vec<DemoFrame> {
+        let mut world = World::new();
+        let rot = Quaternion::new(1., 0., 0., 0.);
+        let player = EntityComponent::new(&mut world.entities, PLAYER_ARCHETYPE, Point3::new(0., 0., 0.), rot);
+        let block = EntityComponent::new(&mut world.entities, BLOCK_ARCHETYPE, Point3::new(0., 0., 0.), rot);
+        let mut replication = ReplicationRegistry::with_default_components();
+        let mut relevance = RelevanceHistory::new(MAX_STATES);
+
+        let mut frames = vec![frame(0., &Signon(SignonPacket {
+            tick: 0,
+            handle: Some(player.to_raw()),
+            snapshot_interval: 1,
+            command_interval: 1
+        }))];
+        for i in range(1u, 21) {
+            world.entities.find_mut(block).unwrap().pos.x = i as f32;
+            replication.add_state(&world);
+            relevance.push(world.entities.iter().map(|(h, _)| h.to_raw()).collect());
+            frames.push(frame(i as f64 * 0.1, &Update(UpdatePacket {
+                tick: i as u64,
+                last_command: 0,
+                component_updates: replication.create_update(1, &relevance),
+                all_entities: None
+            })));
+        }
+        frames
+    }
+
+    fn block_x(player: &DemoPlayer) -> Option<f32> {
+        player.world.entities.iter()
+            .find(|&(_, ent)| ent.archetype == BLOCK_ARCHETYPE)
+            .map(|(_, ent)| ent.pos.x)
+    }
+
+    #[test]
+    fn seek_forwards_and_back() {
+        let mut player = DemoPlayer::new(demo(), 0.1, 0.25);
+        assert!(player.get_viewpoint().is_some());
+        assert_eq!(block_x(&player), None);
+        assert_eq!(player.get_length(), 2.);
+
+        player.seek(1.05);
+        assert_eq!(block_x(&player), Some(10.));
+
+        // backwards starts over, and ends up in the same state as playing through
+        player.seek(0.35);
+        assert_eq!(player.get_time(), 0.35);
+        assert_eq!(block_x(&player), Some(3.));
+        assert!(player.get_viewpoint().is_some());
+
+        // and seeking is clamped to the demo
+        player.seek(100.);
+        assert_eq!(player.get_time(), 2.);
+        assert_eq!(block_x(&player), Some(20.));
+    }
+
+    #[test]
+    fn restart_forgets_everything() {
+        let mut player = DemoPlayer::new(demo(), 0.1, 0.25);
+        player.seek(1.05);
+
+        player.seek(-1.);
+        assert_eq!(player.get_time(), 0.);
+        // only the signon's been played again
+        assert_eq!(block_x(&player), None);
+        assert!(player.last_update.is_none());
+        assert!(player.get_viewpoint().is_some());
+        assert_eq!(player.world.entities.iter().count(), 1);
+    }
+
+    #[test]
+    fn advance_respects_pause_and_speed() {
+        let mut player = DemoPlayer::new(demo(), 0.1, 0.25);
+        player.speed = 2.;
+        player.advance(0.25);
+        assert_eq!(player.get_time(), 0.5);
+        assert_eq!(block_x(&player), Some(5.));
+
+        player.paused = true;
+        player.advance(1.);
+        assert_eq!(player.get_time(), 0.5);
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::{Deque, HashMap, HashSet, RingBuf};
+use cgmath::{Point, Point3, Quaternion, Vector};
+use shared::{ComponentStore, EntityComponent, EntityHandle};
+use shared::TICK_LENGTH;
+
+/// How many snapshots we keep for each entity.
+static MAX_SNAPSHOTS: uint = 32;
+
+struct Snapshot {
+    tick: f64,
+    pos: Point3<f32>,
+    rot: Quaternion<f32>
+}
+
+/// Marks an entity to be drawn between snapshots rather than snapping to each one.
+pub struct InterpolatedComponent {
+    pub entity: EntityHandle
+}
+
+/// Keeps a history of where remote entities were, and draws them
+/// a little in the past so there's always something to interpolate between.
+///
+/// If snapshots stop arriving, entities are extrapolated along their
+/// last known velocity for a short while, then held still.
+pub struct Interpolator {
+    snapshots: HashMap<EntityHandle, RingBuf<Snapshot>>,
+    /// How far in the past remote entities are drawn, in ticks.
+    pub delay: f64,
+    /// How far past the newest snapshot we'll extrapolate, in ticks.
+    pub max_extrapolation: f64
+}
+
+impl Interpolator {
+    /// `delay` and `max_extrapolation` are in seconds.
+    pub fn new(delay: f64, max_extrapolation: f64) -> Interpolator {
+        Interpolator {
+            snapshots: HashMap::new(),
+            delay: delay / (TICK_LENGTH as f64),
+            max_extrapolation: max_extrapolation / (TICK_LENGTH as f64)
+        }
+    }
+
+    /// Records where every interpolated entity was on server tick `tick`.
+    pub fn record(&mut self, tick: u64,
+                  interpolated: &ComponentStore<InterpolatedComponent>,
+                  entities: &ComponentStore<EntityComponent>) {
+        let mut alive = HashSet::new();
+        for (_, interp) in interpolated.iter() {
+            let handle = interp.entity;
+            let ent = match entities.find(handle) {
+                Some(ent) => ent,
+                None => continue
+            };
+            alive.insert(handle);
+            if !self.snapshots.contains_key(&handle) {
+                self.snapshots.insert(handle, RingBuf::new());
+            }
+            let history = self.snapshots.find_mut(&handle).unwrap();
+
+            if history.back().map(|snap| snap.tick >= tick as f64).unwrap_or(false) {
+                continue;
+            }
+            history.push(Snapshot { tick: tick as f64, pos: ent.pos, rot: ent.rot });
+            while history.len() > MAX_SNAPSHOTS {
+                history.pop_front();
+            }
+        }
+
+        // forget entities that have been destroyed
+        let dead: Vec<EntityHandle> = self.snapshots.keys()
+            .filter(|&handle| !alive.contains(handle))
+            .map(|&handle| handle)
+            .collect();
+        for handle in dead.iter() {
+            self.snapshots.remove(handle);
+        }
+    }
+
+    /// Moves remote entities to where they were `delay` ticks before `server_tick`.
+    pub fn apply(&self, server_tick: f64, entities: &mut ComponentStore<EntityComponent>) {
+        let render_tick = server_tick - self.delay;
+
+        for (&handle, history) in self.snapshots.iter() {
+            let ent = match entities.find_mut(handle) {
+                Some(ent) => ent,
+                None => continue
+            };
+            match self.sample(history, render_tick) {
+                Some((pos, rot)) => {
+                    ent.pos = pos;
+                    ent.rot = rot;
+                },
+                None => ()
+            }
+        }
+    }
+
+    fn sample(&self, history: &RingBuf<Snapshot>, tick: f64) -> Option<(Point3<f32>, Quaternion<f32>)> {
+        let newest = match history.back() {
+            Some(newest) => newest,
+            None => return None
+        };
+        let oldest = history.front().unwrap();
+
+        if tick <= oldest.tick {
+            return Some((oldest.pos, oldest.rot));
+        }
+
+        if tick >= newest.tick {
+            // extrapolate along the last known velocity
+            if history.len() < 2 {
+                return Some((newest.pos, newest.rot));
+            }
+            let prev = &history[history.len() - 2];
+            let velocity = newest.pos.sub_p(&prev.pos).div_s((newest.tick - prev.tick) as f32);
+            let ahead = (tick - newest.tick).min(self.max_extrapolation);
+            return Some((newest.pos.add_v(&velocity.mul_s(ahead as f32)), newest.rot));
+        }
+
+        for i in range(0, history.len() - 1) {
+            let (a, b) = (&history[i], &history[i + 1]);
+            if a.tick <= tick && tick <= b.tick {
+                let t = ((tick - a.tick) / (b.tick - a.tick)) as f32;
+                let pos = a.pos.add_v(&b.pos.sub_p(&a.pos).mul_s(t));
+                return Some((pos, a.rot.slerp(&b.rot, t)));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::RingBuf;
+    use cgmath::{Point3, Quaternion};
+    use shared::TICK_LENGTH;
+    use super::{Interpolator, Snapshot};
+
+    fn snapshot(tick: f64, x: f32) -> Snapshot {
+        Snapshot { tick: tick, pos: Point3::new(x, 0., 0.), rot: Quaternion::new(1., 0., 0., 0.) }
+    }
+
+    fn history() -> RingBuf<Snapshot> {
+        let mut history = RingBuf::new();
+        history.push(snapshot(10., 0.));
+        history.push(snapshot(20., 10.));
+        history
+    }
+
+    #[test]
+    fn lerps_between_snapshots() {
+        let interp = Interpolator::new(0., 0.);
+        let history = history();
+        assert_eq!(interp.sample(&history, 15.).unwrap().val0(), Point3::new(5., 0., 0.));
+        assert_eq!(interp.sample(&history, 12.5).unwrap().val0(), Point3::new(2.5, 0., 0.));
+        // before the oldest snapshot, we just wait there
+        assert_eq!(interp.sample(&history, 5.).unwrap().val0(), Point3::new(0., 0., 0.));
+    }
+
+    #[test]
+    fn extrapolation_clamped() {
+        // four ticks of extrapolation at most
+        let interp = Interpolator::new(0., 4. * TICK_LENGTH as f64);
+        let history = history();
+        // one unit per tick
+        assert_eq!(interp.sample(&history, 22.).unwrap().val0(), Point3::new(12., 0., 0.));
+        assert_eq!(interp.sample(&history, 24.).unwrap().val0(), Point3::new(14., 0., 0.));
+        // then held still
+        assert_eq!(interp.sample(&history, 100.).unwrap().val0(), Point3::new(14., 0., 0.));
+
+        // a single snapshot has no velocity to go on
+        let mut single = RingBuf::new();
+        single.push(snapshot(10., 3.));
+        assert_eq!(interp.sample(&single, 30.).unwrap().val0(), Point3::new(3., 0., 0.));
+    }
+}
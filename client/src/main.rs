@@ -5,7 +5,6 @@ extern crate gfx;
 #[phase(plugin)]
 extern crate gfx_macros;
 extern crate glfw;
-extern crate flate;
 extern crate native;
 extern crate serialize;
 extern crate shared;
@@ -18,21 +17,33 @@ use cgmath::Vector;
 use cgmath::ToRad;
 use cgmath::rad;
 use glfw::Context;
-use renderer::RenderComponent;
 use shared::EntityComponent;
-use serialize::json;
+use shared::component::components::PLAYER_ARCHETYPE;
 
-use shared::component::ComponentStore;
 use shared::network::channel::NetChannel;
-
+use shared::network::demo::{DemoWriter, read_demo};
+use shared::network::replay::read_replay;
+use shared::network::simulator::{NetConditions, SimulatedTransport};
+use shared::network::transport::Transport;
+use shared::network::replication::ReplicationRegistry;
+
+use std::collections::{Deque, RingBuf};
+use std::io::{BufferedReader, BufferedWriter, File, IoResult};
 use std::io::net::ip::{Ipv4Addr, SocketAddr};
-use std::io::net::udp::{UdpSocket, UdpStream};
+use std::io::net::udp::UdpSocket;
 
-use shared::network::{ServerToClient, Signon, Update, SignonPacket};
+use shared::network::{Challenge, Disconnected, PlayerLeft, Reject, Signon, Update, SignonPacket};
+use shared::network::{decode_server_msg, encode_client_msg};
 
+mod archetype;
+mod clock;
+mod demo;
 mod input;
+mod interpolation;
 mod renderer;
 mod prediction;
+mod replay;
+mod sound;
 
 // A weird hack to get arguments to the linker.
 /*#[cfg(target_family="windows")]
@@ -41,6 +52,17 @@ mod prediction;
   extern {}
   }*/
 
+/// How far in the past remote entities are drawn, in seconds.
+static INTERPOLATION_DELAY: f64 = 0.1;
+/// How long we'll guess where remote entities are going without hearing from the server, in seconds.
+static MAX_EXTRAPOLATION: f64 = 0.25;
+/// How long to wait for servers to answer queries, in seconds.
+static QUERY_TIME: f64 = 1.;
+/// How far the arrow keys seek during demo and replay playback, in seconds.
+static DEMO_SEEK_STEP: f64 = 5.;
+
+type DemoRecorder = DemoWriter<BufferedWriter<File>>;
+
 // We need to run on the main thread for GLFW, so ensure we are using the `native` runtime. This is
 // technically not needed, since this is the default, but it's not guaranteed.
 #[start]
@@ -49,36 +71,197 @@ fn start(argc: int, argv: *const *const u8) -> int {
 }
 
 fn main() {
-    connect(SocketAddr {
-        ip: Ipv4Addr(162,243,139,73),
-        port: 18295
-    }, 10)
+    match std::os::getenv("NMIGP_PLAY") {
+        Some(path) => {
+            play_demo(&Path::new(path));
+            return;
+        },
+        None => ()
+    }
+    // e.g. NMIGP_WATCH=match.rep to watch a replay the server recorded
+    match std::os::getenv("NMIGP_WATCH") {
+        Some(path) => {
+            watch_replay(&Path::new(path));
+            return;
+        },
+        None => ()
+    }
+
+    let name = std::os::getenv("NMIGP_NAME").unwrap_or_else(|| "Player".to_string());
+    let rates = Rates {
+        rate: env_or("NMIGP_RATE", shared::network::DEFAULT_RATE),
+        snapshot_rate: env_or("NMIGP_SNAPSHOT_RATE", shared::network::DEFAULT_SNAPSHOT_RATE),
+        command_rate: env_or("NMIGP_COMMAND_RATE", shared::network::DEFAULT_COMMAND_RATE)
+    };
+
+    // e.g. NMIGP_RELAY=127.0.0.1:18296 NMIGP_SPECTATOR_SECRET=hunter2 to watch
+    // through a relay. Only relays get the secret: a game server would let us
+    // in as a spectator, with no player of our own to move around.
+    let relay: Option<SocketAddr> = std::os::getenv("NMIGP_RELAY").map(|addr| from_str(addr.as_slice()).expect("Bad NMIGP_RELAY"));
+    let spectator_secret = relay.and(std::os::getenv("NMIGP_SPECTATOR_SECRET"));
+
+    let demo = std::os::getenv("NMIGP_RECORD").map(|path| {
+        let file = File::create(&Path::new(path.as_slice())).unwrap();
+        println!("Recording demo to {}", path);
+        DemoWriter::new(BufferedWriter::new(file), time::precise_time_s()).unwrap()
+    });
+
+    let mut socket = UdpSocket::bind(SocketAddr { ip: Ipv4Addr(0,0,0,0), port: 0}).unwrap();
+
+    // e.g. NMIGP_SERVER=127.0.0.1:18295, NMIGP_LAN=1 to look for one on the LAN,
+    // or NMIGP_MASTER=127.0.0.1:18290 to look for one through a master server
+    let serveraddr = match std::os::getenv("NMIGP_SERVER") {
+        _ if relay.is_some() => relay.unwrap(),
+        Some(addr) => from_str(addr.as_slice()).expect("Bad NMIGP_SERVER"),
+        None if std::os::getenv("NMIGP_LAN").is_some() => match find_lan_server(&mut socket) {
+            Ok(Some(addr)) => addr,
+            Ok(None) => fail!("Couldn't find any servers on the LAN"),
+            Err(e) => fail!("Couldn't look for servers on the LAN: {}", e)
+        },
+        None if std::os::getenv("NMIGP_MASTER").is_some() => {
+            let master = from_str(std::os::getenv("NMIGP_MASTER").unwrap().as_slice()).expect("Bad NMIGP_MASTER");
+            match find_listed_server(&mut socket, master) {
+                Some(addr) => addr,
+                None => fail!("Couldn't find any servers through {}", master)
+            }
+        },
+        None => SocketAddr {
+            ip: Ipv4Addr(162,243,139,73),
+            port: shared::network::DEFAULT_PORT
+        }
+    };
+
+    // e.g. NMIGP_NETSIM="latency=0.1,loss=0.05" to try out a bad connection
+    match std::os::getenv("NMIGP_NETSIM") {
+        Some(spec) => {
+            let conditions = NetConditions::parse(spec.as_slice()).expect("Bad NMIGP_NETSIM");
+            println!("Simulating network conditions: {}", conditions);
+            connect(&mut SimulatedTransport::new(socket, conditions), serveraddr, name.as_slice(), rates, spectator_secret, demo, 10)
+        },
+        None => connect(&mut socket, serveraddr, name.as_slice(), rates, spectator_secret, demo, 10)
+    }
+}
+
+/// Asks every server on the LAN about itself.
+fn find_lan_server(socket: &mut Transport) -> IoResult<Option<SocketAddr>> {
+    use shared::network::query::{collect_replies, lan_broadcast_addr, send_query};
+
+    try!(socket.set_broadcast(true));
+    try!(send_query(socket, lan_broadcast_addr(shared::network::DEFAULT_PORT)));
+    Ok(pick_server(try!(collect_replies(socket, QUERY_TIME))))
 }
 
-fn connect(serveraddr: SocketAddr, mut retries: u32) {
-    let mut stream = UdpSocket::bind(SocketAddr { ip: Ipv4Addr(0,0,0,0), port: 0}).unwrap().connect(serveraddr);
+/// Asks every server the master lists about itself.
+fn find_listed_server(socket: &mut UdpSocket, master: SocketAddr) -> Option<SocketAddr> {
+    use shared::network::master::fetch_server_list;
+    use shared::network::query::{collect_replies, send_query};
+
+    let servers = match fetch_server_list(&mut *socket, master, Some(shared::network::PROTOCOL_VERSION), 3) {
+        Ok(servers) => servers,
+        Err(e) => fail!("Couldn't get the server list from {}: {}", master, e)
+    };
+    // The master's info might be stale, and it can't know our ping, so ask everybody ourselves.
+    for &addr in servers.iter() {
+        send_query(&mut *socket, addr).unwrap();
+    }
+    pick_server(collect_replies(&mut *socket, QUERY_TIME).unwrap())
+}
+
+/// Prints every server that answered, and picks the closest one we can play on.
+fn pick_server(listings: Vec<shared::network::query::ServerListing>) -> Option<SocketAddr> {
+    for listing in listings.iter() {
+        println!("{}: {} on {}, {}/{} players, {:.0}ms{}", listing.addr, listing.info.name, listing.info.map,
+                 listing.info.players, listing.info.max_players, listing.ping * 1000.,
+                 if listing.is_compatible() { "" } else { " (incompatible)" });
+    }
+    listings.iter().find(|listing| listing.is_compatible()).map(|listing| listing.addr)
+}
+
+fn env_or(var: &str, default: u32) -> u32 {
+    std::os::getenv(var).and_then(|value| from_str(value.as_slice())).unwrap_or(default)
+}
+
+/// What the client asks the server for. The server has the final say.
+struct Rates {
+    /// Bytes per second.
+    rate: u32,
+    /// Updates per second.
+    snapshot_rate: u32,
+    /// Playercmd packets per second.
+    command_rate: u32
+}
+
+/// Adds a message from the server to the demo being recorded, if any.
+fn record(demo: &mut Option<DemoRecorder>, packet: &[u8]) {
+    let result = match *demo {
+        Some(ref mut writer) => writer.write_raw(time::precise_time_s(), packet),
+        None => return
+    };
+    match result {
+        Ok(()) => (),
+        Err(e) => {
+            println!("Stopped recording demo: {}", e);
+            *demo = None;
+        }
+    }
+}
+
+fn connect(transport: &mut Transport, serveraddr: SocketAddr, name: &str, rates: Rates,
+           spectator_secret: Option<String>, mut demo: Option<DemoRecorder>, mut retries: u32) {
+    use shared::network::{ChallengeResponsePacket, ConnectPacket, PROTOCOL_VERSION};
+    use shared::network::channel::{is_out_of_band, recv_out_of_band, send_out_of_band};
+
     let mut netchan = NetChannel::new();
+    let mut challenge = None;
 
     while retries > 0 {
-        let encoded_packet = json::encode(&shared::network::Connect).into_bytes();
-        let compressed_packet = flate::deflate_bytes_zlib(encoded_packet.as_slice()).unwrap();
-        let packet = netchan.send_unreliable(compressed_packet.as_slice()).unwrap();
-        stream.write(packet.as_slice()).unwrap();
+        let msg = match challenge {
+            None => shared::network::Connect(ConnectPacket {
+                protocol_version: PROTOCOL_VERSION,
+                name: name.to_string()
+            }),
+            Some(challenge) => shared::network::ChallengeResponse(ChallengeResponsePacket {
+                challenge: challenge,
+                protocol_version: PROTOCOL_VERSION,
+                name: name.to_string(),
+                rate: rates.rate,
+                snapshot_rate: rates.snapshot_rate,
+                command_rate: rates.command_rate,
+                spectator_secret: spectator_secret.clone()
+            })
+        };
+        let datagram = send_out_of_band(encode_client_msg(&msg).as_slice()).unwrap();
+        let _ = transport.send_to(datagram.as_slice(), serveraddr);
 
         let mut recvbuf = [0u8, ..16384];
         // 100ms timeout
-        stream.as_socket(|sock| sock.set_read_timeout(Some(100)));
-        match stream.read(recvbuf) {
-            Ok(0) => continue,
-            Ok(len) => {
+        transport.set_read_timeout(Some(100));
+        match transport.recv_from(&mut recvbuf) {
+            Ok((0, _)) => continue,
+            Ok((_, from)) if from != serveraddr => continue,
+            Ok((len, _)) => {
                 let datagram = recvbuf.as_slice().slice_to(len);
-                let compressed_packet = netchan.recv_unreliable(datagram).unwrap();
-                let packet = flate::inflate_bytes_zlib(compressed_packet.as_slice()).unwrap();
-                let msg: ServerToClient = json::decode(std::str::from_utf8(packet.as_slice()).unwrap()).unwrap();
-
-                match msg {
-                    Signon(signon) => {
-                        gameloop(stream, netchan, signon);
+                let packet = if is_out_of_band(datagram) {
+                    match recv_out_of_band(datagram) {
+                        Ok(packet) => packet,
+                        Err(_) => continue
+                    }
+                } else {
+                    match netchan.recv_unreliable(datagram) {
+                        Ok(Some(packet)) => packet,
+                        _ => continue
+                    }
+                };
+                record(&mut demo, packet.as_slice());
+
+                match decode_server_msg(packet.as_slice()) {
+                    Some(Challenge(c)) => {
+                        challenge = Some(c.challenge);
+                        continue;
+                    },
+                    Some(Reject(reason)) => fail!("Server rejected connection: {}", reason),
+                    Some(Signon(signon)) => {
+                        gameloop(transport, serveraddr, netchan, signon, demo);
                         return;
                     },
                     _ => ()
@@ -93,10 +276,7 @@ fn connect(serveraddr: SocketAddr, mut retries: u32) {
     fail!("Out of retries while connecting to server!")
 }
 
-fn gameloop(mut stream: UdpStream, mut netchan: NetChannel, signon: SignonPacket) {
-    stream.as_socket(|sock| sock.set_read_timeout(Some(0)));
-
-
+fn open_window() -> (glfw::Glfw, glfw::Window, Receiver<(f64, glfw::WindowEvent)>) {
     let glfw = glfw::init(glfw::FAIL_ON_ERRORS).unwrap();
 
     glfw.window_hint(glfw::ContextVersion(3, 2));
@@ -110,33 +290,54 @@ fn gameloop(mut stream: UdpStream, mut netchan: NetChannel, signon: SignonPacket
     window.make_current();
     glfw.set_error_callback(glfw::FAIL_ON_ERRORS);
     window.set_key_polling(true);
+    (glfw, window, events)
+}
+
+fn gameloop(transport: &mut Transport, serveraddr: SocketAddr, mut netchan: NetChannel, signon: SignonPacket,
+            mut demo: Option<DemoRecorder>) {
+    transport.set_read_timeout(Some(0));
+
+    let (glfw, mut window, events) = open_window();
     window.set_cursor_pos_polling(true);
     window.set_cursor_mode(glfw::CursorDisabled);
 
-    let mut entities = ComponentStore::new();
-    let mut renderables = ComponentStore::new();
+    let mut world = shared::world::World::new();
+    let archetypes = archetype::ArchetypeTable::load_default();
+    let mut local = archetype::LocalComponents::new();
 
     let mut renderer = renderer::Renderer::new(&mut window);
     let mut input_integrator = input::MouseInputIntegrator::new();
 
     let mut motion = None;
-    let mut hdict = std::collections::HashMap::new();
+    let mut replication = ReplicationRegistry::with_default_components();
 
-    let localplayer = EntityComponent::new(&mut entities, Point3::new(0., 0., 0.),
+    let localplayer = EntityComponent::new(&mut world.entities, PLAYER_ARCHETYPE, Point3::new(0., 0., 0.),
         Rotation3::from_euler(rad(0.), rad(0.), rad(0.)));
 
-    hdict.insert(signon.handle, localplayer);
+    match signon.handle {
+        Some(handle) => { replication.entity_handles().insert(handle, localplayer); },
+        None => fail!("Server signed us on as a spectator!")
+    }
 
     let cam = renderer::CameraComponent::new(localplayer);
 
-    let mut last_command = 0.;
-    let mut servertick = 0;
-
-    let mut prediction = prediction::Prediction::new(shared::playercmd::ControllableComponent::new(localplayer));
+    // Start the clock from the signon, so we can start sending commands.
+    let mut clock = clock::ServerClock::new(signon.command_interval);
+    clock.update(signon.tick, time::precise_time_s(), netchan.get_stats().rtt);
+    let mut command_number = 0u32;
+    // The last few commands we sent, so they can be resent with each packet.
+    let mut recent_commands = RingBuf::new();
+    let mut unsent_commands = 0u32;
+
+    let controllable = world.controllables.add(shared::playercmd::ControllableComponent::new(localplayer));
+    let mut prediction = prediction::Prediction::new(controllable, localplayer, shared::world::default_systems());
+    // Always have at least a couple of updates to interpolate between.
+    let snapshot_length = signon.snapshot_interval as f64 * shared::TICK_LENGTH as f64;
+    let mut interpolator = interpolation::Interpolator::new(INTERPOLATION_DELAY.max(2. * snapshot_length), MAX_EXTRAPOLATION);
+    // What actually gets drawn: predicted local player, interpolated everything else.
+    let mut display_world = shared::world::World::new();
 
     while !window.should_close() {
-        use shared::network::protocol::apply_update;
-
         let framestart_ns = time::precise_time_ns();
 
         glfw.poll_events();
@@ -159,7 +360,6 @@ fn gameloop(mut stream: UdpStream, mut netchan: NetChannel, signon: SignonPacket
             }
         }
 
-        // shared::physics::simulate_tick(&mut physicals, &mut entities);
         // networking
         //     get updates from server, update gamestate
         //     part of that is GC for component stores
@@ -171,59 +371,227 @@ fn gameloop(mut stream: UdpStream, mut netchan: NetChannel, signon: SignonPacket
 
         let mut buf = [0u8, ..8192];
 
-        loop { match stream.read(buf) {
-            Ok(0) => (),
-            Ok(len) => {
-
-                let packet = netchan.recv_unreliable((buf.slice_to(len))).unwrap();
-                let packet = flate::inflate_bytes_zlib(packet.as_slice()).expect("Decompression!");
-                let packet = std::str::from_utf8(packet.as_slice()).unwrap();
-                let packet: ServerToClient = json::decode(packet).unwrap();
-                match packet {
-                    Update(update) => {
-                        servertick = update.tick;
-                        apply_update(update.entity_updates.into_iter(), &mut hdict, &mut entities, |e, h| EntityComponent::from_nohandle(&e, h), |e, store| {
-                            println!("Adding new entity.");
-                            let handle = store.add_with_handle(|handle| EntityComponent::from_nohandle(&e, handle));
-                            renderables.add(RenderComponent{entity: handle});
-                            handle
-                        });
-                        prediction.update(netchan.get_acked_outgoing_sequencenr(), &entities);
+        loop { match transport.recv_from(&mut buf) {
+            Ok((0, _)) => (),
+            Ok((_, from)) if from != serveraddr => (),
+            Ok((len, _)) => {
+
+                let packet = match netchan.recv_unreliable(buf.slice_to(len)) {
+                    Ok(Some(packet)) => packet,
+                    // waiting on more fragments
+                    Ok(None) => continue,
+                    // probably a leftover connectionless packet
+                    Err(_) => continue
+                };
+                record(&mut demo, packet.as_slice());
+                match decode_server_msg(packet.as_slice()) {
+                    Some(Update(update)) => {
+                        clock.update(update.tick, time::precise_time_s(), netchan.get_stats().rtt);
+                        match update.all_entities {
+                            Some(ref all) => replication.retain_entities(all.as_slice(), &mut world),
+                            None => ()
+                        }
+                        for handle in replication.apply_update(update.component_updates, &mut world).into_iter() {
+                            archetypes.spawn(world.entities.find(handle).unwrap(), &mut local);
+                        }
+                        local.remove_dead(&world.entities);
+                        prediction.update(update.last_command, &world);
+                        interpolator.record(update.tick, &local.interpolated, &world.entities);
                     },
-                    Signon(_) => ()
+                    Some(Disconnected(reason)) => {
+                        println!("Disconnected by server: {}", reason);
+                        return;
+                    },
+                    Some(PlayerLeft(left)) => println!("{} left the game: {}", left.name, left.reason),
+                    Some(_) => (),
+                    None => println!("Garbage packet from the server")
                 }
             },
             Err(ref e) if e.kind == std::io::TimedOut => break,
-            Err(e) => fail!("Network error: {}", e)
+            Err(e) => {
+                // Say goodbye and stop, rather than crash.
+                println!("Network error: {}", e);
+                window.set_should_close(true);
+                break;
+            }
         } };
 
-        if last_command + (shared::TICK_LENGTH as f64) < (framestart_ns as f64 / 1000. / 1000. / 1000.) { 
-            last_command = framestart_ns as f64 / 1000. / 1000. / 1000.;
+        // One command per tick, on the server's clock.
+        loop {
+            let tick = match clock.next_command_tick(framestart_ns as f64 / 1000. / 1000. / 1000.) {
+                Some(tick) => tick,
+                None => break
+            };
 
+            command_number += 1;
             let cmd = shared::playercmd::PlayerCommand {
-                tick: servertick,
+                number: command_number,
+                tick: tick,
                 angles: cgmath::Rotation3::from_euler(cgmath::rad(0.), input_integrator.yaw.to_rad(), input_integrator.pitch.to_rad()),
                 movement: motion
             };
 
+            recent_commands.push(cmd);
+            while recent_commands.len() > shared::network::commands_per_packet(signon.command_interval) {
+                recent_commands.pop_front();
+            }
 
-            let encoded_packet = json::encode(&shared::network::Playercmd(cmd)).into_bytes();
-            let compressed_packet = flate::deflate_bytes_zlib(encoded_packet.as_slice()).unwrap();
-            let packet = netchan.send_unreliable(compressed_packet.as_slice()).unwrap();
-            stream.write(packet.as_slice()).unwrap();
-
-            prediction.predict(cmd, netchan.get_outgoing_sequencenr());
+            prediction.predict(cmd);
+            unsent_commands += 1;
         }
 
-        renderer.render(&cam, &mut renderables, prediction.get_entities().unwrap_or(&entities));
+        // Batch up commands to send at the agreed rate.
+        if unsent_commands >= signon.command_interval {
+            unsent_commands = 0;
+            let packet = encode_client_msg(&shared::network::Playercmd(recent_commands.iter().map(|&cmd| cmd).collect()));
+            for datagram in netchan.send_unreliable(packet.as_slice()).unwrap().iter() {
+                let _ = transport.send_to(datagram.as_slice(), serveraddr);
+            }
+        }
 
-        //println!("{}", netchan.get_latency());
+        display_world.clone_from(prediction.get_world().unwrap_or(&world));
+        interpolator.apply(clock.current_tick(time::precise_time_s()), &mut display_world.entities);
+        prediction.apply_smoothing(&mut display_world.entities);
+        renderer.render(&cam, &mut local.renderables, &display_world.entities);
 
         window.swap_buffers();
 
         let frameend_ns = time::precise_time_ns();
         let frametime_ns = frameend_ns - framestart_ns;
         let fps = 1000 * 1000 * 1000 / frametime_ns;
-        window.set_title(format!("{}FPS, frametime: {}ns", fps, frametime_ns).as_slice());
+        prediction.decay_error(frametime_ns as f64 / 1000. / 1000. / 1000.);
+        let netstats = netchan.get_stats();
+        let prederrors = prediction.get_error_stats();
+        window.set_title(format!("{}FPS, frametime: {}ns, rtt: {:.0}+-{:.0}ms, loss: {:.1}%/{:.1}%, {:.1}/{:.1} KB/s, prediction errors: {} (last {:.3}, max {:.3})",
+                                 fps, frametime_ns,
+                                 netstats.rtt * 1000., netstats.rtt_variance * 1000.,
+                                 netstats.loss_in, netstats.loss_out,
+                                 netstats.bytes_in_per_sec / 1024., netstats.bytes_out_per_sec / 1024.,
+                                 prederrors.errors, prederrors.last_error, prederrors.max_error).as_slice());
+    }
+
+    // Say goodbye a few times, in case some get lost.
+    let goodbye = encode_client_msg(&shared::network::Disconnect("Client quit".to_string()));
+    for _ in range(0u, 3) {
+        for datagram in netchan.send_unreliable(goodbye.as_slice()).unwrap().iter() {
+            let _ = transport.send_to(datagram.as_slice(), serveraddr);
+        }
+    }
+
+    match demo {
+        Some(ref mut writer) => { let _ = writer.flush(); },
+        None => ()
+    }
+}
+
+/// Plays a recorded demo. Space pauses, left and right seek, up and
+/// down change the speed.
+fn play_demo(path: &Path) {
+    let frames = match File::open(path).and_then(|file| read_demo(&mut BufferedReader::new(file))) {
+        Ok(frames) => frames,
+        Err(e) => fail!("Couldn't load demo {}: {}", path.display(), e)
+    };
+    let mut player = demo::DemoPlayer::new(frames, INTERPOLATION_DELAY, MAX_EXTRAPOLATION);
+
+    let (glfw, mut window, events) = open_window();
+    let mut renderer = renderer::Renderer::new(&mut window);
+    let mut display_world = shared::world::World::new();
+    let mut last_frame = time::precise_time_s();
+
+    while !window.should_close() {
+        glfw.poll_events();
+
+        for (_, event) in glfw::flush_messages(&events) {
+            match event {
+                glfw::KeyEvent(glfw::KeyEscape, _, glfw::Press, _) => window.set_should_close(true),
+                glfw::KeyEvent(glfw::KeySpace, _, glfw::Press, _) => player.paused = !player.paused,
+                glfw::KeyEvent(glfw::KeyLeft, _, glfw::Press, _) => {
+                    let time = player.get_time() - DEMO_SEEK_STEP;
+                    player.seek(time);
+                },
+                glfw::KeyEvent(glfw::KeyRight, _, glfw::Press, _) => {
+                    let time = player.get_time() + DEMO_SEEK_STEP;
+                    player.seek(time);
+                },
+                glfw::KeyEvent(glfw::KeyUp, _, glfw::Press, _) => player.speed *= 2.,
+                glfw::KeyEvent(glfw::KeyDown, _, glfw::Press, _) => player.speed /= 2.,
+                _ => {},
+            }
+        }
+
+        let now = time::precise_time_s();
+        player.advance(now - last_frame);
+        last_frame = now;
+
+        player.display(&mut display_world);
+        match player.get_viewpoint() {
+            Some(viewpoint) => {
+                let cam = renderer::CameraComponent::new(viewpoint);
+                renderer.render(&cam, &mut player.get_local_mut().renderables, &display_world.entities);
+            },
+            None => ()
+        }
+
+        window.swap_buffers();
+        window.set_title(format!("{} - {:.1}/{:.1}s at {}x{}", path.display(), player.get_time(), player.get_length(),
+                                 player.speed, if player.paused { " (paused)" } else { "" }).as_slice());
+    }
+}
+
+fn watch_replay(path: &Path) {
+    let ticks = match File::open(path).and_then(|file| read_replay(&mut BufferedReader::new(file))) {
+        Ok(ticks) => ticks,
+        Err(e) => fail!("Couldn't load replay {}: {}", path.display(), e)
+    };
+    let mut viewer = match replay::ReplayViewer::new(ticks) {
+        Some(viewer) => viewer,
+        None => fail!("Replay {} is empty", path.display())
+    };
+    let seek_step = (DEMO_SEEK_STEP / shared::TICK_LENGTH as f64) as u64;
+
+    let (glfw, mut window, events) = open_window();
+    let mut renderer = renderer::Renderer::new(&mut window);
+    let mut display_world = shared::world::World::new();
+    let mut last_frame = time::precise_time_s();
+
+    while !window.should_close() {
+        glfw.poll_events();
+
+        for (_, event) in glfw::flush_messages(&events) {
+            match event {
+                glfw::KeyEvent(glfw::KeyEscape, _, glfw::Press, _) => window.set_should_close(true),
+                glfw::KeyEvent(glfw::KeySpace, _, glfw::Press, _) => viewer.paused = !viewer.paused,
+                glfw::KeyEvent(glfw::KeyTab, _, glfw::Press, _) => viewer.next_viewpoint(),
+                glfw::KeyEvent(glfw::KeyLeft, _, glfw::Press, _) => {
+                    let tick = viewer.get_tick();
+                    viewer.seek(if tick > seek_step { tick - seek_step } else { 0 });
+                },
+                glfw::KeyEvent(glfw::KeyRight, _, glfw::Press, _) => {
+                    let tick = viewer.get_tick() + seek_step;
+                    viewer.seek(tick);
+                },
+                glfw::KeyEvent(glfw::KeyUp, _, glfw::Press, _) => viewer.speed *= 2.,
+                glfw::KeyEvent(glfw::KeyDown, _, glfw::Press, _) => viewer.speed /= 2.,
+                _ => {},
+            }
+        }
+
+        let now = time::precise_time_s();
+        viewer.advance(now - last_frame);
+        last_frame = now;
+
+        display_world.clone_from(viewer.get_world());
+        match viewer.get_viewpoint() {
+            Some(viewpoint) => {
+                let cam = renderer::CameraComponent::new(viewpoint);
+                renderer.render(&cam, &mut viewer.get_local_mut().renderables, &display_world.entities);
+            },
+            None => ()
+        }
+
+        window.swap_buffers();
+        let (first, last) = viewer.get_range();
+        window.set_title(format!("{} - tick {}/{} at {}x{}", path.display(), viewer.get_tick() - first, last - first,
+                                 viewer.speed, if viewer.paused { " (paused)" } else { "" }).as_slice());
     }
 }
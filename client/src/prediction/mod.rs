@@ -1,78 +1,285 @@
 use std::collections::RingBuf;
 use std::collections::Deque;
 use shared::{
+    ComponentHandle,
     ComponentStore,
     EntityComponent,
-    EntityHandle,
-
-    playercmd
+    EntityHandle
 };
-use shared::network::UpdatePacket;
-use shared::network::channel::{overflow_aware_compare, SequenceNr};
+use shared::network::channel::overflow_aware_compare;
 use shared::playercmd::{ControllableComponent, PlayerCommand};
-use cgmath::ApproxEq;
+use shared::world::{System, World};
+use cgmath::{ApproxEq, EuclideanVector, Point, Vector, Vector3};
+
+/// Roughly how long (in seconds) a misprediction takes to smooth out.
+static SMOOTHING_TIME: f64 = 0.1;
+/// Mispredictions bigger than this are snapped to instead of smoothed,
+/// since the player was probably teleported.
+static SNAP_DISTANCE: f32 = 4.;
+
+/// How wrong our predictions have been, for debugging.
+#[deriving(Clone, Show)]
+pub struct PredictionErrorStats {
+    pub errors: u64,
+    pub last_error: f32,
+    pub max_error: f32,
+    pub total_error: f64
+}
 
+/// Predicts the results of the local player's commands before the server
+/// confirms them, by rerunning every shared system over a copy of the world.
 pub struct Prediction {
-    controllable: ControllableComponent,
-    history: RingBuf<(SequenceNr, PlayerCommand)>,
+    controllable: ComponentHandle<ControllableComponent>,
+    entity: EntityHandle,
+    systems: Vec<Box<System + 'static>>,
+    /// Commands the server hasn't run yet, oldest first.
+    history: RingBuf<PlayerCommand>,
+
+    predicted: Option<World>,
 
-    predicted: Option<ComponentStore<EntityComponent>>
+    /// Where we're drawing the player, relative to where we've predicted it is.
+    /// Decays to zero, so corrections are smooth.
+    error_offset: Vector3<f32>,
+    error_stats: PredictionErrorStats
 }
 
 impl Prediction {
-    pub fn new(controllable: ControllableComponent) -> Prediction {
+    /// `systems` should be the same systems the server runs, in the same order.
+    pub fn new(controllable: ComponentHandle<ControllableComponent>,
+               entity: EntityHandle,
+               systems: Vec<Box<System + 'static>>) -> Prediction {
         Prediction {
             controllable: controllable,
+            entity: entity,
+            systems: systems,
             history: RingBuf::new(),
 
-            predicted: None
+            predicted: None,
+
+            error_offset: Vector3::new(0., 0., 0.),
+            error_stats: PredictionErrorStats {
+                errors: 0,
+                last_error: 0.,
+                max_error: 0.,
+                total_error: 0.
+            }
         }
     }
 
-    pub fn update(&mut self, acked_sequence: SequenceNr, new_entities: &ComponentStore<EntityComponent>) {
+    /// Takes a new authoritative state from the server, along with the
+    /// number of the last command the server had run when it made it.
+    pub fn update(&mut self, last_command: u32, new_world: &World) {
         self.predicted = Some(match self.predicted.take() {
-            Some(mut entities) => {
-                let oldpos = entities.find(self.controllable.entity).unwrap().pos;
+            Some(mut world) => {
+                let oldpos = world.entities.find(self.entity).unwrap().pos;
 
-                entities.clone_from(new_entities);
+                world.clone_from(new_world);
 
-                self.remove_old_history(acked_sequence);
-                for &(_, cmd) in self.history.iter() {
-                    playercmd::run_command(cmd, &mut self.controllable, &mut entities);
+                self.remove_old_history(last_command);
+                for &cmd in self.history.iter() {
+                    ::shared::world::tick(&mut world, self.systems.as_mut_slice(), &[(self.controllable, cmd)]);
                 }
 
-                let newpos = entities.find(self.controllable.entity).unwrap().pos;
+                let newpos = world.entities.find(self.entity).unwrap().pos;
                 if !newpos.approx_eq(&oldpos) {
-                    println!("Prediction error: {} update vs. {} pred", newpos, oldpos)
+                    let error = oldpos.sub_p(&newpos);
+                    let magnitude = error.length();
+
+                    self.error_stats.errors += 1;
+                    self.error_stats.last_error = magnitude;
+                    self.error_stats.total_error += magnitude as f64;
+                    if magnitude > self.error_stats.max_error {
+                        self.error_stats.max_error = magnitude;
+                    }
+
+                    // Keep drawing the player where it was, and let decay_error
+                    // slide it over to the corrected position.
+                    self.error_offset = if magnitude > SNAP_DISTANCE {
+                        Vector3::new(0., 0., 0.)
+                    } else {
+                        self.error_offset.add_v(&error)
+                    };
                 };
 
-                entities
+                world
             },
-            None => new_entities.clone()
+            None => new_world.clone()
         });
     }
 
-    fn remove_old_history(&mut self, latest_ack: SequenceNr) {
-        while self.history.front().map(|&(seq, _)| overflow_aware_compare(seq, latest_ack) != ::std::cmp::Greater).unwrap_or(false) {
+    fn remove_old_history(&mut self, last_command: u32) {
+        while self.history.front().map(|cmd| overflow_aware_compare(cmd.number, last_command) != ::std::cmp::Greater).unwrap_or(false) {
             self.history.pop_front();
         }
     }
 
-    pub fn predict(&mut self, cmd: PlayerCommand, sequence: SequenceNr) {
-        // borrow checker hack
-        let mut controllable = self.controllable;
+    pub fn predict(&mut self, cmd: PlayerCommand) {
+        match self.predicted {
+            Some(ref mut world) => ::shared::world::tick(world, self.systems.as_mut_slice(), &[(self.controllable, cmd)]),
+            None => ()
+        }
 
-        self.predicted.as_mut().map(|ents| {
-            playercmd::run_command(cmd, &mut controllable, ents)
-        });
+        self.history.push(cmd);
+    }
 
-        self.controllable = controllable;
+    pub fn get_world(&self) -> Option<&World> {
+        self.predicted.as_ref()
+    }
 
-        self.history.push((sequence, cmd));
+    /// Shrinks the visual error offset; call once per frame with the frame time in seconds.
+    pub fn decay_error(&mut self, dt: f64) {
+        let factor = (-dt / SMOOTHING_TIME).exp() as f32;
+        self.error_offset = self.error_offset.mul_s(factor);
     }
 
-    pub fn get_entities(&self) -> Option<&ComponentStore<EntityComponent>> {
-        self.predicted.as_ref()
+    /// Moves the controlled entity in `entities` to where it should be drawn.
+    pub fn apply_smoothing(&self, entities: &mut ComponentStore<EntityComponent>) {
+        match entities.find_mut(self.entity) {
+            Some(ent) => ent.pos = ent.pos.add_v(&self.error_offset),
+            None => ()
+        }
+    }
+
+    pub fn get_error_stats(&self) -> PredictionErrorStats {
+        self.error_stats.clone()
     }
 
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::{Deque, RingBuf};
+    use std::io::net::ip::SocketAddr;
+    use std::str;
+    use cgmath::{ApproxEq, Point3, Quaternion, Vector3};
+    use serialize::json;
+    use shared::EntityComponent;
+    use shared::TICK_LENGTH;
+    use shared::component::components::PLAYER_ARCHETYPE;
+    use shared::network::ComponentTypeUpdate;
+    use shared::network::channel::NetChannel;
+    use shared::network::delta::RelevanceHistory;
+    use shared::network::replication::{ReplicationRegistry, MAX_STATES};
+    use shared::network::simulator::{Manual, NetConditions, SimulatedTransport};
+    use shared::network::transport::{MemoryNetwork, MemoryTransport, Transport};
+    use shared::playercmd::{ControllableComponent, PlayerCommand};
+    use shared::world::{default_systems, tick, World};
+    use super::Prediction;
+
+    type Link = SimulatedTransport<MemoryTransport>;
+
+    fn bad_link(network: &MemoryNetwork, seed: u32) -> Link {
+        let mut transport = network.bind_any();
+        transport.set_read_timeout(Some(0));
+        SimulatedTransport::with_clock(transport, NetConditions {
+            latency: 0.05,
+            jitter: 0.02,
+            loss: 0.1,
+            duplicate: 0.05,
+            reorder: 0.05,
+            bandwidth: 0
+        }, Manual(0.), seed)
+    }
+
+    fn send(link: &mut Link, channel: &mut NetChannel, to: SocketAddr, msg: String) {
+        for datagram in channel.send_unreliable(msg.as_bytes()).unwrap().iter() {
+            link.send_to(datagram.as_slice(), to).unwrap();
+        }
+    }
+
+    fn recv_all(link: &mut Link, channel: &mut NetChannel) -> Vec<String> {
+        let mut buf = [0u8, ..8192];
+        let mut packets = Vec::new();
+        loop {
+            match link.recv_from(&mut buf) {
+                Ok((len, _)) => match channel.recv_unreliable(buf.slice_to(len)) {
+                    Ok(Some(packet)) => packets.push(str::from_utf8(packet.as_slice()).unwrap().to_string()),
+                    _ => ()
+                },
+                Err(_) => return packets
+            }
+        }
+    }
+
+    #[test]
+    fn converges_over_bad_link() {
+        let network = MemoryNetwork::new();
+        let mut server_link = bad_link(&network, 5);
+        let mut client_link = bad_link(&network, 6);
+        let server_addr = server_link.local_addr().unwrap();
+        let client_addr = client_link.local_addr().unwrap();
+        let mut server_chan = NetChannel::new();
+        let mut client_chan = NetChannel::new();
+        let rot = Quaternion::new(1., 0., 0., 0.);
+
+        let mut server_world = World::new();
+        let server_player = EntityComponent::new(&mut server_world.entities, PLAYER_ARCHETYPE, Point3::new(0., 0., 0.), rot);
+        let server_controllable = server_world.controllables.add(ControllableComponent::new(server_player));
+        let mut server_systems = default_systems();
+        let mut server = ReplicationRegistry::with_default_components();
+        let mut relevance = RelevanceHistory::new(MAX_STATES);
+        let mut last_run = 0u32;
+
+        // the client sets up its own player, like after a signon
+        let mut client_world = World::new();
+        let client_player = EntityComponent::new(&mut client_world.entities, PLAYER_ARCHETYPE, Point3::new(0., 0., 0.), rot);
+        let client_controllable = client_world.controllables.add(ControllableComponent::new(client_player));
+        let mut client = ReplicationRegistry::with_default_components();
+        client.entity_handles().insert(server_player.to_raw(), client_player);
+        let mut prediction = Prediction::new(client_controllable, client_player, default_systems());
+        prediction.update(0, &client_world);
+        let mut recent: RingBuf<PlayerCommand> = RingBuf::new();
+
+        for i in range(0u, 600) {
+            // client: walk around for a while, then stand still while everything lands
+            if i < 400 {
+                let cmd = PlayerCommand {
+                    number: i as u32 + 1,
+                    tick: i as u64,
+                    angles: rot,
+                    movement: Vector3::new(0.1, 0., (i % 7) as f32 * 0.01)
+                };
+                prediction.predict(cmd);
+                recent.push(cmd);
+                while recent.len() > 4 {
+                    recent.pop_front();
+                }
+            }
+            let cmds: Vec<PlayerCommand> = recent.iter().map(|&cmd| cmd).collect();
+            send(&mut client_link, &mut client_chan, server_addr, json::encode(&cmds));
+
+            // server: run whatever's new, in order, and send the world back
+            for packet in recv_all(&mut server_link, &mut server_chan).iter() {
+                let cmds: Vec<PlayerCommand> = json::decode(packet.as_slice()).unwrap();
+                for &cmd in cmds.iter() {
+                    if cmd.number > last_run {
+                        last_run = cmd.number;
+                        tick(&mut server_world, server_systems.as_mut_slice(), &[(server_controllable, cmd)]);
+                    }
+                }
+            }
+            server.add_state(&server_world);
+            relevance.push(server_world.entities.iter().map(|(h, _)| h.to_raw()).collect());
+            let length = (server_chan.get_outgoing_sequencenr() + 1 - server_chan.get_acked_outgoing_sequencenr()) as u64;
+            let update = (last_run, server.create_update(length, &relevance));
+            send(&mut server_link, &mut server_chan, client_addr, json::encode(&update));
+
+            // client: correct the prediction with whatever turned up
+            for packet in recv_all(&mut client_link, &mut client_chan).iter() {
+                let (last_command, update): (u32, Vec<ComponentTypeUpdate>) = json::decode(packet.as_slice()).unwrap();
+                client.apply_update(update, &mut client_world);
+                prediction.update(last_command, &client_world);
+            }
+
+            server_link.advance(TICK_LENGTH as f64);
+            client_link.advance(TICK_LENGTH as f64);
+        }
+
+        let server_pos = server_world.entities.find(server_player).unwrap().pos;
+        let predicted_pos = prediction.get_world().unwrap().entities.find(client_player).unwrap().pos;
+        // the player really went somewhere...
+        assert!(server_pos.x > 30.);
+        // ...and the client ended up agreeing, even about commands that got lost
+        assert!(predicted_pos.approx_eq(&server_pos));
+    }
+}
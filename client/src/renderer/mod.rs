@@ -14,7 +14,10 @@ struct Params {
     mvp: [[f32, ..4], ..4],
     
     #[name = "u_Color"]
-    color: [f32, ..3]
+    color: [f32, ..3],
+
+    #[name = "u_Scale"]
+    scale: f32
 }
 
 #[vertex_format]
@@ -30,13 +33,14 @@ GLSL_150: b"
     
     uniform mat4 u_MVP;
     uniform vec3 u_Color;
+    uniform float u_Scale;
 
     in vec3 a_Pos;
     out vec4 v_Color;
 
     void main() {
-        v_Color = vec4((a_Pos + vec3(1.0, 1.0, 1.0))/2, 1.0);
-        gl_Position = u_MVP * vec4(a_Pos, 1.0);
+        v_Color = vec4(u_Color * (a_Pos + vec3(3.0, 3.0, 3.0))/4, 1.0);
+        gl_Position = u_MVP * vec4(a_Pos * u_Scale, 1.0);
     }
 "
 };
@@ -55,7 +59,9 @@ GLSL_150: b"
 };
 
 pub struct RenderComponent {
-    pub entity: EntityHandle
+    pub entity: EntityHandle,
+    pub color: [f32, ..3],
+    pub scale: f32
 }
 pub struct CameraComponent {
     entity: EntityHandle,
@@ -172,7 +178,7 @@ impl Renderer {
                     };
 
                     let model = ent.make_matrix();
-                    self.graphics.draw(&batch, &Params { color: [0.8, 1.0, 0.8], mvp: (proj * view * model).into_fixed()}, &self.frame);
+                    self.graphics.draw(&batch, &Params { color: renderable.color, scale: renderable.scale, mvp: (proj * view * model).into_fixed()}, &self.frame);
                 },
                 None => dead.push(handle)
             }
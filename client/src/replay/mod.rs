@@ -0,0 +1,205 @@
+//! Watching server replays.
+//!
+//! A replay has the whole world on every tick, so there's nothing to
+//! interpolate or predict: playback just steps through the ticks, and
+//! the camera can follow any player in the game.
+
+use shared::EntityHandle;
+use shared::component::components::PLAYER_ARCHETYPE;
+use shared::network::replay::{ReplayPlayer, ReplayTick};
+use shared::world::World;
+use shared::TICK_LENGTH;
+use archetype::{ArchetypeTable, LocalComponents};
+
+pub struct ReplayViewer {
+    player: ReplayPlayer,
+    /// The first and last ticks in the replay.
+    first_tick: u64,
+    last_tick: u64,
+    /// Where playback is, in ticks, with fractions.
+    time: f64,
+    /// 1 is normal speed.
+    pub speed: f64,
+    pub paused: bool,
+
+    archetypes: ArchetypeTable,
+    local: LocalComponents,
+    /// The player we're watching from.
+    viewpoint: Option<EntityHandle>
+}
+
+impl ReplayViewer {
+    /// Returns None for replays with no ticks in them.
+    pub fn new(ticks: Vec<ReplayTick>) -> Option<ReplayViewer> {
+        let player = ReplayPlayer::new(ticks);
+        let (first_tick, last_tick) = match player.get_range() {
+            Some(range) => range,
+            None => return None
+        };
+        let mut viewer = ReplayViewer {
+            player: player,
+            first_tick: first_tick,
+            last_tick: last_tick,
+            time: first_tick as f64,
+            speed: 1.,
+            paused: false,
+            archetypes: ArchetypeTable::load_default(),
+            local: LocalComponents::new(),
+            viewpoint: None
+        };
+        viewer.seek(first_tick);
+        Some(viewer)
+    }
+
+    /// Makes local components for whatever the replay just created.
+    fn spawn_new(&mut self) {
+        for handle in self.player.take_new_entities().into_iter() {
+            self.archetypes.spawn(self.player.get_world().entities.find(handle).unwrap(), &mut self.local);
+        }
+        self.local.remove_dead(&self.player.get_world().entities);
+
+        // Whoever we're watching from shouldn't block the view.
+        let viewpoint = self.viewpoint;
+        match viewpoint {
+            Some(viewpoint) if self.player.get_world().entities.find(viewpoint).is_some() =>
+                self.local.remove_entity(viewpoint),
+            _ => self.next_viewpoint()
+        }
+    }
+
+    /// Moves playback forward by `dt` real seconds.
+    pub fn advance(&mut self, dt: f64) {
+        if self.paused {
+            return;
+        }
+        self.time = (self.time + dt * self.speed / TICK_LENGTH as f64).min(self.last_tick as f64);
+        while self.player.current_tick().map(|tick| (tick as f64) < self.time.floor()).unwrap_or(true) {
+            if !self.player.step() {
+                break;
+            }
+        }
+        self.spawn_new();
+    }
+
+    /// Jumps to `tick`, or as close as the replay goes.
+    pub fn seek(&mut self, tick: u64) {
+        let tick = ::std::cmp::max(::std::cmp::min(tick, self.last_tick), self.first_tick);
+        self.player.seek(tick);
+        self.time = tick as f64;
+        // The world's been rebuilt from scratch, handles and all.
+        self.local = LocalComponents::new();
+        self.viewpoint = None;
+        self.spawn_new();
+    }
+
+    /// Switches to watching the next player along, if there are any.
+    pub fn next_viewpoint(&mut self) {
+        let players: Vec<EntityHandle> = self.player.get_world().entities.iter()
+            .filter(|&(_, ent)| ent.archetype == PLAYER_ARCHETYPE)
+            .map(|(handle, _)| handle)
+            .collect();
+        let players = players.as_slice();
+
+        let next = match self.viewpoint.and_then(|viewpoint| players.iter().position(|&p| p == viewpoint)) {
+            Some(idx) => players.get(idx + 1).or(players.head()).map(|&p| p),
+            None => players.head().map(|&p| p)
+        };
+
+        // Put the old one back in the picture.
+        match self.viewpoint {
+            Some(old) => match self.player.get_world().entities.find(old) {
+                Some(ent) if Some(old) != next => self.archetypes.spawn(ent, &mut self.local),
+                _ => ()
+            },
+            None => ()
+        }
+        match next {
+            Some(viewpoint) => self.local.remove_entity(viewpoint),
+            None => ()
+        }
+        self.viewpoint = next;
+    }
+
+    pub fn get_tick(&self) -> u64 {
+        self.time as u64
+    }
+
+    pub fn get_range(&self) -> (u64, u64) {
+        (self.first_tick, self.last_tick)
+    }
+
+    pub fn get_viewpoint(&self) -> Option<EntityHandle> {
+        self.viewpoint
+    }
+
+    pub fn get_local_mut(&mut self) -> &mut LocalComponents {
+        &mut self.local
+    }
+
+    pub fn get_world(&self) -> &World {
+        self.player.get_world()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufReader, MemWriter};
+    use cgmath::{Point3, Quaternion};
+    use shared::EntityComponent;
+    use shared::component::components::{BLOCK_ARCHETYPE, PLAYER_ARCHETYPE};
+    use shared::network::replay::{read_replay, ReplayWriter};
+    use shared::network::replication::ReplicationRegistry;
+    use shared::world::World;
+    use super::ReplayViewer;
+
+    /// Two players standing around a block for ten ticks.
+    fn replay() -> ReplayViewer {
+        let mut world = World::new();
+        let rot = Quaternion::new(1., 0., 0., 0.);
+        EntityComponent::new(&mut world.entities, PLAYER_ARCHETYPE, Point3::new(0., 0., 0.), rot);
+        EntityComponent::new(&mut world.entities, PLAYER_ARCHETYPE, Point3::new(5., 0., 0.), rot);
+        EntityComponent::new(&mut world.entities, BLOCK_ARCHETYPE, Point3::new(0., 0., 5.), rot);
+        let mut replication = ReplicationRegistry::with_default_components();
+        let mut writer = ReplayWriter::new(MemWriter::new(), 4).unwrap();
+        for tick in range(1u64, 11) {
+            replication.add_state(&world);
+            writer.record_tick(tick, &replication, &world, &[]).unwrap();
+        }
+        let bytes = writer.unwrap().unwrap();
+        ReplayViewer::new(read_replay(&mut BufReader::new(bytes.as_slice())).unwrap()).unwrap()
+    }
+
+    fn drawn(viewer: &mut ReplayViewer) -> uint {
+        viewer.get_local_mut().renderables.iter().count()
+    }
+
+    #[test]
+    fn cycles_through_players() {
+        let mut viewer = replay();
+        assert_eq!(viewer.get_range(), (1, 10));
+        let first = viewer.get_viewpoint().unwrap();
+        // everything but whoever we're watching from gets drawn
+        assert_eq!(drawn(&mut viewer), 2);
+
+        viewer.next_viewpoint();
+        let second = viewer.get_viewpoint().unwrap();
+        assert!(first != second);
+        assert_eq!(viewer.get_world().entities.find(second).unwrap().archetype, PLAYER_ARCHETYPE);
+        assert_eq!(drawn(&mut viewer), 2);
+
+        viewer.next_viewpoint();
+        assert!(viewer.get_viewpoint() == Some(first));
+    }
+
+    #[test]
+    fn seeking_keeps_a_viewpoint() {
+        let mut viewer = replay();
+        viewer.seek(7);
+        assert_eq!(viewer.get_tick(), 7);
+        assert!(viewer.get_viewpoint().is_some());
+        assert_eq!(drawn(&mut viewer), 2);
+
+        viewer.seek(100);
+        assert_eq!(viewer.get_tick(), 10);
+    }
+}
@@ -0,0 +1,10 @@
+use shared::EntityHandle;
+
+/// Something that makes noise.
+///
+/// There's no audio backend yet, so nothing plays these;
+/// they're here so archetypes can ask for them.
+pub struct SoundEmitterComponent {
+    pub entity: EntityHandle,
+    pub sound: String
+}
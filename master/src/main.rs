@@ -0,0 +1,171 @@
+//! A master server: keeps a list of game servers, and hands it out to clients.
+//!
+//! See shared::network::master for the protocol. Run one locally to test
+//! server registration, or host one for a community's servers.
+
+extern crate shared;
+extern crate time;
+
+use std::io::net::ip::{Ipv4Addr, SocketAddr};
+use std::io::net::udp::UdpSocket;
+use shared::network::{decode_server_msg, encode_client_msg, Info, InfoRequest, InfoRequestPacket};
+use shared::network::channel::{is_out_of_band, recv_out_of_band, send_out_of_band};
+use shared::network::master::{decode_to_master, encode_from_master, Heartbeat, ListRequest, ServerList};
+use shared::network::master::DEFAULT_MASTER_PORT;
+use shared::network::transport::Transport;
+
+mod registry;
+
+/// The most servers we'll list at once.
+static MAX_SERVERS: uint = 4096;
+/// The most servers we'll be checking on at once, before they're listed.
+static MAX_UNVERIFIED: uint = 256;
+
+fn main() {
+    let port = std::os::getenv("NMIGP_MASTER_PORT").and_then(|port| from_str(port.as_slice())).unwrap_or(DEFAULT_MASTER_PORT);
+    let bindaddr = SocketAddr { ip: Ipv4Addr(0, 0, 0, 0), port: port };
+    let mut socket = match UdpSocket::bind(bindaddr) {
+        Ok(s) => s,
+        Err(e) => fail!("couldn't bind socket: {}", e),
+    };
+    println!("Master server listening on {}", bindaddr);
+
+    // Nothing tells a standalone master server to quit.
+    let (_quit_sender, quit) = channel();
+    masterloop(&mut socket, quit);
+}
+
+/// Sends an out-of-band message, giving up quietly if it can't: whoever
+/// it's for might not be listening, or not even exist.
+fn send_connectionless(socket: &mut Transport, addr: SocketAddr, data: &[u8]) {
+    match send_out_of_band(data) {
+        Ok(datagram) => { let _ = socket.send_to(datagram.as_slice(), addr); },
+        Err(e) => println!("Dropped a {} byte message to {}: {}", data.len(), addr, e)
+    }
+}
+
+/// Runs the master server on any transport until something's sent on `quit`.
+fn masterloop(socket: &mut Transport, quit: Receiver<()>) {
+    // Wake up every now and then to expire servers, even if nothing's coming in.
+    socket.set_read_timeout(Some(1000));
+    let mut registry = registry::ServerRegistry::new(MAX_SERVERS, MAX_UNVERIFIED);
+    let mut recvbuf = [0u8, ..2048];
+
+    loop {
+        match quit.try_recv() {
+            Ok(()) => return,
+            Err(_) => ()
+        }
+
+        let received = socket.recv_from(&mut recvbuf);
+        let now = time::precise_time_s();
+        for addr in registry.expire(now).iter() {
+            println!("{} stopped sending heartbeats", addr);
+        }
+
+        let (len, addr) = match received {
+            Ok(received) => received,
+            Err(ref e) if e.kind == std::io::TimedOut => continue,
+            // e.g. an ICMP unreachable for something we sent; the socket's still fine
+            Err(e) => {
+                println!("Network error: {}", e);
+                continue;
+            }
+        };
+        let datagram = recvbuf.slice_to(len);
+        if !is_out_of_band(datagram) {
+            continue;
+        }
+        let data = match recv_out_of_band(datagram) {
+            Ok(data) => data,
+            Err(_) => continue
+        };
+
+        match decode_to_master(data.as_slice()) {
+            Some(Heartbeat(_)) => match registry.heartbeat(addr, now) {
+                // Make sure it's really a server before listing it.
+                Some(token) => {
+                    let request = InfoRequest(InfoRequestPacket { time: now, token: token });
+                    send_connectionless(socket, addr, encode_client_msg(&request).as_slice());
+                },
+                None => ()
+            },
+            Some(ListRequest(request)) => {
+                let page = ServerList(registry.page(request.start as uint, request.protocol_version));
+                send_connectionless(socket, addr, encode_from_master(&page).as_slice());
+            },
+            None => match decode_server_msg(data.as_slice()) {
+                Some(Info(info)) => {
+                    let name = info.name.clone();
+                    if registry.verify(addr, info, now) {
+                        println!("Listed {} at {} ({} servers)", name, addr, registry.len());
+                    }
+                },
+                _ => println!("Garbage packet from {}", addr)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use shared::network::{decode_client_msg, encode_server_msg, Info, InfoRequest, ServerInfoPacket, PROTOCOL_VERSION};
+    use shared::network::channel::{recv_out_of_band, send_out_of_band};
+    use shared::network::master::{fetch_server_list, send_heartbeat};
+    use shared::network::transport::{MemoryNetwork, Transport};
+    use super::masterloop;
+
+    #[test]
+    fn heartbeat_gets_listed() {
+        let network = MemoryNetwork::new();
+        let mut master = network.bind_any();
+        let master_addr = master.local_addr().unwrap();
+        let (quit_sender, quit) = channel();
+        spawn(proc() {
+            masterloop(&mut master, quit);
+        });
+
+        // We play the game server.
+        let mut server = network.bind_any();
+        let server_addr = server.local_addr().unwrap();
+        server.set_read_timeout(Some(5000));
+        send_heartbeat(&mut server, master_addr).unwrap();
+
+        let mut buf = [0u8, ..2048];
+        let (len, from) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(from, master_addr);
+        let request = match decode_client_msg(recv_out_of_band(buf.slice_to(len)).unwrap().as_slice()) {
+            Some(InfoRequest(request)) => request,
+            _ => fail!("Expected an info request")
+        };
+
+        // Not listed until we answer with the token.
+        let mut client = network.bind_any();
+        assert!(fetch_server_list(&mut client, master_addr, None, 3).unwrap().is_empty());
+
+        let reply = Info(ServerInfoPacket {
+            time: request.time,
+            token: request.token,
+            protocol_version: PROTOCOL_VERSION,
+            name: "test server".to_string(),
+            map: "none".to_string(),
+            players: 0,
+            max_players: 32
+        });
+        let datagram = send_out_of_band(encode_server_msg(&reply).as_slice()).unwrap();
+        server.send_to(datagram.as_slice(), master_addr).unwrap();
+
+        // The master might not have got to it yet.
+        let mut servers = Vec::new();
+        for _ in range(0u, 100) {
+            servers = fetch_server_list(&mut client, master_addr, None, 3).unwrap();
+            if !servers.is_empty() {
+                break;
+            }
+            ::std::io::timer::sleep(::std::time::Duration::milliseconds(10));
+        }
+        assert_eq!(servers, vec![server_addr]);
+
+        quit_sender.send(());
+    }
+}
@@ -0,0 +1,214 @@
+//! Which servers are listed, and which are still proving they exist.
+
+use std::collections::HashMap;
+use std::io::net::ip::SocketAddr;
+use shared::network::ServerInfoPacket;
+use shared::network::challenge::{make_challenge, random_keys, CHALLENGE_LIFETIME};
+use shared::network::master::{ServerListPacket, HEARTBEAT_INTERVAL, LIST_PAGE_SIZE};
+
+/// Servers that miss this many seconds' worth of heartbeats are delisted.
+static SERVER_TIMEOUT: f64 = HEARTBEAT_INTERVAL * 3.;
+/// Seconds a server has to answer our InfoRequest after a heartbeat.
+static VERIFY_TIMEOUT: f64 = 5.;
+
+struct Listing {
+    info: ServerInfoPacket,
+    last_heartbeat: f64
+}
+
+pub struct ServerRegistry {
+    listed: HashMap<SocketAddr, Listing>,
+    /// Servers we've sent an InfoRequest to, when, and the token they
+    /// have to echo.
+    unverified: HashMap<SocketAddr, (f64, u64)>,
+    max_servers: uint,
+    /// Heartbeats are easy to spoof, so checks on new servers get their own
+    /// limit, to keep fake ones from crowding out real listings.
+    max_unverified: uint,
+    /// For making tokens nobody else can guess.
+    keys: (u64, u64)
+}
+
+impl ServerRegistry {
+    pub fn new(max_servers: uint, max_unverified: uint) -> ServerRegistry {
+        ServerRegistry {
+            listed: HashMap::new(),
+            unverified: HashMap::new(),
+            max_servers: max_servers,
+            max_unverified: max_unverified,
+            keys: random_keys()
+        }
+    }
+
+    pub fn len(&self) -> uint {
+        self.listed.len()
+    }
+
+    /// Records a heartbeat from `addr`, sent at `now`. Returns the token to
+    /// put in an InfoRequest to check the server's really there, unless
+    /// we're full or it's already being checked. Servers that are already
+    /// listed always get checked, however many new ones are waiting.
+    pub fn heartbeat(&mut self, addr: SocketAddr, now: f64) -> Option<u64> {
+        if self.unverified.contains_key(&addr) {
+            return None;
+        }
+        if !self.listed.contains_key(&addr)
+            && (self.listed.len() >= self.max_servers || self.unverified.len() >= self.max_unverified) {
+            return None;
+        }
+        let token = make_challenge(self.keys, &addr, (now / CHALLENGE_LIFETIME) as u64);
+        self.unverified.insert(addr, (now, token));
+        Some(token)
+    }
+
+    /// Takes an Info reply from `addr`. Only replies echoing the token from
+    /// our own InfoRequest count, so nobody can list a server from an address
+    /// they don't own. Wrong ones are ignored, rather than ending the check,
+    /// so a spoofed reply can't stop the real server from answering.
+    /// Returns whether the server's newly listed, rather than just refreshed.
+    pub fn verify(&mut self, addr: SocketAddr, info: ServerInfoPacket, now: f64) -> bool {
+        match self.unverified.find_copy(&addr) {
+            Some((_, token)) if info.token == token => (),
+            _ => return false
+        }
+        self.unverified.remove(&addr);
+        self.listed.insert(addr, Listing { info: info, last_heartbeat: now })
+    }
+
+    /// Forgets servers that have gone quiet, and checks that never finished.
+    /// Returns the servers that got delisted.
+    pub fn expire(&mut self, now: f64) -> Vec<SocketAddr> {
+        let stale: Vec<SocketAddr> = self.unverified.iter()
+            .filter(|&(_, &(sent, _))| now - sent > VERIFY_TIMEOUT)
+            .map(|(&addr, _)| addr)
+            .collect();
+        for addr in stale.iter() {
+            self.unverified.remove(addr);
+        }
+
+        let gone: Vec<SocketAddr> = self.listed.iter()
+            .filter(|&(_, listing)| now - listing.last_heartbeat > SERVER_TIMEOUT)
+            .map(|(&addr, _)| addr)
+            .collect();
+        for addr in gone.iter() {
+            self.listed.remove(addr);
+        }
+        gone
+    }
+
+    /// The page of the list starting `start` servers in, optionally only
+    /// counting servers running `protocol_version`.
+    pub fn page(&self, start: uint, protocol_version: Option<u32>) -> ServerListPacket {
+        let mut servers: Vec<String> = self.listed.iter()
+            .filter(|&(_, listing)| protocol_version.map(|version| listing.info.protocol_version == version).unwrap_or(true))
+            .map(|(addr, _)| addr.to_string())
+            .collect();
+        // Sorted, so pages line up between requests.
+        servers.sort();
+
+        let start = ::std::cmp::min(start, servers.len());
+        let end = ::std::cmp::min(start + LIST_PAGE_SIZE, servers.len());
+        ServerListPacket {
+            servers: servers.slice(start, end).to_vec(),
+            next: if end < servers.len() { Some(end as u32) } else { None }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::net::ip::{Ipv4Addr, SocketAddr};
+    use shared::network::{ServerInfoPacket, PROTOCOL_VERSION};
+    use shared::network::master::LIST_PAGE_SIZE;
+    use super::ServerRegistry;
+
+    fn addr(n: u8) -> SocketAddr {
+        SocketAddr { ip: Ipv4Addr(10, 0, 0, n), port: 18295 }
+    }
+
+    fn info(token: u64) -> ServerInfoPacket {
+        ServerInfoPacket {
+            time: 0.,
+            token: token,
+            protocol_version: PROTOCOL_VERSION,
+            name: "test".to_string(),
+            map: "none".to_string(),
+            players: 0,
+            max_players: 32
+        }
+    }
+
+    #[test]
+    fn servers_must_answer_to_be_listed() {
+        let mut registry = ServerRegistry::new(16, 16);
+        let token = registry.heartbeat(addr(1), 100.).unwrap();
+
+        // Unsolicited: somebody's faking it.
+        assert!(!registry.verify(addr(2), info(token), 100.1));
+        assert_eq!(registry.len(), 0);
+
+        assert!(registry.verify(addr(1), info(token), 100.1));
+        assert_eq!(registry.len(), 1);
+        assert_eq!(registry.page(0, None).servers, vec![addr(1).to_string()]);
+
+        // Later heartbeats just keep it listed.
+        let token = registry.heartbeat(addr(1), 160.).unwrap();
+        assert!(!registry.verify(addr(1), info(token), 160.1));
+        assert!(registry.expire(200.).is_empty());
+
+        // Without heartbeats, it eventually drops off.
+        assert_eq!(registry.expire(1000.), vec![addr(1)]);
+        assert_eq!(registry.len(), 0);
+    }
+
+    #[test]
+    fn wrong_token_doesnt_end_the_check() {
+        let mut registry = ServerRegistry::new(16, 16);
+        let token = registry.heartbeat(addr(1), 100.).unwrap();
+        // different servers get different tokens
+        assert!(registry.heartbeat(addr(2), 100.).unwrap() != token);
+
+        // Somebody spoofing the server's reply gets nowhere...
+        assert!(!registry.verify(addr(1), info(token + 1), 100.1));
+        assert_eq!(registry.len(), 0);
+        // ...and the real one still counts.
+        assert!(registry.verify(addr(1), info(token), 100.1));
+        assert_eq!(registry.len(), 1);
+    }
+
+    #[test]
+    fn fake_heartbeats_dont_crowd_out_listed_servers() {
+        let mut registry = ServerRegistry::new(16, 2);
+        let token = registry.heartbeat(addr(1), 0.).unwrap();
+        registry.verify(addr(1), info(token), 0.);
+
+        // Spoofed heartbeats fill up the checks...
+        assert!(registry.heartbeat(addr(2), 1.).is_some());
+        assert!(registry.heartbeat(addr(3), 1.).is_some());
+        assert!(registry.heartbeat(addr(4), 1.).is_none());
+        // ...but a listed server still gets checked, and stays listed.
+        let token = registry.heartbeat(addr(1), 60.).unwrap();
+        assert!(!registry.verify(addr(1), info(token), 60.1));
+        assert_eq!(registry.len(), 1);
+
+        // Once they've timed out, new servers can get in again.
+        assert!(registry.expire(70.).is_empty());
+        assert!(registry.heartbeat(addr(4), 70.).is_some());
+    }
+
+    #[test]
+    fn list_is_paged() {
+        let mut registry = ServerRegistry::new(256, 256);
+        for n in range(0u8, 40) {
+            let token = registry.heartbeat(addr(n), 0.).unwrap();
+            registry.verify(addr(n), info(token), 0.);
+        }
+
+        let first = registry.page(0, None);
+        assert_eq!(first.servers.len(), LIST_PAGE_SIZE);
+        let second = registry.page(first.next.unwrap() as uint, None);
+        assert_eq!(second.servers.len(), 40 - LIST_PAGE_SIZE);
+        assert_eq!(second.next, None);
+        assert!(registry.page(0, Some(PROTOCOL_VERSION + 1)).servers.is_empty());
+    }
+}
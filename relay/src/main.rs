@@ -0,0 +1,542 @@
+//! A relay for spectators, e.g. for tournaments.
+//!
+//! The relay connects to the game server as a spectator, so it's told
+//! about the whole world, and passes that on to as many viewers as want
+//! it, after a delay so nobody can use the broadcast to cheat. Viewers
+//! connect with a normal client and fly around as a camera only they
+//! can see. The game server only ever has one extra client to deal with.
+
+extern crate cgmath;
+extern crate shared;
+extern crate time;
+
+use cgmath::{Point3, Rotation3};
+use shared::{EntityComponent, TICK_LENGTH};
+use shared::component::RawComponentHandle;
+use shared::component::components::PLAYER_ARCHETYPE;
+use shared::playercmd::ControllableComponent;
+use shared::network::{ChallengeResponse, Connect, Disconnect, Playercmd};
+use shared::network::{ChallengePacket, ServerToClient, UpdatePacket, PROTOCOL_VERSION};
+use shared::network::{commands_per_packet, rate_to_interval, MAX_RATE, MIN_RATE};
+use shared::network::challenge::{challenge_period, check_challenge, make_challenge};
+use shared::network::channel::NetChannel;
+use shared::network::delta::RelevanceHistory;
+use shared::network::priority::UpdateScheduler;
+use shared::network::replication::{ReplicationRegistry, MAX_STATES};
+use shared::network::transport::Transport;
+use shared::world::World;
+use std::collections::{Deque, HashMap, HashSet, RingBuf};
+use std::io::net::ip::{Ipv4Addr, SocketAddr};
+use std::io::net::udp::UdpSocket;
+
+mod upstream;
+
+/// How many viewers we'll let in at once.
+static MAX_VIEWERS: uint = 256;
+/// Seconds without hearing from a viewer before we drop it.
+static VIEWER_TIMEOUT: f64 = 15.;
+/// Slowest update and Playercmd rates viewers can ask for, in Hz.
+static MIN_SNAPSHOT_RATE: u32 = 10;
+static MIN_COMMAND_RATE: u32 = 32;
+/// Seconds between signons to viewers that haven't answered yet.
+static SIGNON_INTERVAL: f64 = 0.05;
+/// Default broadcast delay, in seconds.
+static DEFAULT_DELAY: f64 = 30.;
+
+fn main() {
+    let upstream_addr = std::os::getenv("NMIGP_UPSTREAM")
+        .map(|addr| from_str(addr.as_slice()).expect("Bad NMIGP_UPSTREAM"))
+        .unwrap_or(SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: shared::network::DEFAULT_PORT });
+    let port = std::os::getenv("NMIGP_RELAY_PORT").and_then(|port| from_str(port.as_slice())).unwrap_or(18296);
+    let delay = std::os::getenv("NMIGP_RELAY_DELAY").and_then(|delay| from_str(delay.as_slice())).unwrap_or(DEFAULT_DELAY);
+    let name = std::os::getenv("NMIGP_NAME").unwrap_or_else(|| "Relay".to_string());
+    // What the game server lets spectators in with, and what we let viewers in with.
+    let secret = std::os::getenv("NMIGP_SPECTATOR_SECRET").expect("Set NMIGP_SPECTATOR_SECRET to the game server's spectator secret");
+    let viewer_secret = std::os::getenv("NMIGP_VIEWER_SECRET").expect("Set NMIGP_VIEWER_SECRET for viewers to connect with");
+
+    let upstream_socket = match UdpSocket::bind(SocketAddr { ip: Ipv4Addr(0, 0, 0, 0), port: 0 }) {
+        Ok(s) => s,
+        Err(e) => fail!("couldn't bind socket: {}", e),
+    };
+    let upstream = match upstream::Upstream::connect(upstream_socket, upstream_addr, name.as_slice(), secret.as_slice(), delay, 10) {
+        Ok(upstream) => upstream,
+        Err(e) => fail!("Couldn't connect to {}: {}", upstream_addr, e)
+    };
+    println!("Relaying {} with a {}s delay", upstream_addr, delay);
+
+    let bindaddr = SocketAddr { ip: Ipv4Addr(0, 0, 0, 0), port: port };
+    let mut socket = match UdpSocket::bind(bindaddr) {
+        Ok(s) => s,
+        Err(e) => fail!("couldn't bind socket: {}", e),
+    };
+    // Nothing tells a standalone relay to quit.
+    let (_quit_sender, quit) = channel();
+    relayloop(&mut socket, upstream, viewer_secret, quit);
+}
+
+struct Viewer {
+    addr: SocketAddr,
+    name: String,
+    channel: NetChannel,
+
+    /// What the viewer flies around with. Nobody else sees it.
+    camera: ControllableComponent,
+    watching: bool,
+    last_signon: f64,
+    /// The number of the last command we moved the camera with.
+    last_command: u32,
+    command_interval: u32,
+    /// When we last got a valid packet from this viewer.
+    last_recv: f64,
+    /// Which entities this viewer was told about in recent broadcasts.
+    relevance: RelevanceHistory,
+    /// Fits updates into this viewer's bandwidth.
+    scheduler: UpdateScheduler,
+    /// Ticks between updates to this viewer.
+    snapshot_interval: u32,
+    next_snapshot_tick: u64,
+    /// The broadcast each recent update was for, by sequence number,
+    /// so we know how far back the viewer's baseline is.
+    snapshots: RingBuf<(u32, u64)>
+}
+
+fn send_connectionless(socket: &mut Transport, addr: SocketAddr, msg: &ServerToClient) {
+    use shared::network::channel::send_out_of_band;
+    let datagram = send_out_of_band(shared::network::encode_server_msg(msg).as_slice()).unwrap();
+    let _ = socket.send_to(datagram.as_slice(), addr);
+}
+
+/// Messages too big to fragment are dropped, returning false; the next
+/// update deltas against an older baseline and catches the viewer up.
+fn send_to_viewer(socket: &mut Transport, viewer: &mut Viewer, msg: &ServerToClient) -> bool {
+    let packet = shared::network::encode_server_msg(msg);
+    let datagrams = match viewer.channel.send_unreliable(packet.as_slice()) {
+        Ok(datagrams) => datagrams,
+        Err(e) => {
+            println!("Dropped a {} byte message to {}: {}", packet.len(), viewer.name, e);
+            return false;
+        }
+    };
+    for datagram in datagrams.iter() {
+        let _ = socket.send_to(datagram.as_slice(), viewer.addr);
+    }
+    true
+}
+
+/// Ticks between updates for a viewer that wants `snapshot_rate` of them a
+/// second. We only have something to send when the game server updates us,
+/// so it's rounded up to a whole number of `upstream_interval`s.
+fn viewer_snapshot_interval(snapshot_rate: u32, upstream_interval: u32) -> u32 {
+    let wanted = rate_to_interval(std::cmp::max(snapshot_rate, MIN_SNAPSHOT_RATE));
+    let upstream_interval = std::cmp::max(upstream_interval, 1);
+    (wanted + upstream_interval - 1) / upstream_interval * upstream_interval
+}
+
+/// The newest broadcast the viewer is known to have, with the sequence
+/// number it went out in, if it's acked any of the ones in `snapshots`.
+fn newest_acked(snapshots: &RingBuf<(u32, u64)>, is_acked: |u32| -> bool) -> Option<(u32, u64)> {
+    snapshots.iter().rev().find(|&&(seq, _)| is_acked(seq)).map(|&x| x)
+}
+
+/// How many broadcasts back an update for a viewer whose newest known
+/// broadcast is `baseline` has to cover, out of `broadcasts` so far.
+fn delta_length(baseline: Option<(u32, u64)>, broadcasts: u64) -> u64 {
+    match baseline {
+        Some((_, acked)) => std::cmp::max(broadcasts - acked, 1),
+        None => MAX_STATES as u64
+    }
+}
+
+fn drop_viewer(socket: &mut Transport, viewers: &mut HashMap<SocketAddr, Viewer>, addr: SocketAddr, reason: String, world: &mut World) {
+    let mut viewer = match viewers.pop(&addr) {
+        Some(viewer) => viewer,
+        None => return
+    };
+    println!("{} stopped watching: {}", viewer.name, reason);
+    send_to_viewer(socket, &mut viewer, &shared::network::Disconnected(reason));
+    world.entities.remove(viewer.camera.entity);
+}
+
+fn drop_all_viewers(socket: &mut Transport, viewers: &mut HashMap<SocketAddr, Viewer>, reason: String, world: &mut World) {
+    let addrs: Vec<SocketAddr> = viewers.keys().map(|&addr| addr).collect();
+    for addr in addrs.into_iter() {
+        drop_viewer(socket, viewers, addr, reason.clone(), world);
+    }
+}
+
+/// Relays the game to viewers on `socket` until the game server goes away,
+/// the socket fails, or something's sent on `quit`. Only viewers that know
+/// `viewer_secret` get let in.
+fn relayloop<T: Transport>(socket: &mut Transport, mut upstream: upstream::Upstream<T>, viewer_secret: String, quit: Receiver<()>) {
+    socket.set_read_timeout(Some(0));
+
+    // What the game looked like `delay` seconds ago.
+    let mut world = World::new();
+    // Reads updates from the game server into the world...
+    let mut mirror = ReplicationRegistry::with_default_components();
+    // ...and makes updates for viewers out of it.
+    let mut broadcast = ReplicationRegistry::with_default_components();
+    // How many states `broadcast` has been given.
+    let mut broadcasts = 0u64;
+    let mut current_tick = upstream.signon.tick;
+
+    let mut viewers: HashMap<SocketAddr, Viewer> = HashMap::new();
+    let challenge_keys = shared::network::challenge::random_keys();
+
+    loop {
+        std::io::timer::sleep(std::time::Duration::milliseconds(1));
+        let now = time::precise_time_s();
+
+        match quit.try_recv() {
+            Ok(()) => {
+                drop_all_viewers(socket, &mut viewers, "Relay shutting down".to_string(), &mut world);
+                upstream.disconnect("Relay shutting down");
+                return;
+            },
+            Err(_) => ()
+        }
+
+        match upstream.poll(now) {
+            Ok(()) => (),
+            Err(reason) => {
+                drop_all_viewers(socket, &mut viewers, format!("Game server went away: {}", reason), &mut world);
+                return;
+            }
+        }
+
+        let mut to_drop = Vec::new();
+        let mut socket_error = None;
+
+        // incoming packets from viewers
+        let mut recvbuf = [0u8, ..8192];
+        loop { match socket.recv_from(&mut recvbuf) {
+            Ok((len, addr)) => {
+                use shared::network::channel::{is_out_of_band, recv_out_of_band};
+                use shared::network::decode_client_msg;
+
+                let data = recvbuf.as_slice().slice_to(len);
+
+                if is_out_of_band(data) {
+                    let msg = recv_out_of_band(data).ok().and_then(|data| decode_client_msg(data.as_slice()));
+
+                    match msg {
+                        Some(Connect(connect)) => {
+                            let reply = if connect.protocol_version != PROTOCOL_VERSION {
+                                shared::network::Reject(format!("Relay is running protocol version {}, but you have version {}.",
+                                                                PROTOCOL_VERSION, connect.protocol_version))
+                            } else {
+                                shared::network::Challenge(ChallengePacket {
+                                    challenge: make_challenge(challenge_keys, &addr, challenge_period())
+                                })
+                            };
+                            send_connectionless(socket, addr, &reply);
+                        },
+                        Some(ChallengeResponse(response)) => {
+                            if response.protocol_version != PROTOCOL_VERSION {
+                                send_connectionless(socket, addr, &shared::network::Reject(
+                                    format!("Relay is running protocol version {}, but you have version {}.",
+                                            PROTOCOL_VERSION, response.protocol_version)));
+                            } else if !check_challenge(challenge_keys, &addr, response.challenge) {
+                                println!("Bad challenge from {}", addr);
+                            } else if viewers.contains_key(&addr) {
+                                // Already watching, they just haven't gotten a signon yet.
+                            } else if response.spectator_secret.as_ref() != Some(&viewer_secret) {
+                                println!("Bad viewer secret from {}", addr);
+                                send_connectionless(socket, addr, &shared::network::Reject("Wrong secret for this relay.".to_string()));
+                            } else if viewers.len() >= MAX_VIEWERS {
+                                send_connectionless(socket, addr, &shared::network::Reject("Relay is full.".to_string()));
+                            } else {
+                                println!("{} is watching from {}", response.name, addr);
+                                let camera = EntityComponent::new(&mut world.entities,
+                                                                  PLAYER_ARCHETYPE,
+                                                                  Point3::new(0.0, 5., 0.0),
+                                                                  Rotation3::from_euler(cgmath::rad(0.), cgmath::rad(0.), cgmath::rad(0.)));
+                                viewers.insert(addr, Viewer {
+                                    addr: addr,
+                                    name: response.name,
+                                    channel: NetChannel::new(),
+                                    camera: ControllableComponent::new(camera),
+                                    watching: false,
+                                    last_signon: 0.,
+                                    last_command: 0,
+                                    command_interval: rate_to_interval(std::cmp::max(response.command_rate, MIN_COMMAND_RATE)),
+                                    last_recv: now,
+                                    relevance: RelevanceHistory::new(MAX_STATES),
+                                    scheduler: UpdateScheduler::new(std::cmp::min(std::cmp::max(response.rate, MIN_RATE), MAX_RATE)),
+                                    snapshot_interval: viewer_snapshot_interval(response.snapshot_rate, upstream.signon.snapshot_interval),
+                                    next_snapshot_tick: 0,
+                                    snapshots: RingBuf::new()
+                                });
+                            }
+                        },
+                        Some(_) => (), // needs a connection first
+                        None => println!("Garbage connectionless packet from {}", addr)
+                    }
+                    continue;
+                }
+
+                let viewer = match viewers.find_mut(&addr) {
+                    Some(viewer) => viewer,
+                    None => continue
+                };
+
+                let data = match viewer.channel.recv_unreliable(data) {
+                    Ok(Some(data)) => data,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        println!("Bad packet from {}: {}", addr, e);
+                        continue;
+                    }
+                };
+                viewer.last_recv = now;
+
+                match decode_client_msg(data.as_slice()) {
+                    Some(Playercmd(mut cmds)) => {
+                        use shared::network::channel::overflow_aware_compare;
+                        use shared::playercmd::{run_command, sanitize_command, Clean, Fixed, Rejected};
+
+                        let max_commands = commands_per_packet(viewer.command_interval);
+                        if cmds.len() > max_commands {
+                            let excess = cmds.len() - max_commands;
+                            cmds = cmds.slice_from(excess).to_vec();
+                        }
+                        cmds.sort_by(|a, b| overflow_aware_compare(a.number, b.number));
+
+                        // Cameras don't affect the game, so there's no need to
+                        // buffer commands; new ones are run as soon as they come in.
+                        for &cmd in cmds.iter() {
+                            if overflow_aware_compare(cmd.number, viewer.last_command) != std::cmp::Greater {
+                                continue;
+                            }
+                            viewer.last_command = cmd.number;
+                            match sanitize_command(cmd) {
+                                Clean(cmd) | Fixed(cmd, _) => run_command(cmd, &mut viewer.camera, &mut world.entities),
+                                Rejected(_) => ()
+                            }
+                        }
+                        viewer.watching = true;
+                    },
+                    Some(Disconnect(reason)) => to_drop.push((addr, reason)),
+                    Some(_) => (),
+                    None => println!("Garbage packet from {}", addr)
+                }
+            },
+            Err(ref e) if e.kind == std::io::TimedOut => break,
+            Err(e) => {
+                socket_error = Some(e);
+                break;
+            }
+        }}
+
+        match socket_error {
+            Some(e) => {
+                println!("Network error: {}", e);
+                drop_all_viewers(socket, &mut viewers, "Relay shutting down".to_string(), &mut world);
+                upstream.disconnect("Relay shutting down");
+                return;
+            },
+            None => ()
+        }
+
+        for (&addr, viewer) in viewers.iter() {
+            if now - viewer.last_recv > VIEWER_TIMEOUT {
+                to_drop.push((addr, "Timed out".to_string()));
+            }
+        }
+        for (addr, reason) in to_drop.into_iter() {
+            drop_viewer(socket, &mut viewers, addr, reason, &mut world);
+        }
+
+        // Everything that's come out the other end of the delay goes out to viewers.
+        loop {
+            let update = match upstream.next_due(now) {
+                Some(update) => update,
+                None => break
+            };
+            current_tick = update.tick;
+            match update.all_entities {
+                Some(ref all) => mirror.retain_entities(all.as_slice(), &mut world),
+                None => ()
+            }
+            mirror.apply_update(update.component_updates, &mut world);
+            broadcast.add_state(&world);
+            broadcasts += 1;
+
+            let cameras: HashSet<RawComponentHandle> = viewers.values().map(|viewer| viewer.camera.entity.to_raw()).collect();
+            let everything: HashSet<RawComponentHandle> = world.entities.iter()
+                .map(|(handle, _)| handle.to_raw())
+                .filter(|handle| !cameras.contains(handle))
+                .collect();
+            let entity_map: HashMap<_, EntityComponent> = world.entities.iter()
+                .map(|(handle, ent)| (handle.to_raw(), ent.clone()))
+                .collect();
+
+            for (_, viewer) in viewers.iter_mut() {
+                let mut relevant = everything.clone();
+                relevant.insert(viewer.camera.entity.to_raw());
+                viewer.relevance.push(relevant);
+
+                if !viewer.watching || current_tick < viewer.next_snapshot_tick {
+                    continue;
+                }
+                viewer.next_snapshot_tick = current_tick + viewer.snapshot_interval as u64;
+
+                let sequence = viewer.channel.get_outgoing_sequencenr() + 1;
+                // Delta from the newest broadcast we know the viewer has, or send everything.
+                let baseline = newest_acked(&viewer.snapshots, |seq| viewer.channel.is_acked(seq));
+                let length = delta_length(baseline, broadcasts);
+                let updates = broadcast.collect_updates(length, &viewer.relevance);
+                let interval = viewer.snapshot_interval as f64 * TICK_LENGTH as f64;
+                let updates = viewer.scheduler.schedule(updates, &broadcast, &viewer.relevance,
+                                                        Some(viewer.camera.entity.to_raw()), &entity_map,
+                                                        sequence, baseline.map(|(seq, _)| seq), interval);
+
+                let update = shared::network::Update(UpdatePacket {
+                    tick: current_tick,
+                    last_command: viewer.last_command,
+                    component_updates: broadcast.pack_updates(updates),
+                    all_entities: broadcast.all_entities(length, &viewer.relevance)
+                });
+                if !send_to_viewer(socket, viewer, &update) {
+                    viewer.scheduler.dropped(sequence);
+                    continue;
+                }
+
+                viewer.snapshots.push((sequence, broadcasts));
+                while viewer.snapshots.len() > MAX_STATES {
+                    viewer.snapshots.pop_front();
+                }
+            }
+        }
+
+        for (_, viewer) in viewers.iter_mut() {
+            if viewer.watching || now - viewer.last_signon < SIGNON_INTERVAL {
+                continue;
+            }
+            viewer.last_signon = now;
+            let signon = shared::network::Signon(shared::network::SignonPacket {
+                tick: current_tick,
+                handle: Some(viewer.camera.entity.to_raw()),
+                snapshot_interval: viewer.snapshot_interval,
+                command_interval: viewer.command_interval
+            });
+            send_to_viewer(socket, viewer, &signon);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::RingBuf;
+    use std::io::net::ip::SocketAddr;
+    use shared::network::{decode_client_msg, encode_server_msg, ClientToServer, PROTOCOL_VERSION};
+    use shared::network::{Challenge, ChallengeResponse, Connect, Disconnect, Signon};
+    use shared::network::{ChallengePacket, SignonPacket};
+    use shared::network::channel::{is_out_of_band, recv_out_of_band, send_out_of_band, NetChannel};
+    use shared::network::replication::MAX_STATES;
+    use shared::network::transport::{MemoryNetwork, MemoryTransport, Transport};
+    use super::{delta_length, newest_acked, relayloop, viewer_snapshot_interval};
+    use upstream::Upstream;
+
+    #[test]
+    fn baseline_is_newest_acked_broadcast() {
+        let mut snapshots = RingBuf::new();
+        // packets 10, 11 and 12 carried broadcasts 3, 4 and 6
+        snapshots.push((10u32, 3u64));
+        snapshots.push((11, 4));
+        snapshots.push((12, 6));
+
+        // nothing acked yet, so everything goes
+        let baseline = newest_acked(&snapshots, |_| false);
+        assert_eq!(baseline, None);
+        assert_eq!(delta_length(baseline, 7), MAX_STATES as u64);
+
+        // 12 was lost, but 11 got there
+        let baseline = newest_acked(&snapshots, |seq| seq == 10 || seq == 11);
+        assert_eq!(baseline, Some((11, 4)));
+        assert_eq!(delta_length(baseline, 7), 3);
+
+        // caught up: still send the newest broadcast
+        let baseline = newest_acked(&snapshots, |_| true);
+        assert_eq!(delta_length(baseline, 6), 1);
+    }
+
+    #[test]
+    fn viewer_interval_fits_upstream() {
+        // as fast as the game server sends to us, and no faster
+        assert_eq!(viewer_snapshot_interval(1000, 2), 2);
+        // 32Hz is every 4 ticks, which with updates every 3 is really every 6
+        assert_eq!(viewer_snapshot_interval(32, 3), 6);
+        assert_eq!(viewer_snapshot_interval(32, 1), 4);
+    }
+
+    /// Waits for the next message from the relay, out-of-band or not.
+    fn recv(game: &mut MemoryTransport, channel: &mut NetChannel) -> (ClientToServer, SocketAddr) {
+        let mut buf = [0u8, ..8192];
+        loop {
+            let (len, from) = game.recv_from(&mut buf).unwrap();
+            let datagram = buf.slice_to(len);
+            let packet = if is_out_of_band(datagram) {
+                recv_out_of_band(datagram).unwrap()
+            } else {
+                match channel.recv_unreliable(datagram).unwrap() {
+                    Some(packet) => packet,
+                    None => continue
+                }
+            };
+            return (decode_client_msg(packet.as_slice()).expect("Garbage from the relay"), from);
+        }
+    }
+
+    #[test]
+    fn says_goodbye_upstream_on_quit() {
+        let network = MemoryNetwork::new();
+        // We play the game server.
+        let mut game = network.bind_any();
+        let game_addr = game.local_addr().unwrap();
+        game.set_read_timeout(Some(5000));
+        let upstream_socket = network.bind_any();
+        let mut socket = network.bind_any();
+        let (quit_sender, quit) = channel();
+        spawn(proc() {
+            let upstream = Upstream::connect(upstream_socket, game_addr, "Relay", "hunter2", 0., 100).unwrap();
+            relayloop(&mut socket, upstream, "viewers".to_string(), quit);
+        });
+
+        let mut channel = NetChannel::new();
+        loop {
+            let (msg, from) = recv(&mut game, &mut channel);
+            let reply = match msg {
+                Connect(_) => Challenge(ChallengePacket { challenge: 1234 }),
+                ChallengeResponse(response) => {
+                    assert_eq!(response.protocol_version, PROTOCOL_VERSION);
+                    assert_eq!(response.spectator_secret, Some("hunter2".to_string()));
+                    Signon(SignonPacket {
+                        tick: 0,
+                        handle: None,
+                        snapshot_interval: 1,
+                        command_interval: 1
+                    })
+                },
+                // the relay's keepalive: it's signed on
+                _ => break
+            };
+            let packet = encode_server_msg(&reply);
+            let datagrams = match reply {
+                Signon(_) => channel.send_unreliable(packet.as_slice()).unwrap(),
+                _ => vec![send_out_of_band(packet.as_slice()).unwrap()]
+            };
+            for datagram in datagrams.iter() {
+                game.send_to(datagram.as_slice(), from).unwrap();
+            }
+        }
+
+        quit_sender.send(());
+        loop {
+            match recv(&mut game, &mut channel) {
+                (Disconnect(_), _) => break,
+                _ => ()
+            }
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! The relay's connection to the game server, as a spectator.
+
+use std::collections::{Deque, RingBuf};
+use std::io::net::ip::SocketAddr;
+use shared::network::{ChallengeResponsePacket, ConnectPacket, SignonPacket, UpdatePacket, PROTOCOL_VERSION};
+use shared::network::{Challenge, Disconnected, Reject, Signon, Update};
+use shared::network::{decode_server_msg, encode_client_msg};
+use shared::network::channel::{is_out_of_band, recv_out_of_band, send_out_of_band, NetChannel};
+use shared::network::transport::Transport;
+
+/// What we ask the game server for. It'll clamp the rate to what it allows.
+static UPSTREAM_RATE: u32 = 1024 * 1024;
+/// Seconds between keepalives when the game server's gone quiet, well
+/// under how long it waits before it stops sending to us.
+static KEEPALIVE_INTERVAL: f64 = 0.25;
+
+/// Holds things back until they're `delay` seconds old.
+pub struct DelayQueue<T> {
+    delay: f64,
+    /// With when they came in, oldest first.
+    queue: RingBuf<(f64, T)>
+}
+
+impl<T> DelayQueue<T> {
+    pub fn new(delay: f64) -> DelayQueue<T> {
+        DelayQueue {
+            delay: delay,
+            queue: RingBuf::new()
+        }
+    }
+
+    pub fn push(&mut self, now: f64, item: T) {
+        self.queue.push((now, item));
+    }
+
+    /// The oldest thing that's been held back long enough, if any.
+    pub fn next_due(&mut self, now: f64) -> Option<T> {
+        let due = match self.queue.front() {
+            Some(&(arrived, _)) => now - arrived >= self.delay,
+            None => false
+        };
+        if due {
+            self.queue.pop_front().map(|(_, item)| item)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct Upstream<T> {
+    socket: T,
+    addr: SocketAddr,
+    channel: NetChannel,
+    pub signon: SignonPacket,
+    /// Updates waiting out the broadcast delay.
+    delayed: DelayQueue<UpdatePacket>,
+    next_keepalive: f64
+}
+
+impl<T: Transport> Upstream<T> {
+    /// Signs on to the game server at `addr` as a spectator, over `socket`,
+    /// with the secret it's been configured to let spectators in with.
+    /// Updates are held back for `delay` seconds.
+    pub fn connect(mut socket: T, addr: SocketAddr, name: &str, secret: &str, delay: f64, mut retries: u32) -> Result<Upstream<T>, String> {
+        let mut channel = NetChannel::new();
+        let mut challenge = None;
+
+        while retries > 0 {
+            let msg = match challenge {
+                None => ::shared::network::Connect(ConnectPacket {
+                    protocol_version: PROTOCOL_VERSION,
+                    name: name.to_string()
+                }),
+                Some(challenge) => ::shared::network::ChallengeResponse(ChallengeResponsePacket {
+                    challenge: challenge,
+                    protocol_version: PROTOCOL_VERSION,
+                    name: name.to_string(),
+                    rate: UPSTREAM_RATE,
+                    snapshot_rate: ::shared::network::DEFAULT_SNAPSHOT_RATE,
+                    command_rate: ::shared::network::DEFAULT_COMMAND_RATE,
+                    spectator_secret: Some(secret.to_string())
+                })
+            };
+            let datagram = send_out_of_band(encode_client_msg(&msg).as_slice()).unwrap();
+            let _ = socket.send_to(datagram.as_slice(), addr);
+
+            let mut recvbuf = [0u8, ..16384];
+            socket.set_read_timeout(Some(100));
+            match socket.recv_from(&mut recvbuf) {
+                Ok((_, from)) if from != addr => continue,
+                Ok((len, _)) => {
+                    let datagram = recvbuf.as_slice().slice_to(len);
+                    let packet = if is_out_of_band(datagram) {
+                        match recv_out_of_band(datagram) {
+                            Ok(packet) => packet,
+                            Err(_) => continue
+                        }
+                    } else {
+                        match channel.recv_unreliable(datagram) {
+                            Ok(Some(packet)) => packet,
+                            _ => continue
+                        }
+                    };
+
+                    match decode_server_msg(packet.as_slice()) {
+                        Some(Challenge(c)) => {
+                            challenge = Some(c.challenge);
+                            continue;
+                        },
+                        Some(Reject(reason)) => return Err(format!("Server rejected us: {}", reason)),
+                        Some(Signon(signon)) => {
+                            socket.set_read_timeout(Some(0));
+                            let mut upstream = Upstream {
+                                socket: socket,
+                                addr: addr,
+                                channel: channel,
+                                signon: signon,
+                                delayed: DelayQueue::new(delay),
+                                next_keepalive: 0.
+                            };
+                            // The server starts sending updates once it's heard from us.
+                            upstream.keepalive(::time::precise_time_s());
+                            return Ok(upstream);
+                        },
+                        _ => ()
+                    }
+                },
+                Err(ref e) if e.kind == ::std::io::TimedOut => (),
+                Err(e) => return Err(format!("Network error: {}", e))
+            }
+            retries -= 1;
+        }
+
+        Err("Out of retries".to_string())
+    }
+
+    /// Spectators don't have commands, but an empty Playercmd keeps the
+    /// connection alive and acks the updates we've gotten.
+    fn keepalive(&mut self, now: f64) {
+        self.next_keepalive = now + KEEPALIVE_INTERVAL;
+        let packet = encode_client_msg(&::shared::network::Playercmd(Vec::new()));
+        for datagram in self.channel.send_unreliable(packet.as_slice()).unwrap().iter() {
+            let _ = self.socket.send_to(datagram.as_slice(), self.addr);
+        }
+    }
+
+    /// Takes in whatever the game server has sent. Errors if it's dropped us.
+    pub fn poll(&mut self, now: f64) -> Result<(), String> {
+        let mut buf = [0u8, ..16384];
+        // Anything we should answer right away: updates to ack, or a
+        // signon that means the server hasn't heard from us yet.
+        let mut answer = false;
+        loop { match self.socket.recv_from(&mut buf) {
+            Ok((_, from)) if from != self.addr => (),
+            Ok((len, _)) => {
+                let packet = match self.channel.recv_unreliable(buf.slice_to(len)) {
+                    Ok(Some(packet)) => packet,
+                    // waiting on more fragments, or a leftover connectionless packet
+                    _ => continue
+                };
+                match decode_server_msg(packet.as_slice()) {
+                    Some(Update(update)) => {
+                        self.delayed.push(now, update);
+                        answer = true;
+                    },
+                    Some(Signon(_)) => answer = true,
+                    Some(Disconnected(reason)) => return Err(reason),
+                    Some(_) => (),
+                    None => println!("Garbage packet from the game server")
+                }
+            },
+            Err(ref e) if e.kind == ::std::io::TimedOut => break,
+            Err(e) => return Err(format!("Network error: {}", e))
+        } }
+
+        if answer || now >= self.next_keepalive {
+            self.keepalive(now);
+        }
+        Ok(())
+    }
+
+    /// The oldest update that's been held back long enough, if any.
+    pub fn next_due(&mut self, now: f64) -> Option<UpdatePacket> {
+        self.delayed.next_due(now)
+    }
+
+    /// Says goodbye a few times, in case some get lost.
+    pub fn disconnect(&mut self, reason: &str) {
+        let goodbye = encode_client_msg(&::shared::network::Disconnect(reason.to_string()));
+        for _ in range(0u, 3) {
+            for datagram in self.channel.send_unreliable(goodbye.as_slice()).unwrap().iter() {
+                let _ = self.socket.send_to(datagram.as_slice(), self.addr);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DelayQueue;
+
+    #[test]
+    fn held_back_for_the_delay() {
+        let mut queue = DelayQueue::new(30.);
+        queue.push(0., 1u);
+        queue.push(0.5, 2u);
+        assert_eq!(queue.next_due(29.9), None);
+        assert_eq!(queue.next_due(30.), Some(1));
+        // the second one came in later, so it's not due yet
+        assert_eq!(queue.next_due(30.), None);
+        assert_eq!(queue.next_due(31.), Some(2));
+        assert_eq!(queue.next_due(100.), None);
+    }
+
+    #[test]
+    fn everything_due_comes_out_in_order() {
+        let mut queue = DelayQueue::new(1.);
+        for i in range(0u, 5) {
+            queue.push(i as f64 * 0.1, i);
+        }
+        let mut due = Vec::new();
+        loop {
+            match queue.next_due(1.25) {
+                Some(i) => due.push(i),
+                None => break
+            }
+        }
+        assert_eq!(due, vec![0, 1, 2]);
+    }
+}
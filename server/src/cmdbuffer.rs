@@ -0,0 +1,267 @@
+use std::cmp::{max, min, Greater};
+use std::collections::{Deque, RingBuf};
+use shared::network::channel::overflow_aware_compare;
+use shared::playercmd::PlayerCommand;
+
+/// The fewest and most commands we'll try to keep queued.
+static MIN_DEPTH: uint = 1;
+static MAX_DEPTH: uint = 8;
+/// How many commands past the target depth we'll queue before throwing old
+/// ones away, on top of a whole batch of them arriving at once.
+static OVERFLOW_SLACK: uint = 2;
+/// How many ticks we watch the queue for before adjusting its depth.
+static ADAPT_INTERVAL: uint = 128;
+
+#[deriving(Clone, Show)]
+pub struct CommandBufferStats {
+    pub executed: u64,
+    /// Commands that never arrived, even redundantly.
+    pub lost: u64,
+    /// How many times the queue ran dry.
+    pub starved: u64,
+    /// Commands thrown away because too many were queued.
+    pub overflowed: u64,
+    pub target_depth: uint
+}
+
+/// Queues a client's commands as they arrive, and hands them out
+/// one per tick, so network jitter doesn't change how many moves
+/// a player gets each tick.
+///
+/// The buffer tries to keep `target_depth` commands queued. Running dry
+/// makes it deeper, and always having spare commands makes it shallower.
+/// If far too many pile up, the oldest are thrown away rather than run:
+/// a client sending commands faster than the tick rate (a speedhack, or
+/// just a fast clock) shouldn't get to move faster. Its prediction gets
+/// corrected once it hears which command ran.
+pub struct CommandBuffer {
+    queue: RingBuf<PlayerCommand>,
+    last_queued: u32,
+    last_executed: u32,
+
+    target_depth: uint,
+    /// How far past the target depth the queue can get before it's overflowing.
+    slack: uint,
+    /// Set after running dry. No commands are handed out until
+    /// the queue is back up to the target depth.
+    refilling: bool,
+
+    ticks_since_adapt: uint,
+    starved_since_adapt: bool,
+    min_depth_since_adapt: uint,
+
+    stats: CommandBufferStats
+}
+
+impl CommandBuffer {
+    /// `command_interval` is how many ticks' worth of commands the client
+    /// sends in each packet.
+    pub fn new(command_interval: u32) -> CommandBuffer {
+        CommandBuffer {
+            queue: RingBuf::new(),
+            last_queued: 0,
+            last_executed: 0,
+
+            target_depth: 2,
+            slack: OVERFLOW_SLACK + command_interval as uint,
+            refilling: true,
+
+            ticks_since_adapt: 0,
+            starved_since_adapt: false,
+            min_depth_since_adapt: ::std::uint::MAX,
+
+            stats: CommandBufferStats {
+                executed: 0,
+                lost: 0,
+                starved: 0,
+                overflowed: 0,
+                target_depth: 2
+            }
+        }
+    }
+
+    /// Queues a command, unless we've already seen it.
+    /// Commands must be pushed oldest first.
+    pub fn push(&mut self, cmd: PlayerCommand) -> bool {
+        if overflow_aware_compare(cmd.number, self.last_queued) != Greater {
+            return false;
+        }
+        self.stats.lost += (cmd.number - self.last_queued - 1) as u64;
+        self.last_queued = cmd.number;
+        self.queue.push(cmd);
+        true
+    }
+
+    /// Takes the command to run this tick, if there is one.
+    pub fn next(&mut self) -> Option<PlayerCommand> {
+        if self.refilling && self.queue.len() >= self.target_depth {
+            self.refilling = false;
+        }
+
+        let cmd = if self.refilling {
+            None
+        } else {
+            self.min_depth_since_adapt = min(self.min_depth_since_adapt, self.queue.len());
+            while self.queue.len() > self.target_depth + self.slack {
+                self.queue.pop_front();
+                self.stats.overflowed += 1;
+            }
+            match self.queue.pop_front() {
+                Some(cmd) => Some(cmd),
+                None => {
+                    self.stats.starved += 1;
+                    self.starved_since_adapt = true;
+                    self.refilling = true;
+                    None
+                }
+            }
+        };
+        match cmd {
+            Some(cmd) => {
+                self.last_executed = cmd.number;
+                self.stats.executed += 1;
+            },
+            None => ()
+        }
+
+        self.adapt();
+        cmd
+    }
+
+    fn adapt(&mut self) {
+        self.ticks_since_adapt += 1;
+        if self.ticks_since_adapt < ADAPT_INTERVAL {
+            return;
+        }
+
+        if self.starved_since_adapt {
+            self.target_depth = min(self.target_depth + 1, MAX_DEPTH);
+        } else if self.min_depth_since_adapt != ::std::uint::MAX && self.min_depth_since_adapt > self.target_depth {
+            // We always had commands to spare, so we can afford less latency.
+            self.target_depth = max(self.target_depth - 1, MIN_DEPTH);
+        }
+        self.stats.target_depth = self.target_depth;
+
+        self.ticks_since_adapt = 0;
+        self.starved_since_adapt = false;
+        self.min_depth_since_adapt = ::std::uint::MAX;
+    }
+
+    /// The number of the last command that was run.
+    pub fn last_executed(&self) -> u32 {
+        self.last_executed
+    }
+
+    pub fn stats(&self) -> CommandBufferStats {
+        self.stats.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cgmath::{Quaternion, Vector3};
+    use shared::playercmd::PlayerCommand;
+    use super::{CommandBuffer, ADAPT_INTERVAL};
+
+    fn cmd(number: u32) -> PlayerCommand {
+        PlayerCommand {
+            number: number,
+            tick: 0,
+            angles: Quaternion::new(1., 0., 0., 0.),
+            movement: Vector3::new(0., 0., 0.)
+        }
+    }
+
+    #[test]
+    fn one_command_per_tick() {
+        let mut buffer = CommandBuffer::new(1);
+        for i in range(1u32, 4) {
+            assert!(buffer.push(cmd(i)));
+        }
+        // redundant copies are ignored
+        assert!(!buffer.push(cmd(2)));
+
+        assert_eq!(buffer.next().unwrap().number, 1);
+        assert_eq!(buffer.next().unwrap().number, 2);
+        assert_eq!(buffer.last_executed(), 2);
+        assert_eq!(buffer.stats().executed, 2);
+    }
+
+    #[test]
+    fn waits_to_refill_after_starving() {
+        let mut buffer = CommandBuffer::new(1);
+        buffer.push(cmd(1));
+        assert!(buffer.next().is_none()); // not up to depth yet
+        buffer.push(cmd(2));
+        assert_eq!(buffer.next().unwrap().number, 1);
+        assert_eq!(buffer.next().unwrap().number, 2);
+        assert!(buffer.next().is_none());
+        assert_eq!(buffer.stats().starved, 1);
+    }
+
+    #[test]
+    fn overflow_drops_oldest() {
+        let mut buffer = CommandBuffer::new(1);
+        for i in range(1u32, 11) {
+            buffer.push(cmd(i));
+        }
+        let kept = buffer.stats().target_depth + super::OVERFLOW_SLACK + 1;
+        // the oldest are thrown away, and only one command runs
+        let first = 10 - kept as u32 + 1;
+        assert_eq!(buffer.next().unwrap().number, first);
+        assert_eq!(buffer.stats().overflowed, 10 - kept as u64);
+        assert_eq!(buffer.stats().executed, 1);
+        assert_eq!(buffer.last_executed(), first);
+        assert_eq!(buffer.next().unwrap().number, first + 1);
+    }
+
+    #[test]
+    fn fast_clients_dont_move_faster() {
+        let mut buffer = CommandBuffer::new(1);
+        let mut number = 0u32;
+        let mut ran = 0u;
+        // 1.2 commands a tick, just under what the rate limiter catches
+        for tick in range(0u, 1000) {
+            let due = (tick + 1) * 6 / 5;
+            while (number as uint) < due {
+                number += 1;
+                buffer.push(cmd(number));
+            }
+            if buffer.next().is_some() {
+                ran += 1;
+            }
+        }
+        // everything that didn't run or isn't still queued was thrown away
+        assert!(ran <= 1000);
+        assert!(buffer.stats().overflowed >= 1200 - 1000 - 12);
+    }
+
+    #[test]
+    fn batches_dont_overflow() {
+        // four ticks' worth of commands per packet
+        let mut buffer = CommandBuffer::new(4);
+        let target = buffer.stats().target_depth as u32;
+        // a whole batch turns up while the target depth's still queued
+        for i in range(1u32, target + 4 + 1) {
+            buffer.push(cmd(i));
+        }
+        assert!(buffer.next().is_some());
+        assert_eq!(buffer.stats().overflowed, 0);
+    }
+
+    #[test]
+    fn deepens_after_starving() {
+        let mut buffer = CommandBuffer::new(1);
+        let before = buffer.stats().target_depth;
+        let mut number = 0u32;
+        // only deliver a command every other tick, so it keeps running dry
+        for tick in range(0u, ADAPT_INTERVAL) {
+            if tick % 2 == 0 {
+                number += 1;
+                buffer.push(cmd(number));
+            }
+            buffer.next();
+        }
+        assert!(buffer.stats().target_depth > before);
+    }
+}
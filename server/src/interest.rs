@@ -0,0 +1,100 @@
+use std::collections::HashSet;
+use cgmath::{EuclideanVector, Point, Point3};
+use shared::{ComponentStore, EntityComponent, EntityHandle};
+use shared::component::RawComponentHandle;
+
+/// How far away players can see other entities, by default.
+static DEFAULT_RADIUS: f32 = 200.;
+
+/// Decides whether one point can possibly see another.
+/// Maps will hook in here with their PVS, once map files store one.
+pub trait Visibility {
+    fn can_see(&self, from: &Point3<f32>, to: &Point3<f32>) -> bool;
+}
+
+/// For when there's no map: everything can see everything.
+pub struct EverythingVisible;
+
+impl Visibility for EverythingVisible {
+    fn can_see(&self, _: &Point3<f32>, _: &Point3<f32>) -> bool { true }
+}
+
+/// Decides which entities each client gets told about.
+///
+/// An entity is relevant to a viewer if it's the viewer itself, if it's
+/// flagged always-relevant, or if it's within the radius and visible.
+pub struct InterestManager {
+    pub radius: f32,
+    visibility: Box<Visibility + 'static>,
+    always_relevant: HashSet<EntityHandle>
+}
+
+impl InterestManager {
+    pub fn new(visibility: Box<Visibility + 'static>) -> InterestManager {
+        InterestManager {
+            radius: DEFAULT_RADIUS,
+            visibility: visibility,
+            always_relevant: HashSet::new()
+        }
+    }
+
+    /// Always-relevant entities are sent to everybody no matter where they are.
+    pub fn set_always_relevant(&mut self, entity: EntityHandle, always: bool) {
+        if always {
+            self.always_relevant.insert(entity);
+        } else {
+            self.always_relevant.remove(&entity);
+        }
+    }
+
+    /// Every entity `viewer` should know about right now.
+    pub fn relevant_set(&self, viewer: EntityHandle, entities: &ComponentStore<EntityComponent>) -> HashSet<RawComponentHandle> {
+        let mut relevant = HashSet::new();
+        let eye = match entities.find(viewer) {
+            Some(ent) => ent.pos,
+            None => return relevant
+        };
+        let radius2 = self.radius * self.radius;
+
+        for (handle, ent) in entities.iter() {
+            if handle == viewer
+                || self.always_relevant.contains(&handle)
+                || (ent.pos.sub_p(&eye).length2() <= radius2 && self.visibility.can_see(&eye, &ent.pos)) {
+                relevant.insert(handle.to_raw());
+            }
+        }
+        relevant
+    }
+
+    /// Cleans up after an entity's been removed.
+    pub fn forget(&mut self, entity: EntityHandle) {
+        self.always_relevant.remove(&entity);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cgmath::{Point3, Quaternion};
+    use shared::{ComponentStore, EntityComponent};
+    use shared::component::components::{BLOCK_ARCHETYPE, PLAYER_ARCHETYPE};
+    use super::{EverythingVisible, InterestManager};
+
+    #[test]
+    fn relevance() {
+        let mut entities = ComponentStore::new();
+        let rot = Quaternion::new(1., 0., 0., 0.);
+        let viewer = EntityComponent::new(&mut entities, PLAYER_ARCHETYPE, Point3::new(0., 0., 0.), rot);
+        let near = EntityComponent::new(&mut entities, BLOCK_ARCHETYPE, Point3::new(5., 0., 0.), rot);
+        let far = EntityComponent::new(&mut entities, BLOCK_ARCHETYPE, Point3::new(500., 0., 0.), rot);
+        let flagged = EntityComponent::new(&mut entities, BLOCK_ARCHETYPE, Point3::new(0., 900., 0.), rot);
+
+        let mut interest = InterestManager::new(box EverythingVisible);
+        interest.set_always_relevant(flagged, true);
+        let relevant = interest.relevant_set(viewer, &entities);
+
+        assert!(relevant.contains(&viewer.to_raw()));
+        assert!(relevant.contains(&near.to_raw()));
+        assert!(!relevant.contains(&far.to_raw()));
+        assert!(relevant.contains(&flagged.to_raw()));
+    }
+}
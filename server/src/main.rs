@@ -1,4 +1,3 @@
-extern crate flate;
 extern crate cgmath;
 extern crate shared;
 extern crate serialize;
@@ -6,23 +5,120 @@ extern crate time;
 
 use cgmath::{Point3, Rotation3};
 use shared::{ComponentHandle, EntityComponent, EntityHandle};
-use shared::component::components::NoHandleEntityComponent;
-use shared::network::{ClientToServer, Connect, Disconnect, Playercmd};
+use shared::component::RawComponentHandle;
+use shared::component::components::PLAYER_ARCHETYPE;
+use shared::playercmd::ControllableComponent;
+use shared::network::{ChallengeResponse, Connect, Disconnect, InfoRequest, Playercmd};
+use shared::network::{ChallengePacket, ServerInfoPacket, ServerToClient, DEFAULT_PORT, PROTOCOL_VERSION};
+use shared::network::{commands_per_packet, rate_to_interval, MAX_RATE, MIN_RATE};
+use shared::network::master::{send_heartbeat, HEARTBEAT_INTERVAL};
+use shared::network::challenge::{challenge_period, check_challenge, make_challenge};
 use shared::network::channel::NetChannel;
-use std::collections::HashMap;
+use shared::network::delta::RelevanceHistory;
+use shared::network::priority::UpdateScheduler;
+use shared::network::replay::ReplayWriter;
+use shared::network::simulator::{NetConditions, SimulatedTransport};
+use shared::network::transport::Transport;
+use shared::network::replication::{ReplicationRegistry, MAX_STATES};
+use shared::world::World;
+use std::collections::{Deque, HashMap, HashSet, RingBuf};
+use shared::TICK_LENGTH;
+use std::io::{BufferedWriter, File};
+use std::io::net::ip::SocketAddr;
+use std::io::net::udp::UdpSocket;
+
+mod cmdbuffer;
+mod interest;
+mod validation;
+
+/// How many players we'll let in at once.
+static MAX_CLIENTS: uint = 32;
+/// How many spectators we'll let in at once, on top of the players.
+/// Each gets the whole world, so this is kept low; relays are for crowds.
+static MAX_SPECTATORS: uint = 4;
+/// Ticks without hearing from a client before we stop sending it updates.
+static TIMINGOUT_TICKS: u64 = 512;
+/// Ticks without hearing from a client before we drop it entirely.
+static TIMEOUT_TICKS: u64 = 128 * 15;
+/// Slowest update and Playercmd rates clients can ask for, in Hz.
+/// (The fastest is once a tick.)
+static MIN_SNAPSHOT_RATE: u32 = 10;
+static MIN_COMMAND_RATE: u32 = 32;
+/// Bad commands a client can send before getting kicked.
+static MAX_STRIKES: uint = 10;
+/// What we tell anyone who asks which map we're on. There's no map loading yet.
+static MAP_NAME: &'static str = "none";
+/// Seconds between full copies of the world in replays.
+static REPLAY_KEYFRAME_INTERVAL: f64 = 5.;
+
+type ReplayRecorder = ReplayWriter<BufferedWriter<File>>;
 
 fn main() {
-    gameloop();
+    use std::io::net::ip::Ipv4Addr;
+
+    let port = std::os::getenv("NMIGP_PORT").and_then(|port| from_str(port.as_slice())).unwrap_or(DEFAULT_PORT);
+    let bindaddr = SocketAddr { ip: Ipv4Addr(0, 0, 0, 0), port: port };
+    let mut socket = match UdpSocket::bind(bindaddr) {
+        Ok(s) => s,
+        Err(e) => fail!("couldn't bind socket: {}", e),
+    };
+
+    let name = std::os::getenv("NMIGP_SERVER_NAME").unwrap_or_else(|| "NMIGP server".to_string());
+    // e.g. NMIGP_MASTER=127.0.0.1:18290 to get listed
+    let master = std::os::getenv("NMIGP_MASTER").map(|addr| from_str(addr.as_slice()).expect("Bad NMIGP_MASTER"));
+
+    // e.g. NMIGP_REPLAY=match.rep to record the whole match
+    let replay = std::os::getenv("NMIGP_REPLAY").map(|path| {
+        let file = File::create(&Path::new(path.as_slice())).unwrap();
+        println!("Recording replay to {}", path);
+        let keyframe_interval = (REPLAY_KEYFRAME_INTERVAL / TICK_LENGTH as f64) as u64;
+        ReplayWriter::new(BufferedWriter::new(file), keyframe_interval).unwrap()
+    });
+
+    // e.g. NMIGP_SPECTATOR_SECRET=hunter2 to let relays in. Spectators see
+    // everything, so without a secret nobody can spectate.
+    let spectator_secret = std::os::getenv("NMIGP_SPECTATOR_SECRET");
+
+    // Nothing tells a standalone server to quit.
+    let (_quit_sender, quit) = channel();
+
+    // e.g. NMIGP_NETSIM="latency=0.1,loss=0.05" to try out a bad connection
+    match std::os::getenv("NMIGP_NETSIM") {
+        Some(spec) => {
+            let conditions = NetConditions::parse(spec.as_slice()).expect("Bad NMIGP_NETSIM");
+            println!("Simulating network conditions: {}", conditions);
+            gameloop(&mut SimulatedTransport::new(socket, conditions), name, master, replay, spectator_secret, quit);
+        },
+        None => gameloop(&mut socket, name, master, replay, spectator_secret, quit)
+    }
 }
 
 struct Client {
-    addr: std::io::net::ip::SocketAddr,
+    addr: SocketAddr,
+    name: String,
     channel: NetChannel,
 
-    entity: EntityHandle,
-    controllable: ComponentHandle<shared::playercmd::ControllableComponent>,
+    /// Spectators have neither.
+    entity: Option<EntityHandle>,
+    controllable: Option<ComponentHandle<ControllableComponent>>,
     connstate: ConnectionState,
     last_acked_tick: u64,
+    commands: cmdbuffer::CommandBuffer,
+    rate_limiter: validation::CommandRateLimiter,
+    /// The tick we last got a valid packet from this client on.
+    last_recv_tick: u64,
+    /// Which entities this client was told about on recent ticks.
+    relevance: RelevanceHistory,
+    /// Fits updates into this client's bandwidth.
+    scheduler: UpdateScheduler,
+    /// Ticks between updates to this client.
+    snapshot_interval: u32,
+    /// Ticks between this client's Playercmd packets.
+    command_interval: u32,
+    next_snapshot_tick: u64,
+    /// The tick each recent update was for, by sequence number,
+    /// so we know how far back the client's baseline is.
+    snapshot_ticks: RingBuf<(u32, u64)>
 }
 
 #[deriving(PartialEq, Eq)]
@@ -32,35 +128,99 @@ enum ConnectionState {
     TimingOut
 }
 
-fn gameloop() {
-    
-    use std::io::net::ip::{Ipv4Addr, SocketAddr};
-    use std::io::net::udp::UdpSocket;
-    use shared::component::ComponentStore;
+/// Replies can go to spoofed addresses nobody's listening on, so
+/// failed sends are ignored, here and in send_to_client.
+fn send_connectionless(socket: &mut Transport, addr: SocketAddr, msg: &ServerToClient) {
+    use shared::network::channel::send_out_of_band;
+    let packet = shared::network::encode_server_msg(msg);
+    let datagram = match send_out_of_band(packet.as_slice()) {
+        Ok(datagram) => datagram,
+        Err(e) => {
+            println!("Dropped a {} byte connectionless message to {}: {}", packet.len(), addr, e);
+            return;
+        }
+    };
+    let _ = socket.send_to(datagram.as_slice(), addr);
+}
 
-    let bindaddr = SocketAddr { ip: Ipv4Addr(0, 0, 0, 0), port: 18295 };
-    let mut socket = match UdpSocket::bind(bindaddr) {
-        Ok(s) => s,
-        Err(e) => fail!("couldn't bind socket: {}", e),
+/// Messages too big to fragment are dropped, returning false; the next
+/// update deltas against an older baseline and catches the client up.
+fn send_to_client(socket: &mut Transport, client: &mut Client, msg: &ServerToClient) -> bool {
+    let packet = shared::network::encode_server_msg(msg);
+    let datagrams = match client.channel.send_unreliable(packet.as_slice()) {
+        Ok(datagrams) => datagrams,
+        Err(e) => {
+            println!("Dropped a {} byte message to {}: {}", packet.len(), client.name, e);
+            return false;
+        }
     };
+    for datagram in datagrams.iter() {
+        let _ = socket.send_to(datagram.as_slice(), client.addr);
+    }
+    true
+}
+
+/// Removes a client and everything it owned from the world,
+/// and tells everybody else it's gone.
+fn drop_client(socket: &mut Transport,
+               clients: &mut HashMap<SocketAddr, Client>,
+               addr: SocketAddr,
+               reason: String,
+               world: &mut World) {
+    let mut client = match clients.pop(&addr) {
+        Some(client) => client,
+        None => return
+    };
+    println!("{} disconnected: {}", client.name, reason);
+    println!("{}'s command buffer: {}", client.name, client.commands.stats());
+
+    // Best effort. If they've timed out, they won't hear it anyways.
+    send_to_client(socket, &mut client, &shared::network::Disconnected(reason.clone()));
+
+    match (client.controllable, client.entity) {
+        (Some(controllable), Some(entity)) => {
+            world.controllables.remove(controllable);
+            world.entities.remove(entity);
+        },
+        _ => ()
+    }
+
+    let left = shared::network::PlayerLeft(shared::network::PlayerLeftPacket {
+        name: client.name,
+        reason: reason
+    });
+    for (_, other) in clients.iter_mut() {
+        if other.connstate == Playing {
+            send_to_client(socket, other, &left);
+        }
+    }
+}
+
+/// Runs the server on any transport, e.g. in-process alongside a client,
+/// until something's sent on `quit`. Only clients that know
+/// `spectator_secret` can spectate; if it's None, nobody can.
+fn gameloop(socket: &mut Transport, name: String, master: Option<SocketAddr>,
+            mut replay: Option<ReplayRecorder>, spectator_secret: Option<String>, quit: Receiver<()>) {
     socket.set_read_timeout(Some(0));
 
-    let mut entities = ComponentStore::new();
-    let mut controllables = ComponentStore::new();
-    //let mut physicals = ComponentStore::new();
+    let mut world = World::new();
+    let mut systems = shared::world::default_systems();
 
-    //let debugbox = EntityComponent::new(&mut entities, Point3::new(0.0, 0.01, 0.0), Rotation3::from_euler(cgmath::rad(0.), cgmath::rad(0.), cgmath::rad(0.)));
+    //let debugbox = EntityComponent::new(&mut world.entities, Point3::new(0.0, 0.01, 0.0), Rotation3::from_euler(cgmath::rad(0.), cgmath::rad(0.), cgmath::rad(0.)));
     
     let mut clients: HashMap<SocketAddr, Client> = HashMap::new();
+    let challenge_keys = shared::network::challenge::random_keys();
+    let mut policy: Box<validation::ViolationPolicy> = box validation::StrikePolicy::new(MAX_STRIKES);
     
     let mut current_tick = 0u64;
 
-    let mut ent_deltas: shared::network::delta::DeltaEncoder<EntityComponent, NoHandleEntityComponent> = shared::network::delta::DeltaEncoder::new(64);
+    let mut replication = ReplicationRegistry::with_default_components();
+    // There's no map yet, so there's no PVS to check either.
+    let mut interest = interest::InterestManager::new(box interest::EverythingVisible);
 
     let mut next_tick_time = time::precise_time_s();
+    let mut next_heartbeat = next_tick_time;
     loop {
-        use serialize::json;
-        
         'timing: loop {
             let starttime = time::precise_time_s();
             let time_until_next = next_tick_time - starttime;
@@ -78,100 +238,490 @@ fn gameloop() {
         }
 
         current_tick = current_tick + 1;
+
+        match quit.try_recv() {
+            Ok(()) => {
+                for (_, client) in clients.iter_mut() {
+                    send_to_client(socket, client, &shared::network::Disconnected("Server shutting down".to_string()));
+                }
+                return;
+            },
+            Err(_) => ()
+        }
+
+        match master {
+            Some(master) if next_tick_time >= next_heartbeat => {
+                next_heartbeat = next_tick_time + HEARTBEAT_INTERVAL;
+                match send_heartbeat(socket, master) {
+                    Ok(()) => (),
+                    Err(e) => println!("Couldn't send heartbeat to {}: {}", master, e)
+                }
+            },
+            _ => ()
+        }
         
 
+        let mut to_drop = Vec::new();
+
         // incoming packets
         let mut recvbuf = [0u8, ..8192]; 
         loop { match socket.recv_from(&mut recvbuf) {
             Ok((len, addr)) => {
+                use shared::network::channel::{is_out_of_band, recv_out_of_band};
+                use shared::network::decode_client_msg;
+
                 let data = recvbuf.as_slice().slice_to(len);
 
-                // borrow checker hack
-                let is_new = match clients.find_mut(&addr) {
-                    Some(client) => {
+                if is_out_of_band(data) {
+                    let msg = recv_out_of_band(data).ok().and_then(|data| decode_client_msg(data.as_slice()));
 
-                        let prevseq = client.channel.get_incoming_sequencenr();
+                    match msg {
+                        Some(Connect(connect)) => {
+                            let reply = if connect.protocol_version != PROTOCOL_VERSION {
+                                shared::network::Reject(format!("Server is running protocol version {}, but you have version {}.",
+                                                                PROTOCOL_VERSION, connect.protocol_version))
+                            } else {
+                                shared::network::Challenge(ChallengePacket {
+                                    challenge: make_challenge(challenge_keys, &addr, challenge_period())
+                                })
+                            };
+                            send_connectionless(socket, addr, &reply);
+                        },
+                        Some(ChallengeResponse(response)) => {
+                            if response.protocol_version != PROTOCOL_VERSION {
+                                send_connectionless(socket, addr, &shared::network::Reject(
+                                    format!("Server is running protocol version {}, but you have version {}.",
+                                            PROTOCOL_VERSION, response.protocol_version)));
+                            } else if !check_challenge(challenge_keys, &addr, response.challenge) {
+                                println!("Bad challenge from {}", addr);
+                            } else if clients.contains_key(&addr) {
+                                // Already connected, they just haven't gotten a signon yet.
+                            } else if response.spectator_secret.is_some() && response.spectator_secret != spectator_secret {
+                                println!("Bad spectator secret from {}", addr);
+                                send_connectionless(socket, addr, &shared::network::Reject("Spectating isn't allowed.".to_string()));
+                            } else if response.spectator_secret.is_some()
+                                      && clients.values().filter(|client| client.entity.is_none()).count() >= MAX_SPECTATORS {
+                                send_connectionless(socket, addr, &shared::network::Reject("Too many spectators.".to_string()));
+                            } else if response.spectator_secret.is_none()
+                                      && clients.values().filter(|client| client.entity.is_some()).count() >= MAX_CLIENTS {
+                                send_connectionless(socket, addr, &shared::network::Reject("Server is full.".to_string()));
+                            } else {
+                                let (playerent, controllable) = if response.spectator_secret.is_some() {
+                                    println!("{} is spectating from {}", response.name, addr);
+                                    (None, None)
+                                } else {
+                                    println!("{} connected from {}!", response.name, addr);
+                                    let playerent = EntityComponent::new(&mut world.entities,
+                                                                         PLAYER_ARCHETYPE,
+                                                                         Point3::new(0.0, 5., 0.0),
+                                                                         Rotation3::from_euler(cgmath::rad(0.), cgmath::rad(0.), cgmath::rad(0.))
+                                                                        );
+                                    // there's few enough players that everyone can always hear about all of them
+                                    interest.set_always_relevant(playerent, true);
+                                    (Some(playerent), Some(world.controllables.add(ControllableComponent::new(playerent))))
+                                };
 
-                        let data = client.channel.recv_unreliable(data).unwrap();
-                        let dropped_packets = client.channel.get_incoming_sequencenr() - (prevseq + 1);
-                        if dropped_packets > 0 {
-                            println!("Lost {} client packets...", dropped_packets)
-                        }
+                                let command_interval = rate_to_interval(std::cmp::max(response.command_rate, MIN_COMMAND_RATE));
+                                clients.insert(addr, Client {
+                                    addr: addr,
+                                    name: response.name,
+                                    channel: NetChannel::new(),
+                                    entity: playerent,
+                                    controllable: controllable,
+                                    connstate: SigningOn,
+                                    last_acked_tick: 0,
+                                    commands: cmdbuffer::CommandBuffer::new(command_interval),
+                                    rate_limiter: validation::CommandRateLimiter::new(time::precise_time_s()),
+                                    last_recv_tick: current_tick,
+                                    relevance: RelevanceHistory::new(MAX_STATES),
+                                    scheduler: UpdateScheduler::new(std::cmp::min(std::cmp::max(response.rate, MIN_RATE), MAX_RATE)),
+                                    snapshot_interval: rate_to_interval(std::cmp::max(response.snapshot_rate, MIN_SNAPSHOT_RATE)),
+                                    command_interval: command_interval,
+                                    next_snapshot_tick: current_tick,
+                                    snapshot_ticks: RingBuf::new()
+                                });
+                            }
+                        },
+                        Some(InfoRequest(request)) => {
+                            let players = clients.values().filter(|client| client.entity.is_some()).count();
+                            send_connectionless(socket, addr, &shared::network::Info(ServerInfoPacket {
+                                time: request.time,
+                                token: request.token,
+                                protocol_version: PROTOCOL_VERSION,
+                                name: name.clone(),
+                                map: MAP_NAME.to_string(),
+                                players: players as u32,
+                                max_players: MAX_CLIENTS as u32
+                            }));
+                        },
+                        Some(_) => (), // needs a connection first
+                        None => println!("Garbage connectionless packet from {}", addr)
+                    }
+                    continue;
+                }
+
+                let client = match clients.find_mut(&addr) {
+                    Some(client) => client,
+                    // NetChannel traffic from someone who never connected.
+                    None => continue
+                };
+
+                let data = match client.channel.recv_unreliable(data) {
+                    Ok(Some(data)) => data,
+                    // fragment of a packet we haven't fully received yet,
+                    // or a stale/duplicate packet
+                    Ok(None) => continue,
+                    Err(e) => {
+                        println!("Bad packet from {}: {}", addr, e);
+                        continue;
+                    }
+                };
+                client.last_recv_tick = current_tick;
 
-                        let data = flate::inflate_bytes_zlib(data.as_slice()).unwrap();
-                        let cmdstr = std::str::from_utf8(data.as_slice()).unwrap();
-                        let cmd: shared::network::ClientToServer = json::decode(cmdstr).unwrap();
+                match decode_client_msg(data.as_slice()) {
+                    Some(Playercmd(mut cmds)) => {
+                        use shared::network::channel::overflow_aware_compare;
 
-                        match cmd {
+                        // Keep the newest ones; they're sent oldest first.
+                        let max_commands = commands_per_packet(client.command_interval);
+                        if cmds.len() > max_commands {
+                            let excess = cmds.len() - max_commands;
+                            cmds = cmds.slice_from(excess).to_vec();
+                        }
+                        cmds.sort_by(|a, b| overflow_aware_compare(a.number, b.number));
 
-                            Playercmd(cmd) => {
-                                client.last_acked_tick = cmd.tick;
-                                for _ in range(0, dropped_packets + 1) {
-                                    shared::playercmd::run_command(cmd,controllables.find_mut(client.controllable).unwrap(), &mut entities);
+                        // Spectators can't do anything. Their Playercmds are just keepalives.
+                        if client.controllable.is_none() {
+                            cmds.clear();
+                        }
+
+                        // Redundant copies of commands we've seen get ignored.
+                        for &cmd in cmds.iter() {
+                            if client.commands.push(cmd) {
+                                match client.rate_limiter.record(time::precise_time_s()) {
+                                    Some(violation) => match policy.on_violation(addr, client.name.as_slice(), &violation) {
+                                        validation::Kick(reason) => to_drop.push((addr, reason)),
+                                        validation::Allow => ()
+                                    },
+                                    None => ()
                                 }
-                                client.connstate = Playing;
-                                false
                             }
-                            Connect => false,
-                            Disconnect => unimplemented!()
                         }
+                        client.connstate = Playing;
                     },
-                    None => true
-                };
-
-                if is_new {
-                    println!("Got connect from {}!", addr);
-                    let playerent = EntityComponent::new(&mut entities,
-                                                         Point3::new(0.0, 5., 0.0),
-                                                         Rotation3::from_euler(cgmath::rad(0.), cgmath::rad(0.), cgmath::rad(0.))
-                                                        );
-                    let controllable = controllables.add(shared::playercmd::ControllableComponent::new(playerent));
-
-                    clients.remove(&addr);
-                    clients.insert(addr, Client {
-                        addr: addr,
-                        channel: NetChannel::new(),
-                        entity: playerent,
-                        controllable: controllable,
-                        connstate: SigningOn,
-                        last_acked_tick: 0
-                    });
+                    Some(Disconnect(reason)) => to_drop.push((addr, reason)),
+                    Some(_) => (),
+                    None => println!("Garbage packet from {}", addr)
                 }
             },
             Err(_) => break,
         }}
 
-        ent_deltas.add_state(&entities, |ent| ent.to_nohandle());
+        // Each client gets at most one command per tick.
+        let mut tick_commands = Vec::new();
+        for (&addr, client) in clients.iter_mut() {
+            use shared::playercmd::{sanitize_command, Clean, Fixed, Rejected};
+
+            let controllable = match client.controllable {
+                Some(controllable) if client.connstate == Playing => controllable,
+                _ => continue
+            };
+            match client.commands.next() {
+                Some(cmd) => {
+                    client.last_acked_tick = cmd.tick;
+
+                    let (cmd, violation) = match sanitize_command(cmd) {
+                        Clean(cmd) => (Some(cmd), None),
+                        Fixed(cmd, violation) => (Some(cmd), Some(violation)),
+                        Rejected(violation) => (None, Some(violation))
+                    };
+                    match violation {
+                        Some(violation) => match policy.on_violation(addr, client.name.as_slice(), &violation) {
+                            validation::Kick(reason) => to_drop.push((addr, reason)),
+                            validation::Allow => ()
+                        },
+                        None => ()
+                    }
+                    match cmd {
+                        Some(cmd) => tick_commands.push((controllable, cmd)),
+                        None => ()
+                    }
+                },
+                None => ()
+            }
+        }
+
+        shared::world::tick(&mut world, systems.as_mut_slice(), tick_commands.as_slice());
+
+        for (&addr, client) in clients.iter_mut() {
+            let silence = current_tick - client.last_recv_tick;
+            if silence > TIMEOUT_TICKS {
+                to_drop.push((addr, "Timed out".to_string()));
+            } else if silence > TIMINGOUT_TICKS {
+                client.connstate = TimingOut;
+            }
+        }
+        for (addr, reason) in to_drop.into_iter() {
+            match clients.find(&addr) {
+                Some(client) => {
+                    policy.forget(addr);
+                    match client.entity {
+                        Some(entity) => interest.forget(entity),
+                        None => ()
+                    }
+                },
+                None => ()
+            }
+            drop_client(socket, &mut clients, addr, reason, &mut world);
+        }
+
+        replication.add_state(&world);
+        let replay_result = match replay {
+            Some(ref mut writer) => writer.record_tick(current_tick, &replication, &world, tick_commands.as_slice()),
+            None => Ok(())
+        };
+        match replay_result {
+            Ok(()) => (),
+            Err(e) => {
+                println!("Stopped recording replay: {}", e);
+                replay = None;
+            }
+        }
+        let everything: HashSet<RawComponentHandle> = world.entities.iter().map(|(handle, _)| handle.to_raw()).collect();
+        for (_, client) in clients.iter_mut() {
+            client.relevance.push(match client.entity {
+                Some(entity) => interest.relevant_set(entity, &world.entities),
+                None => everything.clone()
+            });
+        }
+        let entity_map: HashMap<_, EntityComponent> = world.entities.iter()
+            .map(|(handle, ent)| (handle.to_raw(), ent.clone()))
+            .collect();
 
         // outgoing
         for (_, client) in clients.iter_mut() {
-            if client.last_acked_tick != 0 && client.last_acked_tick + 512 < current_tick {
-                client.connstate = TimingOut;
-            };
             match client.connstate {
-                Playing => {
+                Playing if current_tick >= client.next_snapshot_tick => {
+                    client.next_snapshot_tick = current_tick + client.snapshot_interval as u64;
+
+                    let sequence = client.channel.get_outgoing_sequencenr() + 1;
+                    // Delta from the newest update we know the client has, or send everything.
+                    let baseline = client.snapshot_ticks.iter().rev()
+                        .find(|&&(seq, _)| client.channel.is_acked(seq))
+                        .map(|&x| x);
+                    let length = match baseline {
+                        Some((_, tick)) => std::cmp::max(current_tick - tick, 1),
+                        None => MAX_STATES as u64
+                    };
+                    let updates = replication.collect_updates(length, &client.relevance);
+                    let updates = client.scheduler.schedule(updates, &replication, &client.relevance,
+                                                            client.entity.map(|entity| entity.to_raw()), &entity_map,
+                                                            sequence, baseline.map(|(seq, _)| seq),
+                                                            client.snapshot_interval as f64 * TICK_LENGTH as f64);
+
                     let update = shared::network::Update(shared::network::UpdatePacket {
                         tick: current_tick,
-                        entity_updates: ent_deltas.create_delta((client.channel.get_outgoing_sequencenr() + 1 - client.channel.get_acked_outgoing_sequencenr()) as u64)
+                        last_command: client.commands.last_executed(),
+                        component_updates: replication.pack_updates(updates),
+                        all_entities: replication.all_entities(length, &client.relevance)
                     });
-                    let update = json::encode(&update);
-                    let update = update.into_bytes();
+                    if !send_to_client(socket, client, &update) {
+                        client.scheduler.dropped(sequence);
+                        continue;
+                    }
 
-                    let update = flate::deflate_bytes_zlib(update.as_slice()).unwrap();
-                    let datagram = client.channel.send_unreliable(update.as_slice());
-                    socket.send_to(datagram.unwrap().as_slice(), client.addr).unwrap();
+                    client.snapshot_ticks.push((sequence, current_tick));
+                    while client.snapshot_ticks.len() > MAX_STATES {
+                        client.snapshot_ticks.pop_front();
+                    }
                 },
+                Playing => (),
                 SigningOn => {
                     let signon = shared::network::Signon(shared::network::SignonPacket {
-                        handle: client.entity.to_raw()
+                        tick: current_tick,
+                        handle: client.entity.map(|entity| entity.to_raw()),
+                        snapshot_interval: client.snapshot_interval,
+                        command_interval: client.command_interval
                     });
-                    let signon = json::encode(&signon).into_bytes();
-                    let signon = flate::deflate_bytes_zlib(signon.as_slice()).unwrap();
-                    let datagram = client.channel.send_unreliable(signon.as_slice());
-                    socket.send_to(datagram.unwrap().as_slice(), client.addr).unwrap();
+                    send_to_client(socket, client, &signon);
                 },
                 TimingOut => ()
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use cgmath::{Quaternion, Vector3};
+    use shared::network::{decode_server_msg, encode_client_msg, ClientToServer, ServerToClient, PROTOCOL_VERSION};
+    use shared::network::{Challenge, ChallengeResponse, Connect, Playercmd, Reject, Signon, Update};
+    use shared::network::{ChallengeResponsePacket, ConnectPacket};
+    use shared::network::channel::{is_out_of_band, recv_out_of_band, send_out_of_band, NetChannel};
+    use shared::network::transport::{MemoryNetwork, MemoryTransport, Transport};
+    use shared::playercmd::PlayerCommand;
+    use super::gameloop;
+
+    /// Waits for the next message from the server, out-of-band or not.
+    fn recv(client: &mut MemoryTransport, channel: &mut NetChannel) -> ServerToClient {
+        let mut buf = [0u8, ..8192];
+        loop {
+            let (len, _) = client.recv_from(&mut buf).unwrap();
+            let datagram = buf.slice_to(len);
+            let packet = if is_out_of_band(datagram) {
+                recv_out_of_band(datagram).unwrap()
+            } else {
+                match channel.recv_unreliable(datagram).unwrap() {
+                    Some(packet) => packet,
+                    None => continue
+                }
+            };
+            return decode_server_msg(packet.as_slice()).expect("Garbage from the server");
+        }
+    }
+
+    fn send_out_of_band_msg(client: &mut MemoryTransport, server: ::std::io::net::ip::SocketAddr, msg: &ClientToServer) {
+        let datagram = send_out_of_band(encode_client_msg(msg).as_slice()).unwrap();
+        client.send_to(datagram.as_slice(), server).unwrap();
+    }
+
+    /// Goes through the challenge, and returns what the server says to our response.
+    fn respond_to_challenge(client: &mut MemoryTransport, channel: &mut NetChannel, server: ::std::io::net::ip::SocketAddr,
+                            spectator_secret: Option<String>) -> ServerToClient {
+        send_out_of_band_msg(client, server, &Connect(ConnectPacket {
+            protocol_version: PROTOCOL_VERSION,
+            name: "Tester".to_string()
+        }));
+        let challenge = match recv(client, channel) {
+            Challenge(challenge) => challenge.challenge,
+            _ => fail!("Expected a challenge")
+        };
+        send_out_of_band_msg(client, server, &ChallengeResponse(ChallengeResponsePacket {
+            challenge: challenge,
+            protocol_version: PROTOCOL_VERSION,
+            name: "Tester".to_string(),
+            rate: 64 * 1024,
+            snapshot_rate: 64,
+            command_rate: 128,
+            spectator_secret: spectator_secret
+        }));
+        recv(client, channel)
+    }
+
+    #[test]
+    fn spectators_need_the_secret() {
+        let network = MemoryNetwork::new();
+        let mut server = network.bind_any();
+        let server_addr = server.local_addr().unwrap();
+        let (quit_sender, quit) = channel();
+        spawn(proc() {
+            gameloop(&mut server, "Test server".to_string(), None, None, Some("hunter2".to_string()), quit);
+        });
+
+        let mut client = network.bind_any();
+        client.set_read_timeout(Some(5000));
+        match respond_to_challenge(&mut client, &mut NetChannel::new(), server_addr, Some("hunter3".to_string())) {
+            Reject(_) => (),
+            _ => fail!("Spectator with the wrong secret got in")
+        }
+        match respond_to_challenge(&mut client, &mut NetChannel::new(), server_addr, Some("hunter2".to_string())) {
+            Signon(signon) => assert!(signon.handle.is_none()),
+            _ => fail!("Spectator with the right secret didn't get in")
+        }
+
+        quit_sender.send(());
+    }
+
+    #[test]
+    fn nobody_spectates_without_a_secret() {
+        let network = MemoryNetwork::new();
+        let mut server = network.bind_any();
+        let server_addr = server.local_addr().unwrap();
+        let (quit_sender, quit) = channel();
+        spawn(proc() {
+            gameloop(&mut server, "Test server".to_string(), None, None, None, quit);
+        });
+
+        let mut client = network.bind_any();
+        client.set_read_timeout(Some(5000));
+        match respond_to_challenge(&mut client, &mut NetChannel::new(), server_addr, Some("".to_string())) {
+            Reject(_) => (),
+            _ => fail!("Spectator got in with no secret configured")
+        }
+
+        quit_sender.send(());
+    }
+
+    #[test]
+    fn client_plays_in_process() {
+        let network = MemoryNetwork::new();
+        let mut server = network.bind_any();
+        let server_addr = server.local_addr().unwrap();
+        let (quit_sender, quit) = channel();
+        spawn(proc() {
+            gameloop(&mut server, "Test server".to_string(), None, None, None, quit);
+        });
+
+        let mut client = network.bind_any();
+        // long enough for a slow test machine, short enough to fail rather than hang
+        client.set_read_timeout(Some(5000));
+        let mut channel = NetChannel::new();
+
+        send_out_of_band_msg(&mut client, server_addr, &Connect(ConnectPacket {
+            protocol_version: PROTOCOL_VERSION,
+            name: "Tester".to_string()
+        }));
+        let challenge = match recv(&mut client, &mut channel) {
+            Challenge(challenge) => challenge.challenge,
+            _ => fail!("Expected a challenge")
+        };
+        send_out_of_band_msg(&mut client, server_addr, &ChallengeResponse(ChallengeResponsePacket {
+            challenge: challenge,
+            protocol_version: PROTOCOL_VERSION,
+            name: "Tester".to_string(),
+            rate: 64 * 1024,
+            snapshot_rate: 64,
+            command_rate: 128,
+            spectator_secret: None
+        }));
+
+        let mut signon = None;
+        while signon.is_none() {
+            match recv(&mut client, &mut channel) {
+                Signon(packet) => signon = Some(packet),
+                _ => ()
+            }
+        }
+        let signon = signon.unwrap();
+        assert!(signon.handle.is_some());
+
+        // Send a new command for every update, until the server says it's run one.
+        let mut number = 0u32;
+        let mut updates = 0u;
+        loop {
+            number += 1;
+            let cmd = PlayerCommand {
+                number: number,
+                tick: signon.tick + number as u64,
+                angles: Quaternion::new(1., 0., 0., 0.),
+                movement: Vector3::new(0.1, 0., 0.)
+            };
+            for datagram in channel.send_unreliable(encode_client_msg(&Playercmd(vec![cmd])).as_slice()).unwrap().iter() {
+                client.send_to(datagram.as_slice(), server_addr).unwrap();
+            }
+
+            match recv(&mut client, &mut channel) {
+                Update(update) => {
+                    updates += 1;
+                    if update.last_command > 0 {
+                        break;
+                    }
+                },
+                _ => ()
+            }
+            assert!(updates < 100, "Server never ran our commands");
+        }
+
+        quit_sender.send(());
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::io::net::ip::SocketAddr;
+use shared::playercmd::{CommandViolation, TooManyCommands};
+
+/// Tolerance on top of the tick rate before we call it a speedhack,
+/// to allow for bursts after lag.
+static RATE_TOLERANCE: f32 = 1.25;
+
+/// What to do about a player who sent a bad command.
+pub enum PolicyAction {
+    Allow,
+    Kick(String)
+}
+
+/// Decides what happens to players who send bad commands.
+/// Players are told apart by address, since anyone can pick any name.
+pub trait ViolationPolicy {
+    fn on_violation(&mut self, player: SocketAddr, name: &str, violation: &CommandViolation) -> PolicyAction;
+    /// Called when a player leaves, so per-player state can be forgotten.
+    fn forget(&mut self, _player: SocketAddr) {}
+}
+
+/// Logs violations, and kicks players once they've made too many.
+pub struct StrikePolicy {
+    strikes: HashMap<SocketAddr, uint>,
+    max_strikes: uint
+}
+
+impl StrikePolicy {
+    pub fn new(max_strikes: uint) -> StrikePolicy {
+        StrikePolicy {
+            strikes: HashMap::new(),
+            max_strikes: max_strikes
+        }
+    }
+}
+
+impl ViolationPolicy for StrikePolicy {
+    fn on_violation(&mut self, player: SocketAddr, name: &str, violation: &CommandViolation) -> PolicyAction {
+        let strikes = self.strikes.find_copy(&player).unwrap_or(0) + 1;
+        self.strikes.insert(player, strikes);
+        println!("{} ({}) sent a bad command ({}/{}): {}", name, player, strikes, self.max_strikes, violation);
+
+        if strikes >= self.max_strikes {
+            Kick(format!("Kicked for sending bad commands ({})", violation))
+        } else {
+            Allow
+        }
+    }
+
+    fn forget(&mut self, player: SocketAddr) {
+        self.strikes.remove(&player);
+    }
+}
+
+/// Counts how many commands a client sends each second,
+/// to catch clients running their clock fast.
+pub struct CommandRateLimiter {
+    window_start: f64,
+    count: uint,
+    limit: uint
+}
+
+impl CommandRateLimiter {
+    pub fn new(now: f64) -> CommandRateLimiter {
+        CommandRateLimiter {
+            window_start: now,
+            count: 0,
+            limit: ((1.0 / ::shared::TICK_LENGTH) * RATE_TOLERANCE) as uint
+        }
+    }
+
+    /// Records a new command. Returns a violation the first time
+    /// the limit is passed in any second.
+    pub fn record(&mut self, now: f64) -> Option<CommandViolation> {
+        if now - self.window_start >= 1.0 {
+            self.window_start = now;
+            self.count = 0;
+        }
+        self.count += 1;
+
+        if self.count == self.limit + 1 {
+            Some(TooManyCommands(self.count))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::net::ip::SocketAddr;
+    use shared::playercmd::{NotFinite, TooManyCommands};
+    use super::{Allow, CommandRateLimiter, Kick, StrikePolicy, ViolationPolicy};
+
+    #[test]
+    fn rate_limit() {
+        let mut limiter = CommandRateLimiter::new(0.);
+        let limit = limiter.limit;
+
+        for i in range(0, limit) {
+            assert!(limiter.record(i as f64 / limit as f64 * 0.5).is_none());
+        }
+        assert_eq!(limiter.record(0.6), Some(TooManyCommands(limit + 1)));
+        // only reported once per second
+        assert!(limiter.record(0.7).is_none());
+        // and a new second starts fresh
+        assert!(limiter.record(1.7).is_none());
+    }
+
+    #[test]
+    fn strikes() {
+        let cheater: SocketAddr = from_str("10.0.0.1:1234").unwrap();
+        let bystander: SocketAddr = from_str("10.0.0.2:1234").unwrap();
+        let mut policy = StrikePolicy::new(2);
+        match policy.on_violation(cheater, "Player", &NotFinite) { Allow => (), Kick(_) => fail!("Kicked too early") }
+        // same name, different player
+        match policy.on_violation(bystander, "Player", &NotFinite) { Allow => (), Kick(_) => fail!("Strikes weren't per-player") }
+        match policy.on_violation(cheater, "Player", &NotFinite) { Kick(_) => (), Allow => fail!("Wasn't kicked") }
+
+        policy.forget(cheater);
+        match policy.on_violation(cheater, "Player", &NotFinite) { Allow => (), Kick(_) => fail!("Strikes weren't forgotten") }
+        // forgetting one player doesn't forgive another with the same name
+        match policy.on_violation(bystander, "Player", &NotFinite) { Kick(_) => (), Allow => fail!("Wrong strikes forgotten") }
+    }
+}
@@ -1,34 +1,455 @@
-use cgmath;
-use cgmath::Plane;
-use cgmath::Vector3;
+//! BSP trees, and the potentially visible set (PVS) computed from them.
+//!
+//! Pvs::compute generates portals between neighbouring empty leaves of a
+//! tree, and floods through them to work out which leaves could possibly
+//! see each other. The result is a bitset the server can cull updates with.
+//!
+//! There's no map format yet, so nothing builds trees outside of tests.
+//! Once there is, the PVS is slow enough to compute that it should be done
+//! once, when the map is built, rather than every time it's loaded.
 
-enum Tree<LeafType> {
-    Subtree(INode),
+use cgmath::{EuclideanVector, Plane, Point, Point3, Vector, Vector3};
+
+/// Anything bigger than a map could ever be.
+static MAP_SIZE: f32 = 65536.;
+/// How close to a plane a point can be and still count as being on it.
+static PLANE_EPSILON: f32 = 0.01;
+
+pub enum Tree<LeafType> {
+    Subtree(INode<LeafType>),
     Leaf(LeafType)
 }
 
-struct INode<LeafType> {
-    plane: Plane<f32>,
+/// Points `p` with `plane.n.dot(p) == plane.d` are on the plane.
+pub struct INode<LeafType> {
+    pub plane: Plane<f32>,
 
     /// away from normal
-    inside: Box<Tree<LeafType>>,
+    pub inside: Box<Tree<LeafType>>,
     /// towards normal
-    outside: Box<Tree<LeafType>>
+    pub outside: Box<Tree<LeafType>>
+}
+
+pub type LeafId = uint;
+pub type ClusterId = uint;
+
+/// What the PVS needs to know about a leaf.
+#[deriving(Clone, PartialEq, Show)]
+pub struct BspLeaf {
+    /// Leaves are numbered from 0, with no gaps.
+    pub id: LeafId,
+    pub solid: bool
 }
 
 impl<LeafType> Tree<LeafType> {
     pub fn map_leaves<NewLeafType>(self, f: |LeafType| -> NewLeafType) -> Tree<NewLeafType> {
+        let mut f = f;
+        self.map_leaves_inner(&mut f)
+    }
+
+    fn map_leaves_inner<NewLeafType>(self, f: &mut |LeafType| -> NewLeafType) -> Tree<NewLeafType> {
         match self {
             Subtree(inode) => {
+                let INode { plane, inside, outside } = inode;
+                let inside = (*inside).map_leaves_inner(f);
+                let outside = (*outside).map_leaves_inner(f);
                 Subtree(INode {
-                    plane: inode.plane,
-                    inside: box inode.inside.map_leaves(f),
-                    outside: box inode.outside.map_leaves(f),
+                    plane: plane,
+                    inside: box inside,
+                    outside: box outside,
                 })
             },
-            Leaf(leaf) => Leaf(f(leaf))
+            Leaf(leaf) => Leaf((*f)(leaf))
+        }
+    }
+
+    /// The leaf `point` is in. Points exactly on a plane count as outside it.
+    pub fn find(&self, point: &Point3<f32>) -> &LeafType {
+        let mut node = self;
+        loop {
+            match *node {
+                Subtree(ref inode) => {
+                    node = if plane_dist(&inode.plane.n, inode.plane.d, point) >= 0. {
+                        &*inode.outside
+                    } else {
+                        &*inode.inside
+                    };
+                },
+                Leaf(ref leaf) => return leaf
+            }
+        }
+    }
+}
+
+/// Numbers the leaves of a tree whose leaves say whether they're solid.
+pub fn number_leaves(tree: Tree<bool>) -> Tree<BspLeaf> {
+    let mut next = 0u;
+    tree.map_leaves(|solid| {
+        let leaf = BspLeaf { id: next, solid: solid };
+        next += 1;
+        leaf
+    })
+}
+
+fn plane_dist(normal: &Vector3<f32>, dist: f32, point: &Point3<f32>) -> f32 {
+    normal.dot(&point.to_vec()) - dist
+}
+
+type Winding = Vec<Point3<f32>>;
+
+/// A huge square lying on a plane.
+fn base_winding(normal: &Vector3<f32>, dist: f32) -> Winding {
+    let up = if normal.z.abs() < 0.9 { Vector3::new(0., 0., 1.) } else { Vector3::new(1., 0., 0.) };
+    let right = up.cross(normal).normalize().mul_s(MAP_SIZE);
+    let up = normal.cross(&right).normalize().mul_s(MAP_SIZE);
+    let center = Point3::from_vec(&normal.mul_s(dist));
+
+    let (left, down) = (right.mul_s(-1.), up.mul_s(-1.));
+
+    vec![center.add_v(&right).add_v(&up),
+         center.add_v(&right).add_v(&down),
+         center.add_v(&left).add_v(&down),
+         center.add_v(&left).add_v(&up)]
+}
+
+fn winding_area(winding: &[Point3<f32>]) -> f32 {
+    if winding.len() < 3 {
+        return 0.;
+    }
+    let mut total = Vector3::new(0f32, 0., 0.);
+    for i in range(1, winding.len() - 1) {
+        let a = winding[i].sub_p(&winding[0]);
+        let b = winding[i + 1].sub_p(&winding[0]);
+        total = total.add_v(&a.cross(&b));
+    }
+    total.length() / 2.
+}
+
+/// Splits a winding into the parts in front of and behind a plane.
+/// Either part may come back degenerate.
+fn split_winding(winding: &[Point3<f32>], normal: &Vector3<f32>, dist: f32) -> (Winding, Winding) {
+    let mut front = Vec::new();
+    let mut back = Vec::new();
+
+    for i in range(0, winding.len()) {
+        let a = winding[i];
+        let b = winding[(i + 1) % winding.len()];
+        let da = plane_dist(normal, dist, &a);
+        let db = plane_dist(normal, dist, &b);
+
+        if da >= -PLANE_EPSILON {
+            front.push(a);
+        }
+        if da <= PLANE_EPSILON {
+            back.push(a);
+        }
+        if (da > PLANE_EPSILON && db < -PLANE_EPSILON) || (da < -PLANE_EPSILON && db > PLANE_EPSILON) {
+            let t = da / (da - db);
+            let mid = a.add_v(&b.sub_p(&a).mul_s(t));
+            front.push(mid);
+            back.push(mid);
         }
     }
-    
-    fn find(&self, point: Point3<f32>) -> LeafType { 
+    (front, back)
+}
 
+fn is_degenerate(winding: &[Point3<f32>]) -> bool {
+    winding_area(winding) <= PLANE_EPSILON
+}
+
+/// A hole from one empty leaf into another.
+struct Portal {
+    winding: Winding,
+    /// Points from `from` into `to`.
+    normal: Vector3<f32>,
+    dist: f32,
+    from: LeafId,
+    to: LeafId
+}
+
+/// Pushes a winding down a tree, cutting it up by the leaves it touches.
+/// `facing` points from the winding into the region it's being pushed into,
+/// which decides where pieces lying on a node's plane go.
+fn clip_to_leaves(tree: &Tree<BspLeaf>, winding: Winding, facing: &Vector3<f32>, out: &mut Vec<(BspLeaf, Winding)>) {
+    match *tree {
+        Leaf(leaf) => out.push((leaf, winding)),
+        Subtree(ref node) => {
+            let coplanar = winding.iter().all(|p| plane_dist(&node.plane.n, node.plane.d, p).abs() <= PLANE_EPSILON);
+            if coplanar {
+                let child = if facing.dot(&node.plane.n) > 0. { &node.outside } else { &node.inside };
+                clip_to_leaves(&**child, winding, facing, out);
+                return;
+            }
+
+            let (front, back) = split_winding(winding.as_slice(), &node.plane.n, node.plane.d);
+            if !is_degenerate(front.as_slice()) {
+                clip_to_leaves(&*node.outside, front, facing, out);
+            }
+            if !is_degenerate(back.as_slice()) {
+                clip_to_leaves(&*node.inside, back, facing, out);
+            }
+        }
+    }
+}
+
+/// `constraints` are the planes (facing into this node's region) of every node above this one.
+fn portals_for_node(tree: &Tree<BspLeaf>, constraints: &mut Vec<(Vector3<f32>, f32)>, portals: &mut Vec<Portal>) {
+    let node = match *tree {
+        Subtree(ref node) => node,
+        Leaf(_) => return
+    };
+    let normal = node.plane.n;
+    let dist = node.plane.d;
+
+    let mut winding = base_winding(&normal, dist);
+    for &(ref n, d) in constraints.iter() {
+        let (front, _) = split_winding(winding.as_slice(), n, d);
+        winding = front;
+    }
+
+    if !is_degenerate(winding.as_slice()) {
+        let mut fronts = Vec::new();
+        clip_to_leaves(&*node.outside, winding, &normal, &mut fronts);
+        for (front_leaf, fragment) in fronts.into_iter() {
+            if front_leaf.solid {
+                continue;
+            }
+            let mut backs = Vec::new();
+            clip_to_leaves(&*node.inside, fragment, &normal.mul_s(-1.), &mut backs);
+            for (back_leaf, piece) in backs.into_iter() {
+                if back_leaf.solid {
+                    continue;
+                }
+                portals.push(Portal {
+                    winding: piece.clone(),
+                    normal: normal,
+                    dist: dist,
+                    from: back_leaf.id,
+                    to: front_leaf.id
+                });
+                portals.push(Portal {
+                    winding: piece,
+                    normal: normal.mul_s(-1.),
+                    dist: -dist,
+                    from: front_leaf.id,
+                    to: back_leaf.id
+                });
+            }
+        }
+    }
+
+    constraints.push((normal, dist));
+    portals_for_node(&*node.outside, constraints, portals);
+    constraints.pop();
+    constraints.push((normal.mul_s(-1.), -dist));
+    portals_for_node(&*node.inside, constraints, portals);
+    constraints.pop();
+}
+
+/// Portals between every pair of neighbouring empty leaves, in both directions.
+fn generate_portals(tree: &Tree<BspLeaf>) -> Vec<Portal> {
+    let mut portals = Vec::new();
+    portals_for_node(tree, &mut Vec::new(), &mut portals);
+    portals
+}
+
+/// Whether anything looking through `p` could possibly see through `q`:
+/// `q` has to be at least partly in front of `p`, and `p` partly behind `q`.
+fn might_see(p: &Portal, q: &Portal) -> bool {
+    q.winding.iter().any(|pt| plane_dist(&p.normal, p.dist, pt) > PLANE_EPSILON)
+        && p.winding.iter().any(|pt| plane_dist(&q.normal, q.dist, pt) < -PLANE_EPSILON)
+}
+
+/// Floods out from `leaf` through portals that everything on the path so far might see.
+fn flow(portals: &[Portal], leaf_portals: &[Vec<uint>], mightsee: &[Vec<bool>],
+        leaf: LeafId, might: &[bool], path: &mut Vec<LeafId>, visible: &mut Vec<bool>) {
+    for &q in leaf_portals[leaf].iter() {
+        if !might[q] {
+            continue;
+        }
+        let to = portals[q].to;
+        if path.contains(&to) {
+            continue;
+        }
+        visible[to] = true;
+
+        let next: Vec<bool> = might.iter().zip(mightsee[q].iter()).map(|(&a, &b)| a && b).collect();
+        path.push(to);
+        flow(portals, leaf_portals, mightsee, to, next.as_slice(), path, visible);
+        path.pop();
+    }
+}
+
+/// Which clusters can possibly see which others.
+///
+/// Each empty leaf is its own cluster for now; merging small leaves
+/// together would shrink the bitset.
+#[deriving(Encodable, Decodable, Clone)]
+pub struct Pvs {
+    /// The cluster each leaf is in, or None if it's solid.
+    leaf_clusters: Vec<Option<ClusterId>>,
+    num_clusters: uint,
+    /// One row of bits per cluster.
+    bits: Vec<u32>
+}
+
+impl Pvs {
+    /// Computes the PVS for a tree. This is slow; it's for the map compiler.
+    pub fn compute(tree: &Tree<BspLeaf>) -> Pvs {
+        let mut leaves = Vec::new();
+        collect_leaves(tree, &mut leaves);
+        let num_leaves = leaves.iter().map(|leaf| leaf.id + 1).max().unwrap_or(0);
+
+        let mut leaf_clusters = Vec::from_elem(num_leaves, None);
+        let mut num_clusters = 0;
+        for leaf in leaves.iter() {
+            if !leaf.solid {
+                *leaf_clusters.get_mut(leaf.id) = Some(num_clusters);
+                num_clusters += 1;
+            }
+        }
+
+        let portals = generate_portals(tree);
+        let mut leaf_portals = Vec::from_fn(num_leaves, |_| Vec::new());
+        for (i, portal) in portals.iter().enumerate() {
+            leaf_portals.get_mut(portal.from).push(i);
+        }
+        let mightsee: Vec<Vec<bool>> = portals.iter()
+            .map(|p| portals.iter().map(|q| might_see(p, q)).collect())
+            .collect();
+
+        let stride = (num_clusters + 31) / 32;
+        let mut pvs = Pvs {
+            leaf_clusters: leaf_clusters,
+            num_clusters: num_clusters,
+            bits: Vec::from_elem(stride * num_clusters, 0u32)
+        };
+
+        for leaf in leaves.iter().filter(|leaf| !leaf.solid) {
+            let mut visible = Vec::from_elem(num_leaves, false);
+            *visible.get_mut(leaf.id) = true;
+            for &p in leaf_portals[leaf.id].iter() {
+                let to = portals[p].to;
+                *visible.get_mut(to) = true;
+                let mut path = vec![leaf.id, to];
+                flow(portals.as_slice(), leaf_portals.as_slice(), mightsee.as_slice(),
+                     to, mightsee[p].as_slice(), &mut path, &mut visible);
+            }
+
+            let from = pvs.leaf_clusters[leaf.id].unwrap();
+            for (other, &seen) in visible.iter().enumerate() {
+                match pvs.leaf_clusters[other] {
+                    Some(to) if seen => {
+                        // visibility goes both ways, even if the flood didn't find it
+                        pvs.set_visible(from, to);
+                        pvs.set_visible(to, from);
+                    },
+                    _ => ()
+                }
+            }
+        }
+        pvs
+    }
+
+    fn set_visible(&mut self, from: ClusterId, to: ClusterId) {
+        let stride = (self.num_clusters + 31) / 32;
+        *self.bits.get_mut(from * stride + to / 32) |= 1u32 << (to % 32);
+    }
+
+    /// The cluster a leaf is in, or None if it's solid.
+    pub fn cluster(&self, leaf: LeafId) -> Option<ClusterId> {
+        if leaf < self.leaf_clusters.len() { self.leaf_clusters[leaf] } else { None }
+    }
+
+    /// The cluster a point is in, or None if it's inside something solid.
+    pub fn find_cluster(&self, tree: &Tree<BspLeaf>, point: &Point3<f32>) -> Option<ClusterId> {
+        self.cluster(tree.find(point).id)
+    }
+
+    pub fn num_clusters(&self) -> uint {
+        self.num_clusters
+    }
+
+    pub fn is_cluster_visible(&self, from: ClusterId, to: ClusterId) -> bool {
+        let stride = (self.num_clusters + 31) / 32;
+        self.bits[from * stride + to / 32] & (1u32 << (to % 32)) != 0
+    }
+
+    /// Whether anything in `leaf_a` could possibly see anything in `leaf_b`.
+    /// Solid leaves can't see or be seen.
+    pub fn is_visible(&self, leaf_a: LeafId, leaf_b: LeafId) -> bool {
+        match (self.cluster(leaf_a), self.cluster(leaf_b)) {
+            (Some(a), Some(b)) => self.is_cluster_visible(a, b),
+            _ => false
+        }
+    }
+}
+
+fn collect_leaves(tree: &Tree<BspLeaf>, out: &mut Vec<BspLeaf>) {
+    match *tree {
+        Subtree(ref node) => {
+            collect_leaves(&*node.inside, out);
+            collect_leaves(&*node.outside, out);
+        },
+        Leaf(leaf) => out.push(leaf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cgmath::{Plane, Point3, Vector3};
+    use super::{number_leaves, INode, Leaf, Pvs, Subtree, Tree};
+
+    fn node(normal: Vector3<f32>, dist: f32, inside: Tree<bool>, outside: Tree<bool>) -> Tree<bool> {
+        Subtree(INode {
+            plane: Plane::new(normal, dist),
+            inside: box inside,
+            outside: box outside
+        })
+    }
+
+    /// Two rooms below y=0, split by a wall from x=0 to x=1,
+    /// both open into a big room above y=0.
+    fn two_rooms() -> Tree<bool> {
+        node(Vector3::new(0., 1., 0.), 0.,
+             node(Vector3::new(1., 0., 0.), 0.,
+                  Leaf(false),
+                  node(Vector3::new(1., 0., 0.), 1.,
+                       Leaf(true),
+                       Leaf(false))),
+             Leaf(false))
+    }
+
+    #[test]
+    fn find_leaves() {
+        let tree = number_leaves(two_rooms());
+        let left = tree.find(&Point3::new(-5., -5., 0.));
+        let wall = tree.find(&Point3::new(0.5, -5., 0.));
+        let right = tree.find(&Point3::new(5., -5., 0.));
+        assert!(!left.solid && wall.solid && !right.solid);
+        assert!(left.id != right.id);
+    }
+
+    #[test]
+    fn walls_block_visibility() {
+        let tree = number_leaves(two_rooms());
+        let pvs = Pvs::compute(&tree);
+        let left = tree.find(&Point3::new(-5., -5., 0.)).id;
+        let wall = tree.find(&Point3::new(0.5, -5., 0.)).id;
+        let right = tree.find(&Point3::new(5., -5., 0.)).id;
+        let above = tree.find(&Point3::new(0., 5., 0.)).id;
+
+        assert_eq!(pvs.num_clusters(), 3);
+        assert!(pvs.is_visible(left, left));
+        assert!(pvs.is_visible(left, above));
+        assert!(pvs.is_visible(above, left));
+        assert!(pvs.is_visible(above, right));
+        assert!(pvs.is_visible(right, above));
+        assert!(!pvs.is_visible(left, right));
+        assert!(!pvs.is_visible(right, left));
+        assert!(!pvs.is_visible(left, wall));
+
+        assert_eq!(pvs.find_cluster(&tree, &Point3::new(0.5, -5., 0.)), None);
+        assert!(pvs.find_cluster(&tree, &Point3::new(-5., -5., 0.)).is_some());
+    }
+}
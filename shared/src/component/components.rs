@@ -3,16 +3,27 @@ use component::{ComponentHandle, ComponentStore};
 
 pub type EntityHandle = ComponentHandle<EntityComponent>;
 
+/// Says what kind of thing an entity is, so clients know
+/// which local components (models, sounds, etc.) to give it.
+pub type ArchetypeId = u16;
+
+pub static PLAYER_ARCHETYPE: ArchetypeId = 0;
+pub static BLOCK_ARCHETYPE: ArchetypeId = 1;
+pub static PROJECTILE_ARCHETYPE: ArchetypeId = 2;
+pub static TRIGGER_ARCHETYPE: ArchetypeId = 3;
+
 /// Represents an entity in the world.
 #[deriving(Clone)]
 pub struct EntityComponent {
     pub handle: EntityHandle,
+    pub archetype: ArchetypeId,
 
     pub pos: Point3<f32>,
     pub rot: Quaternion<f32>
 }
 #[deriving(Encodable, Decodable, Clone, PartialEq)]
 pub struct NoHandleEntityComponent {
+    pub archetype: ArchetypeId,
     pub pos: Point3<f32>,
     pub rot: Quaternion<f32>
 }
@@ -21,11 +32,12 @@ impl EntityComponent {
         self.handle
     }
     pub fn to_nohandle(&self) -> NoHandleEntityComponent {
-        NoHandleEntityComponent { pos: self.pos, rot: self.rot }
+        NoHandleEntityComponent { archetype: self.archetype, pos: self.pos, rot: self.rot }
     }
     pub fn from_nohandle(e: &NoHandleEntityComponent, handle: EntityHandle) -> EntityComponent {
         EntityComponent {
             handle: handle,
+            archetype: e.archetype,
             pos: e.pos,
             rot: e.rot
         }
@@ -33,10 +45,12 @@ impl EntityComponent {
     
     /// Constructs an EntityComponent inside a
     pub fn new(ents: &mut ComponentStore<EntityComponent>,
+              archetype: ArchetypeId,
               pos: Point3<f32>,
               rot: Quaternion<f32>) -> EntityHandle {
         ents.add_with_handle(|handle| EntityComponent {
             handle: handle,
+            archetype: archetype,
             pos: pos,
             rot: rot
         })
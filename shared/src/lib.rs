@@ -18,6 +18,7 @@
 
 extern crate anymap;
 extern crate cgmath;
+extern crate flate;
 extern crate serialize;
 extern crate test;
 extern crate time;
@@ -27,10 +28,12 @@ pub use component::{
     EntityComponent, EntityHandle
 };
 
+pub mod bsp;
 pub mod component;
 pub mod network;
 pub mod physics;
 pub mod playercmd;
+pub mod world;
 
 /// Length of one simulation tick, in seconds.
 pub static TICK_LENGTH: f32 = 1.0 / 128.0;
@@ -0,0 +1,31 @@
+//! Challenge tokens, for making sure whoever's connecting can actually
+//! receive packets at the address they claim before keeping any state
+//! around for them.
+//!
+//! Challenges are a keyed hash of the address and the current time period,
+//! so nothing needs to be remembered between handing one out and checking it.
+
+use std::io::net::ip::SocketAddr;
+
+/// Challenges are valid for between one and two of these, in seconds.
+pub static CHALLENGE_LIFETIME: f64 = 30.;
+
+/// Secret keys for the challenge hash. Each process should make its own.
+pub fn random_keys() -> (u64, u64) {
+    (::std::rand::random::<u64>(), ::std::rand::random::<u64>())
+}
+
+/// Makes the challenge token for a client address.
+pub fn make_challenge(keys: (u64, u64), addr: &SocketAddr, period: u64) -> u64 {
+    let (k0, k1) = keys;
+    ::std::hash::sip::hash_with_keys(k0, k1, &(addr, period))
+}
+
+pub fn check_challenge(keys: (u64, u64), addr: &SocketAddr, challenge: u64) -> bool {
+    let period = challenge_period();
+    challenge == make_challenge(keys, addr, period) || challenge == make_challenge(keys, addr, period - 1)
+}
+
+pub fn challenge_period() -> u64 {
+    (::time::precise_time_s() / CHALLENGE_LIFETIME) as u64
+}
@@ -1,9 +1,37 @@
 use std;
-use std::collections::{Deque, RingBuf};
-use std::io::IoResult;
+use std::cmp::{Equal, Greater, Less};
+use std::collections::{Deque, HashMap, RingBuf};
+use std::io::{IoError, IoResult};
 
 pub type SequenceNr = u32;
 
+/// Largest payload (in bytes) we'll put in a single datagram.
+/// Anything bigger is split into fragments, so datagrams stay under the MTU.
+pub static FRAGMENT_SIZE: uint = 1024;
+/// The most fragments a single packet may be split into.
+pub static MAX_FRAGMENTS: uint = 64;
+/// How many bytes a channel may hold in partially-reassembled packets.
+pub static MAX_FRAGMENT_MEMORY: uint = 256 * 1024;
+/// How long (in seconds) we wait for the rest of a fragmented packet.
+pub static FRAGMENT_TIMEOUT: f64 = 1.0;
+/// How many recent packets the packet loss percentages cover.
+pub static LOSS_WINDOW: uint = 128;
+/// How many unacknowledged packets we remember before assuming they're lost.
+static MAX_UNACKED: uint = 1024;
+
+/// First byte of every datagram, telling NetChannel traffic apart
+/// from connectionless (out-of-band) packets.
+static CHANNEL_PACKET: u8 = 0;
+static OUT_OF_BAND_PACKET: u8 = 0xFF;
+
+fn malformed(desc: &'static str) -> IoError {
+    IoError {
+        kind: std::io::InvalidInput,
+        desc: desc,
+        detail: None
+    }
+}
+
 pub fn overflow_aware_compare(a: SequenceNr, b: SequenceNr) -> std::cmp::Ordering {
     use std::cmp::{max, min};
     
@@ -16,13 +44,125 @@ pub fn overflow_aware_compare(a: SequenceNr, b: SequenceNr) -> std::cmp::Orderin
     }
 }
 
+/// Per-connection counters of misbehaving incoming packets.
+#[deriving(Clone, PartialEq, Show)]
+pub struct PacketCounters {
+    /// Packets that hadn't arrived by the time a newer one did.
+    pub dropped: u64,
+    /// Packets received more than once.
+    pub duplicated: u64,
+    /// Packets that arrived after a newer one and were thrown away.
+    /// These have already been counted as dropped.
+    pub out_of_order: u64
+}
+
+/// Connection quality statistics, for lag compensation and net graphs.
+#[deriving(Clone, PartialEq, Show)]
+pub struct NetStats {
+    /// Smoothed round-trip time, in seconds.
+    pub rtt: f64,
+    /// Smoothed mean deviation of the round-trip time (i.e. jitter), in seconds.
+    pub rtt_variance: f64,
+    /// Percentage of recent incoming packets that never arrived.
+    pub loss_in: f64,
+    /// Percentage of recent outgoing packets the other end never acknowledged.
+    pub loss_out: f64,
+    pub bytes_in_per_sec: f64,
+    pub bytes_out_per_sec: f64
+}
+
+struct SentPacket {
+    seq: SequenceNr,
+    time: f64,
+    acked: bool
+}
+
+/// Keeps a rolling count of bytes over the last second.
+struct RateMeter {
+    samples: RingBuf<(f64, uint)>,
+    total: uint
+}
+impl RateMeter {
+    fn new() -> RateMeter {
+        RateMeter { samples: RingBuf::new(), total: 0 }
+    }
+    fn add(&mut self, now: f64, bytes: uint) {
+        self.samples.push((now, bytes));
+        self.total += bytes;
+        self.expire(now);
+    }
+    fn expire(&mut self, now: f64) {
+        while self.samples.front().map(|&(time, _)| now - time > 1.0).unwrap_or(false) {
+            let (_, bytes) = self.samples.pop_front().unwrap();
+            self.total -= bytes;
+        }
+    }
+    /// Bytes per second, as of the last sample.
+    fn rate(&self) -> f64 {
+        self.total as f64
+    }
+}
+
+/// Percentage of lost packets over the last LOSS_WINDOW packets.
+fn loss_percentage(window: &RingBuf<bool>) -> f64 {
+    if window.len() == 0 {
+        return 0.;
+    }
+    let lost = window.iter().filter(|&&arrived| !arrived).count();
+    lost as f64 / window.len() as f64 * 100.
+}
+
+fn push_outcome(window: &mut RingBuf<bool>, arrived: bool) {
+    window.push(arrived);
+    while window.len() > LOSS_WINDOW {
+        window.pop_front();
+    }
+}
+
+/// Is this datagram a connectionless packet, rather than NetChannel traffic?
+pub fn is_out_of_band(datagram: &[u8]) -> bool {
+    datagram.len() > 0 && datagram[0] == OUT_OF_BAND_PACKET
+}
+
+/// Wraps a payload to be sent outside of any NetChannel, e.g. for
+/// connection handshakes. Out-of-band packets are never fragmented.
+pub fn send_out_of_band(data: &[u8]) -> IoResult<Vec<u8>> {
+    if data.len() > FRAGMENT_SIZE {
+        return Err(malformed("Out-of-band packet too large"));
+    }
+    let mut buf = Vec::with_capacity(data.len() + 1);
+    buf.push(OUT_OF_BAND_PACKET);
+    buf.push_all(data);
+    Ok(buf)
+}
+
+pub fn recv_out_of_band(datagram: &[u8]) -> IoResult<Vec<u8>> {
+    if !is_out_of_band(datagram) {
+        return Err(malformed("Not an out-of-band packet"));
+    }
+    Ok(datagram.slice_from(1).to_vec())
+}
+
 pub struct NetChannel {
     last_outgoing: SequenceNr,
     last_acked_outgoing: SequenceNr,
     last_incoming: SequenceNr,
+    /// Bit n is set if we received last_incoming - (n + 1).
+    incoming_history: u32,
     
-    send_times: RingBuf<f64>,
+    sent: RingBuf<SentPacket>,
     latency: f64,
+    /// None until we get our first RTT sample.
+    smoothed_rtt: Option<f64>,
+    rtt_variance: f64,
+
+    incoming_outcomes: RingBuf<bool>,
+    outgoing_outcomes: RingBuf<bool>,
+    bytes_in: RateMeter,
+    bytes_out: RateMeter,
+
+    fragments: FragmentAssembler,
+    counters: PacketCounters,
 }
 
 impl NetChannel {
@@ -32,72 +172,391 @@ impl NetChannel {
             last_outgoing: 0,
             last_acked_outgoing: 0,
             last_incoming: 0,
+            incoming_history: 0,
 
-            send_times: RingBuf::new(),
+            sent: RingBuf::new(),
             latency: 0.,
+            smoothed_rtt: None,
+            rtt_variance: 0.,
+
+            incoming_outcomes: RingBuf::new(),
+            outgoing_outcomes: RingBuf::new(),
+            bytes_in: RateMeter::new(),
+            bytes_out: RateMeter::new(),
+
+            fragments: FragmentAssembler::new(MAX_FRAGMENT_MEMORY, FRAGMENT_TIMEOUT),
+            counters: PacketCounters {
+                dropped: 0,
+                duplicated: 0,
+                out_of_order: 0
+            },
         }
     }
 
     pub fn get_outgoing_sequencenr(&self) -> SequenceNr { self.last_outgoing }
     pub fn get_incoming_sequencenr(&self) -> SequenceNr { self.last_incoming }
     pub fn get_acked_outgoing_sequencenr(&self) -> SequenceNr { self.last_acked_outgoing }
+    /// Whether a recent packet we sent is known to have arrived.
+    /// Packets too old to remember count as not.
+    pub fn is_acked(&self, seq: SequenceNr) -> bool {
+        self.sent.iter().any(|packet| packet.seq == seq && packet.acked)
+    }
+    pub fn get_counters(&self) -> PacketCounters { self.counters.clone() }
+
+    /// Wraps a payload for sending, returning the datagrams to put on the wire.
+    /// Payloads bigger than FRAGMENT_SIZE are split into several datagrams,
+    /// all sharing one sequence number.
+    pub fn send_unreliable(&mut self, data: &[u8]) -> IoResult<Vec<Vec<u8>>> {
+        if data.len() > FRAGMENT_SIZE * MAX_FRAGMENTS {
+            return Err(malformed("Packet too large to fragment"));
+        }
 
-    pub fn send_unreliable(&mut self, data: &[u8]) -> IoResult<Vec<u8>> {
-        let mut buf = std::io::MemWriter::with_capacity(data.len() + 16);
-        
         self.last_outgoing += 1;
 
-        self.send_times.push(::time::precise_time_s());
+        let now = ::time::precise_time_s();
+        self.sent.push(SentPacket {
+            seq: self.last_outgoing,
+            time: now,
+            acked: false
+        });
+        while self.sent.len() > MAX_UNACKED {
+            let packet = self.sent.pop_front().unwrap();
+            push_outcome(&mut self.outgoing_outcomes, packet.acked);
+        }
+
+        let chunks: Vec<&[u8]> = if data.is_empty() {
+            vec![data]
+        } else {
+            data.chunks(FRAGMENT_SIZE).collect()
+        };
+
+        let mut datagrams = Vec::with_capacity(chunks.len());
+        for (index, chunk) in chunks.iter().enumerate() {
+            let datagram = try!(self.write_datagram(index, chunks.len(), *chunk));
+            self.bytes_out.add(now, datagram.len());
+            datagrams.push(datagram);
+        }
+
+        Ok(datagrams)
+    }
+
+    fn write_datagram(&self, fragment_index: uint, fragment_count: uint, data: &[u8]) -> IoResult<Vec<u8>> {
+        let mut buf = std::io::MemWriter::with_capacity(data.len() + 23);
 
+        try!(buf.write_u8(CHANNEL_PACKET));
         try!(buf.write_le_u32(self.last_outgoing));
         try!(buf.write_le_u32(self.last_incoming));
+        try!(buf.write_le_u32(self.incoming_history));
+        try!(buf.write_u8(fragment_index as u8));
+        try!(buf.write_u8(fragment_count as u8));
         try!(buf.write_le_u64(data.len() as u64));
         try!(buf.write(data));
 
         Ok(buf.unwrap())
     }
 
-    pub fn recv_unreliable(&mut self, datagram: &[u8]) -> IoResult<Vec<u8>> {
+    /// Unwraps a received datagram.
+    /// Returns None if the datagram was a fragment of a packet
+    /// that hasn't been fully received yet, or if it was stale or a duplicate.
+    pub fn recv_unreliable(&mut self, datagram: &[u8]) -> IoResult<Option<Vec<u8>>> {
+        let now = ::time::precise_time_s();
+        self.bytes_in.add(now, datagram.len());
+
         let mut buf = std::io::BufReader::new(datagram);
-        
+
+        if try!(buf.read_u8()) != CHANNEL_PACKET {
+            return Err(malformed("Not a NetChannel packet"));
+        }
         let sequence_number = try!(buf.read_le_u32());
         let acked_sequence_number = try!(buf.read_le_u32());
+        let ack_history = try!(buf.read_le_u32());
+        let fragment_index = try!(buf.read_u8()) as uint;
+        let fragment_count = try!(buf.read_u8()) as uint;
         let payload_len = try!(buf.read_le_u64());
+        if payload_len > FRAGMENT_SIZE as u64 {
+            return Err(malformed("Datagram payload too large"));
+        }
         let payload = try!(buf.read_exact(payload_len as uint));
 
-        self.last_incoming = sequence_number;
-        self.ack(acked_sequence_number);
+        let payload = if fragment_count > 1 {
+            if overflow_aware_compare(sequence_number, self.last_incoming) != Greater {
+                // no sense reassembling something we'd throw away
+                return Ok(None);
+            }
+            match try!(self.fragments.add(sequence_number, fragment_index, fragment_count, payload, now)) {
+                Some(packet) => packet,
+                None => return Ok(None)
+            }
+        } else {
+            payload
+        };
 
-        Ok(payload)
+        if !self.accept_incoming(sequence_number) {
+            return Ok(None);
+        }
+        self.ack(acked_sequence_number, ack_history, now);
+
+        Ok(Some(payload))
     }
 
-    fn ack(&mut self, seq: SequenceNr) {
-        let prev_acked = self.last_acked_outgoing;
-        self.last_acked_outgoing = seq;
+    /// Decides whether an incoming sequence number is new, updating
+    /// last_incoming and the counters.
+    fn accept_incoming(&mut self, seq: SequenceNr) -> bool {
+        // NB: all the subtraction here is on u32s, and wraps around,
+        // so it's correct across the overflow boundary.
+        match overflow_aware_compare(seq, self.last_incoming) {
+            Greater => {
+                let advance = seq - self.last_incoming;
+                self.counters.dropped += (advance - 1) as u64;
 
-        let curtime = ::time::precise_time_s();
+                for _ in range(0, ::std::cmp::min(advance - 1, LOSS_WINDOW as u32)) {
+                    push_outcome(&mut self.incoming_outcomes, false);
+                }
+                push_outcome(&mut self.incoming_outcomes, true);
 
-        for _ in range(0, seq - prev_acked) {
-            let sendtime = self.send_times.pop_front().expect("Too many acks!");
-            self.latency = curtime - sendtime;
+                self.incoming_history = if advance > 32 {
+                    0
+                } else {
+                    // the old last_incoming becomes bit (advance - 1)
+                    ((self.incoming_history << 1) | 1) << (advance - 1) as uint
+                };
+                self.last_incoming = seq;
+                true
+            },
+            Equal => {
+                self.counters.duplicated += 1;
+                false
+            },
+            Less => {
+                let age = self.last_incoming - seq;
+                if age <= 32 && self.incoming_history & (1 << (age - 1) as uint) != 0 {
+                    self.counters.duplicated += 1;
+                } else {
+                    self.counters.out_of_order += 1;
+                }
+                false
+            }
         }
     }
 
+    /// Handles an ack for `seq`, along with a bitfield of the 32
+    /// packets before it (bit n is seq - (n + 1)).
+    fn ack(&mut self, seq: SequenceNr, history: u32, now: f64) {
+        // Ignore acks for packets we never sent.
+        if overflow_aware_compare(seq, self.last_outgoing) == Greater {
+            return;
+        }
+        if overflow_aware_compare(seq, self.last_acked_outgoing) == Greater {
+            self.last_acked_outgoing = seq;
+        }
+
+        let mut rtt_samples = Vec::new();
+        for packet in self.sent.iter_mut() {
+            if packet.acked || overflow_aware_compare(packet.seq, seq) == Greater {
+                continue;
+            }
+            let age = seq - packet.seq;
+            if age == 0 || (age <= 32 && history & (1 << (age - 1) as uint) != 0) {
+                packet.acked = true;
+                rtt_samples.push(now - packet.time);
+            }
+        }
+        for &sample in rtt_samples.iter() {
+            self.add_rtt_sample(sample);
+        }
+
+        // Anything that's fallen out of the ack window is settled one way or the other.
+        while self.sent.front().map(|packet| overflow_aware_compare(packet.seq, seq) != Greater && seq - packet.seq >= 32).unwrap_or(false) {
+            let packet = self.sent.pop_front().unwrap();
+            push_outcome(&mut self.outgoing_outcomes, packet.acked);
+        }
+    }
+
+    /// Smooths RTT samples the same way TCP does (RFC 6298).
+    fn add_rtt_sample(&mut self, rtt: f64) {
+        self.latency = rtt;
+        self.smoothed_rtt = Some(match self.smoothed_rtt {
+            Some(srtt) => {
+                self.rtt_variance = 0.75 * self.rtt_variance + 0.25 * (srtt - rtt).abs();
+                0.875 * srtt + 0.125 * rtt
+            },
+            None => {
+                self.rtt_variance = rtt / 2.;
+                rtt
+            }
+        });
+    }
+
+    /// The most recent RTT sample, in seconds.
     pub fn get_latency(&self) -> f64 {
         self.latency
     }
 
+    pub fn get_stats(&self) -> NetStats {
+        NetStats {
+            rtt: self.smoothed_rtt.unwrap_or(0.),
+            rtt_variance: self.rtt_variance,
+            loss_in: loss_percentage(&self.incoming_outcomes),
+            loss_out: loss_percentage(&self.outgoing_outcomes),
+            bytes_in_per_sec: self.bytes_in.rate(),
+            bytes_out_per_sec: self.bytes_out.rate()
+        }
+    }
+
+    /// How many bytes are tied up in partially-received packets.
+    pub fn get_fragment_memory(&self) -> uint {
+        self.fragments.memory_used()
+    }
+
+}
+
+struct PartialPacket {
+    first_seen: f64,
+    fragments: Vec<Option<Vec<u8>>>,
+    received: uint,
+    size: uint
+}
+
+/// What a partial packet costs before any data arrives: a slot for each
+/// fragment. Without counting this, empty fragments would be free.
+fn partial_overhead(count: uint) -> uint {
+    count * std::mem::size_of::<Option<Vec<u8>>>()
+}
+
+/// Reassembles fragmented packets.
+///
+/// Partial packets are thrown away if they aren't completed within `timeout`
+/// seconds, and the oldest ones are evicted if the fragments held, plus
+/// each partial packet's overhead, would exceed `max_memory` bytes.
+pub struct FragmentAssembler {
+    partial: HashMap<SequenceNr, PartialPacket>,
+    memory_used: uint,
+    max_memory: uint,
+    timeout: f64
+}
+
+impl FragmentAssembler {
+    pub fn new(max_memory: uint, timeout: f64) -> FragmentAssembler {
+        FragmentAssembler {
+            partial: HashMap::new(),
+            memory_used: 0,
+            max_memory: max_memory,
+            timeout: timeout
+        }
+    }
+
+    pub fn memory_used(&self) -> uint {
+        self.memory_used
+    }
+
+    /// Adds a fragment, returning the whole packet if this fragment completed it.
+    pub fn add(&mut self, seq: SequenceNr, index: uint, count: uint, data: Vec<u8>, now: f64) -> IoResult<Option<Vec<u8>>> {
+        if count < 2 || count > MAX_FRAGMENTS || index >= count {
+            return Err(malformed("Bad fragment header"));
+        }
+
+        self.expire(now);
+
+        let needed = if self.partial.contains_key(&seq) {
+            data.len()
+        } else {
+            data.len() + partial_overhead(count)
+        };
+        while self.memory_used + needed > self.max_memory {
+            if !self.evict_oldest() {
+                // a single fragment bigger than our whole budget. drop it.
+                return Ok(None);
+            }
+        }
+
+        if !self.partial.contains_key(&seq) {
+            self.memory_used += partial_overhead(count);
+            self.partial.insert(seq, PartialPacket {
+                first_seen: now,
+                fragments: Vec::from_fn(count, |_| None),
+                received: 0,
+                size: 0
+            });
+        }
+
+        let complete = {
+            let partial = self.partial.find_mut(&seq).unwrap();
+
+            if partial.fragments.len() != count {
+                return Err(malformed("Fragment count changed mid-packet"));
+            }
+            if partial.fragments[index].is_some() {
+                // duplicate fragment
+                return Ok(None);
+            }
+
+            self.memory_used += data.len();
+            partial.size += data.len();
+            partial.received += 1;
+            *partial.fragments.get_mut(index) = Some(data);
+
+            partial.received == count
+        };
+
+        if !complete {
+            return Ok(None);
+        }
+
+        let partial = self.partial.pop(&seq).unwrap();
+        self.memory_used -= partial.size + partial_overhead(count);
+
+        let mut packet = Vec::with_capacity(partial.size);
+        for fragment in partial.fragments.into_iter() {
+            packet.push_all(fragment.unwrap().as_slice());
+        }
+        Ok(Some(packet))
+    }
+
+    /// Throws away partial packets that have been waiting too long.
+    pub fn expire(&mut self, now: f64) {
+        let expired: Vec<SequenceNr> = self.partial.iter()
+            .filter(|&(_, partial)| now - partial.first_seen > self.timeout)
+            .map(|(&seq, _)| seq)
+            .collect();
+
+        for seq in expired.iter() {
+            self.remove(*seq);
+        }
+    }
+
+    fn evict_oldest(&mut self) -> bool {
+        let mut oldest = None;
+        for (&seq, partial) in self.partial.iter() {
+            match oldest {
+                Some((_, time)) if time <= partial.first_seen => (),
+                _ => oldest = Some((seq, partial.first_seen))
+            }
+        }
+
+        match oldest {
+            Some((seq, _)) => { self.remove(seq); true },
+            None => false
+        }
+    }
+
+    fn remove(&mut self, seq: SequenceNr) {
+        match self.partial.pop(&seq) {
+            Some(partial) => self.memory_used -= partial.size + partial_overhead(partial.fragments.len()),
+            None => ()
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::NetChannel;
+    use std::rand::{Rng, SeedableRng, XorShiftRng};
+    use super::{partial_overhead, FragmentAssembler, NetChannel, SequenceNr, FRAGMENT_SIZE, MAX_FRAGMENTS};
 
     #[test]
     fn smoke_netchannel() {
         let (mut chan1, mut chan2) = (NetChannel::new(), NetChannel::new());
 
-        let result = chan2.recv_unreliable(chan1.send_unreliable(b"Hello, world!").unwrap().as_slice()).unwrap();
+        let result = chan2.recv_unreliable(chan1.send_unreliable(b"Hello, world!").unwrap()[0].as_slice()).unwrap().unwrap();
         assert_eq!(result.as_slice(), b"Hello, world!");
     }
 
@@ -106,11 +565,232 @@ mod test {
         let (mut chan1, mut chan2) = (NetChannel::new(), NetChannel::new());
 
         let pkt = chan1.send_unreliable(b"Hello, chan2!").unwrap();
-        let result = chan2.recv_unreliable(pkt.as_slice()).unwrap();
+        let result = chan2.recv_unreliable(pkt[0].as_slice()).unwrap().unwrap();
         assert_eq!(result.as_slice(), b"Hello, chan2!");
 
         let pkt = chan2.send_unreliable(b"Hello, chan1!").unwrap();
-        let result = chan1.recv_unreliable(pkt.as_slice()).unwrap();
+        let result = chan1.recv_unreliable(pkt[0].as_slice()).unwrap().unwrap();
         assert_eq!(result.as_slice(), b"Hello, chan1!");
     }
+
+    #[test]
+    fn fragmentation_roundtrip() {
+        let (mut chan1, mut chan2) = (NetChannel::new(), NetChannel::new());
+        let payload = Vec::from_fn(FRAGMENT_SIZE * 5 + 17, |i| i as u8);
+
+        let datagrams = chan1.send_unreliable(payload.as_slice()).unwrap();
+        assert_eq!(datagrams.len(), 6);
+
+        // deliver out of order; only the last fragment should complete it
+        for (i, &idx) in [3u, 0, 5, 1, 4].iter().enumerate() {
+            assert!(chan2.recv_unreliable(datagrams[idx].as_slice()).unwrap().is_none(), "fragment {} completed early", i);
+        }
+        let result = chan2.recv_unreliable(datagrams[2].as_slice()).unwrap().unwrap();
+        assert_eq!(result, payload);
+        assert_eq!(chan2.get_fragment_memory(), 0);
+    }
+
+    #[test]
+    fn oversized_packet_rejected() {
+        let mut chan = NetChannel::new();
+        let payload = Vec::from_elem(FRAGMENT_SIZE * MAX_FRAGMENTS + 1, 0u8);
+        assert!(chan.send_unreliable(payload.as_slice()).is_err());
+    }
+
+    #[test]
+    fn fragment_timeout() {
+        let mut assembler = FragmentAssembler::new(4096, 1.0);
+        assert!(assembler.add(1, 0, 2, vec![1, 2, 3], 0.0).unwrap().is_none());
+        assert_eq!(assembler.memory_used(), 3 + partial_overhead(2));
+
+        assembler.expire(2.0);
+        assert_eq!(assembler.memory_used(), 0);
+
+        // the first half is gone, so the second half can't complete the packet
+        assert!(assembler.add(1, 1, 2, vec![4, 5, 6], 2.0).unwrap().is_none());
+    }
+
+    #[test]
+    fn fragment_memory_limit() {
+        let mut assembler = FragmentAssembler::new(8 + 2 * partial_overhead(2), 10.0);
+        assert!(assembler.add(1, 0, 2, Vec::from_elem(6, 0u8), 0.0).unwrap().is_none());
+        // doesn't fit alongside packet 1, so packet 1 gets evicted
+        assert!(assembler.add(2, 0, 2, Vec::from_elem(6, 0u8), 1.0).unwrap().is_none());
+        assert_eq!(assembler.memory_used(), 6 + partial_overhead(2));
+        assert!(assembler.add(1, 1, 2, vec![1], 1.0).unwrap().is_none());
+        assert_eq!(assembler.add(2, 1, 2, vec![1], 1.0).unwrap().unwrap().len(), 7);
+        assert_eq!(assembler.memory_used(), 1 + partial_overhead(2));
+    }
+
+    #[test]
+    fn empty_fragments_arent_free() {
+        let mut assembler = FragmentAssembler::new(4096, 10.0);
+        for seq in range(0u32, 1000) {
+            assert!(assembler.add(seq, 0, MAX_FRAGMENTS, Vec::new(), 0.0).unwrap().is_none());
+            assert!(assembler.memory_used() > 0);
+            assert!(assembler.memory_used() <= 4096);
+        }
+        assert!(assembler.partial.len() <= 4096 / partial_overhead(MAX_FRAGMENTS));
+    }
+
+    /// Sends 256 packets from a channel starting at `start`, loses,
+    /// duplicates and shuffles them, and checks that the receiver
+    /// only delivers them in order and accounts for everything.
+    fn check_delivery(start: SequenceNr, seed: u32) {
+        let (mut tx, mut rx) = (NetChannel::new(), NetChannel::new());
+        tx.last_outgoing = start;
+        rx.last_incoming = start;
+
+        let mut rng: XorShiftRng = SeedableRng::from_seed([seed, 0x9e37, 0x79b9, 0x7f4a]);
+
+        let mut wire = Vec::new();
+        for i in range(0u, 256) {
+            let datagram = tx.send_unreliable(&[i as u8]).unwrap().into_iter().next().unwrap();
+            match rng.gen_range(0u, 10) {
+                0 => (), // lost
+                1 => { wire.push(datagram.clone()); wire.push(datagram) },
+                _ => wire.push(datagram)
+            }
+        }
+        // reorder within small windows, like a real network would
+        for window in wire.as_mut_slice().chunks_mut(5) {
+            rng.shuffle(window);
+        }
+
+        let mut last_delivered = None;
+        let mut delivered = 0u64;
+        for datagram in wire.iter() {
+            match rx.recv_unreliable(datagram.as_slice()).unwrap() {
+                Some(payload) => {
+                    let idx = payload[0] as u64;
+                    assert!(last_delivered.map_or(true, |last| idx > last), "delivered {} after {}", idx, last_delivered);
+                    last_delivered = Some(idx);
+                    delivered += 1;
+                },
+                None => ()
+            }
+        }
+
+        let counters = rx.get_counters();
+        assert_eq!(delivered + counters.duplicated + counters.out_of_order, wire.len() as u64);
+        match last_delivered {
+            Some(last) => {
+                assert_eq!(delivered + counters.dropped, last + 1);
+                assert_eq!(rx.get_incoming_sequencenr(), start + last as u32 + 1);
+            },
+            None => assert_eq!(delivered, 0)
+        }
+    }
+
+    #[test]
+    fn delivery_properties() {
+        for seed in range(0u32, 50) {
+            check_delivery(0, seed);
+        }
+    }
+
+    #[test]
+    fn delivery_properties_wraparound() {
+        for seed in range(0u32, 50) {
+            check_delivery(::std::u32::MAX - 100, seed);
+        }
+    }
+
+    #[test]
+    fn stale_and_duplicate_packets() {
+        let (mut chan1, mut chan2) = (NetChannel::new(), NetChannel::new());
+        let first = chan1.send_unreliable(b"first").unwrap();
+        let second = chan1.send_unreliable(b"second").unwrap();
+
+        assert!(chan2.recv_unreliable(second[0].as_slice()).unwrap().is_some());
+        assert!(chan2.recv_unreliable(first[0].as_slice()).unwrap().is_none());
+        assert!(chan2.recv_unreliable(second[0].as_slice()).unwrap().is_none());
+
+        let counters = chan2.get_counters();
+        assert_eq!(counters.dropped, 1);
+        assert_eq!(counters.out_of_order, 1);
+        assert_eq!(counters.duplicated, 1);
+    }
+
+    /// Acks arriving in any order must never panic or move backwards.
+    #[test]
+    fn out_of_order_acks() {
+        for &start in [0, ::std::u32::MAX - 10].iter() {
+            for seed in range(0u32, 20) {
+                let (mut chan1, mut chan2) = (NetChannel::new(), NetChannel::new());
+                chan1.last_outgoing = start;
+                chan1.last_acked_outgoing = start;
+                chan2.last_incoming = start;
+
+                let mut replies = Vec::new();
+                for _ in range(0u, 20) {
+                    let pkt = chan1.send_unreliable(b"ping").unwrap();
+                    chan2.recv_unreliable(pkt[0].as_slice()).unwrap();
+                    replies.push(chan2.send_unreliable(b"pong").unwrap().into_iter().next().unwrap());
+                }
+
+                let mut rng: XorShiftRng = SeedableRng::from_seed([seed, 1, 2, 3]);
+                rng.shuffle(replies.as_mut_slice());
+
+                let mut last_acked = chan1.get_acked_outgoing_sequencenr();
+                for reply in replies.iter() {
+                    chan1.recv_unreliable(reply.as_slice()).unwrap();
+                    let acked = chan1.get_acked_outgoing_sequencenr();
+                    assert!(super::overflow_aware_compare(acked, last_acked) != ::std::cmp::Less);
+                    last_acked = acked;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rtt_smoothing() {
+        let mut chan = NetChannel::new();
+        chan.add_rtt_sample(0.1);
+        assert_eq!(chan.get_stats().rtt, 0.1);
+        assert_eq!(chan.get_stats().rtt_variance, 0.05);
+
+        for _ in range(0u, 100) {
+            chan.add_rtt_sample(0.2);
+        }
+        let stats = chan.get_stats();
+        assert!((stats.rtt - 0.2).abs() < 0.001);
+        assert!(stats.rtt_variance < 0.001);
+    }
+
+    #[test]
+    fn loss_statistics() {
+        let (mut chan1, mut chan2) = (NetChannel::new(), NetChannel::new());
+
+        // lose every fourth packet on the way to chan2, and have chan2 reply to the rest
+        for i in range(0u, 100) {
+            let pkt = chan1.send_unreliable(b"ping").unwrap();
+            if i % 4 != 3 {
+                chan2.recv_unreliable(pkt[0].as_slice()).unwrap();
+                let reply = chan2.send_unreliable(b"pong").unwrap();
+                chan1.recv_unreliable(reply[0].as_slice()).unwrap();
+            }
+        }
+
+        let stats2 = chan2.get_stats();
+        assert!((stats2.loss_in - 25.).abs() < 2., "loss_in was {}", stats2.loss_in);
+        assert_eq!(stats2.loss_out, 0.);
+
+        let stats1 = chan1.get_stats();
+        assert_eq!(stats1.loss_in, 0.);
+        assert!((stats1.loss_out - 25.).abs() < 2., "loss_out was {}", stats1.loss_out);
+        assert!(stats1.bytes_out_per_sec > stats1.bytes_in_per_sec);
+    }
+
+    #[test]
+    fn out_of_band() {
+        let mut chan = NetChannel::new();
+        let oob = super::send_out_of_band(b"getchallenge").unwrap();
+        assert!(super::is_out_of_band(oob.as_slice()));
+        assert_eq!(super::recv_out_of_band(oob.as_slice()).unwrap().as_slice(), b"getchallenge");
+        assert!(chan.recv_unreliable(oob.as_slice()).is_err());
+
+        let inband = chan.send_unreliable(b"hello").unwrap();
+        assert!(!super::is_out_of_band(inband[0].as_slice()));
+    }
 }
@@ -1,9 +1,48 @@
-use std::collections::{Deque, HashMap, RingBuf};
+use std::collections::{Deque, HashMap, HashSet, RingBuf};
 use component::{RawComponentHandle, ComponentStore};
 use super::{ComponentUpdate, Change, Destroy};
 
+/// Which entities one client was interested in on each recent tick,
+/// newest first, lined up with a DeltaEncoder's states.
+pub struct RelevanceHistory {
+    sets: RingBuf<HashSet<RawComponentHandle>>,
+    max_states: uint
+}
+
+impl RelevanceHistory {
+    pub fn new(max_states: uint) -> RelevanceHistory {
+        RelevanceHistory {
+            sets: RingBuf::with_capacity(max_states),
+            max_states: max_states
+        }
+    }
+
+    /// Should be called once for every DeltaEncoder::add_state.
+    pub fn push(&mut self, relevant: HashSet<RawComponentHandle>) {
+        self.sets.push_front(relevant);
+        while self.sets.len() > self.max_states {
+            self.sets.pop();
+        }
+    }
+
+    /// The entities relevant in the newest state.
+    pub fn newest(&self) -> Option<&HashSet<RawComponentHandle>> {
+        self.sets.front()
+    }
+
+    /// Whether `entity` was relevant `age` states ago.
+    /// Anything from before we started tracking wasn't.
+    pub fn is_relevant(&self, age: uint, entity: &RawComponentHandle) -> bool {
+        if age >= self.sets.len() {
+            return false;
+        }
+        self.sets[age].contains(entity)
+    }
+}
+
 pub struct DeltaEncoder<Component, MarshalledComponent> {
-    states: RingBuf<HashMap<RawComponentHandle, MarshalledComponent>>,
+    /// Each component is stored along with the entity it belongs to.
+    states: RingBuf<HashMap<RawComponentHandle, (RawComponentHandle, MarshalledComponent)>>,
     max_states: uint
 }
 
@@ -15,13 +54,15 @@ impl<Component, MarshalledComponent: PartialEq + Clone> DeltaEncoder<Component,
         }
     }
 
+    /// `owner` says which entity a component belongs to, for relevance filtering.
     pub fn add_state(&mut self, components: &ComponentStore<Component>,
-                    marshaller: |&Component| -> MarshalledComponent ) {
+                    marshaller: |&Component| -> MarshalledComponent,
+                    owner: |&Component| -> RawComponentHandle) {
         let mut state = HashMap::new(); // FIXME: with_capacity
 
         for (handle, component) in components.iter() {
             let marshalled = marshaller(component);
-            state.insert(handle.to_raw(), marshalled);
+            state.insert(handle.to_raw(), (owner(component), marshalled));
         }
 
         self.states.push_front(state);
@@ -31,24 +72,41 @@ impl<Component, MarshalledComponent: PartialEq + Clone> DeltaEncoder<Component,
         }
     }
 
-    fn create_full_update(&self) -> Vec<ComponentUpdate<MarshalledComponent>> {
+    fn create_full_update(&self, relevance: &RelevanceHistory) -> Vec<(RawComponentHandle, ComponentUpdate<MarshalledComponent>)> {
+        self.full_state(|owner| relevance.is_relevant(0, owner))
+    }
+
+    /// The current state of every component whose entity passes `filter`,
+    /// along with that entity.
+    pub fn full_state(&self, filter: |&RawComponentHandle| -> bool) -> Vec<(RawComponentHandle, ComponentUpdate<MarshalledComponent>)> {
         let mut updates = Vec::new();
-        for (handle, comp) in self.states[0].iter() {
-            updates.push(ComponentUpdate {
+        if self.states.is_empty() {
+            return updates;
+        }
+        for (handle, &(ref owner, ref comp)) in self.states[0].iter() {
+            if !filter(owner) {
+                continue;
+            }
+            updates.push((*owner, ComponentUpdate {
                 target: *handle,
                 data: Change(comp.clone())
-            });
+            }));
         }
         updates
     }
 
     /// Length = number of ticks to cover
     /// e.g. length of 1 is delta between current and previous state
-    pub fn create_delta(&self, length: u64) -> Vec<ComponentUpdate<MarshalledComponent>> {
+    ///
+    /// Only components whose entity is relevant are sent. Entities that
+    /// stop being relevant are destroyed, and recreated when they come back.
+    ///
+    /// Each update comes with the entity its component belongs to.
+    pub fn create_delta(&self, length: u64, relevance: &RelevanceHistory) -> Vec<(RawComponentHandle, ComponentUpdate<MarshalledComponent>)> {
         assert!(length > 0);
 
         if length >= self.states.len() as u64 {
-            return self.create_full_update();
+            return self.create_full_update(relevance);
         };
 
         // FIXME: should this be a hashmap? seems expensive. lots of alloc
@@ -58,26 +116,146 @@ impl<Component, MarshalledComponent: PartialEq + Clone> DeltaEncoder<Component,
         // borrowck hates iterators
         // remember indices go newest to oldest,
         // so we reverse here.
-        for state_idx in range(0u, length as uint - 1).rev() {
+        for state_idx in range(0u, length as uint).rev() {
             let ref curr_state = self.states[state_idx];
             let ref prev_state = self.states[state_idx + 1];
 
-            for (handle, comp) in curr_state.iter() {
+            for (handle, &(ref owner, ref comp)) in curr_state.iter() {
+                if !relevance.is_relevant(state_idx, owner) {
+                    continue;
+                }
                 let has_changed = match prev_state.find(handle) {
-                    Some(prev_comp) => prev_comp != comp,
+                    Some(&(ref prev_owner, ref prev_comp)) =>
+                        prev_comp != comp || !relevance.is_relevant(state_idx + 1, prev_owner),
                     None => true
                 };
                 if has_changed {
-                    updates.insert(handle, Change(comp.clone()));
+                    updates.insert(handle, (*owner, Change(comp.clone())));
                 };
             }
             // removals aren't covered in the previous loop,
             // so we have to go through here. this sucks.
-            for (handle, _) in prev_state.iter().filter(|&(handle, _)| curr_state.find(handle).is_none()) {
-                updates.insert(handle, Destroy);
+            for (handle, &(ref owner, _)) in prev_state.iter() {
+                if !relevance.is_relevant(state_idx + 1, owner) {
+                    continue;
+                }
+                let still_there = match curr_state.find(handle) {
+                    Some(&(ref curr_owner, _)) => relevance.is_relevant(state_idx, curr_owner),
+                    None => false
+                };
+                if !still_there {
+                    updates.insert(handle, (*owner, Destroy));
+                }
             }
         }
 
-        updates.into_iter().map(|(&k, v)| ComponentUpdate { target: k, data: v }).collect()
+        updates.into_iter().map(|(&k, (owner, v))| (owner, ComponentUpdate { target: k, data: v })).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use component::ComponentStore;
+    use component::RawComponentHandle;
+    use network::{Change, ComponentUpdate, ComponentUpdateType, Destroy};
+    use super::{DeltaEncoder, RelevanceHistory};
+
+    #[deriving(Clone, PartialEq)]
+    struct Thing {
+        value: int
+    }
+
+    fn data<'a>(update: &'a (RawComponentHandle, ComponentUpdate<Thing>)) -> &'a ComponentUpdateType<Thing> {
+        let &(_, ref update) = update;
+        &update.data
+    }
+
+    fn everything(store: &ComponentStore<Thing>) -> HashSet<RawComponentHandle> {
+        store.iter().map(|(handle, _)| handle.to_raw()).collect()
+    }
+
+    #[test]
+    fn single_tick_delta() {
+        let mut store = ComponentStore::new();
+        let handle = store.add(Thing { value: 1 });
+        let mut encoder: DeltaEncoder<Thing, Thing> = DeltaEncoder::new(8);
+        let mut relevance = RelevanceHistory::new(8);
+        for _ in range(0u, 3) {
+            encoder.add_state(&store, |t| t.clone(), |_| handle.to_raw());
+            relevance.push(everything(&store));
+        }
+
+        store.find_mut(handle).unwrap().value = 2;
+        encoder.add_state(&store, |t| t.clone(), |_| handle.to_raw());
+        relevance.push(everything(&store));
+
+        let delta = encoder.create_delta(1, &relevance);
+        assert_eq!(delta.len(), 1);
+        match *data(&delta[0]) {
+            Change(ref t) => assert_eq!(t.value, 2),
+            Destroy => fail!("Expected a change")
+        }
+    }
+
+    #[test]
+    fn delta_covers_oldest_tick() {
+        // Deltas used to skip the oldest tick in the window (range(0, length - 1)),
+        // so a change right after the client's baseline never got sent.
+        let mut store = ComponentStore::new();
+        let handle = store.add(Thing { value: 1 });
+        let mut encoder: DeltaEncoder<Thing, Thing> = DeltaEncoder::new(8);
+        let mut relevance = RelevanceHistory::new(8);
+        for _ in range(0u, 3) {
+            encoder.add_state(&store, |t| t.clone(), |_| handle.to_raw());
+            relevance.push(everything(&store));
+        }
+
+        // changes just after the baseline, then sits still for two ticks
+        store.find_mut(handle).unwrap().value = 2;
+        for _ in range(0u, 3) {
+            encoder.add_state(&store, |t| t.clone(), |_| handle.to_raw());
+            relevance.push(everything(&store));
+        }
+
+        let delta = encoder.create_delta(3, &relevance);
+        assert_eq!(delta.len(), 1);
+        match *data(&delta[0]) {
+            Change(ref t) => assert_eq!(t.value, 2),
+            Destroy => fail!("Expected a change")
+        }
+        // and a baseline that already has it gets nothing
+        assert_eq!(encoder.create_delta(2, &relevance).len(), 0);
+    }
+
+    #[test]
+    fn irrelevant_entities_destroyed_and_recreated() {
+        let mut store = ComponentStore::new();
+        let handle = store.add(Thing { value: 1 });
+        let mut encoder: DeltaEncoder<Thing, Thing> = DeltaEncoder::new(8);
+        let mut relevance = RelevanceHistory::new(8);
+        for _ in range(0u, 2) {
+            encoder.add_state(&store, |t| t.clone(), |_| handle.to_raw());
+            relevance.push(everything(&store));
+        }
+
+        // goes out of view
+        encoder.add_state(&store, |t| t.clone(), |_| handle.to_raw());
+        relevance.push(HashSet::new());
+        let delta = encoder.create_delta(1, &relevance);
+        assert_eq!(delta.len(), 1);
+        assert!(match *data(&delta[0]) { Destroy => true, _ => false });
+
+        // comes back, unchanged
+        encoder.add_state(&store, |t| t.clone(), |_| handle.to_raw());
+        relevance.push(everything(&store));
+        let delta = encoder.create_delta(1, &relevance);
+        assert_eq!(delta.len(), 1);
+        assert!(match *data(&delta[0]) { Change(_) => true, _ => false });
+
+        // nothing new to say about it
+        encoder.add_state(&store, |t| t.clone(), |_| handle.to_raw());
+        relevance.push(everything(&store));
+        assert_eq!(encoder.create_delta(1, &relevance).len(), 0);
     }
 }
@@ -0,0 +1,151 @@
+//! Demo files: a recording of every message a client got from the server.
+//!
+//! A demo is a header followed by frames, each holding one encoded
+//! ServerToClient message and when it arrived, in seconds from the
+//! start of the recording. Playing one back is just decoding the
+//! messages and handling them like the client normally would.
+
+use std::io::{EndOfFile, IoError, IoResult, InvalidInput};
+use super::{encode_server_msg, ServerToClient, PROTOCOL_VERSION};
+
+static DEMO_MAGIC: &'static [u8] = b"NMIGPDEM";
+
+/// Far bigger than any message the server can send. A frame that claims
+/// to be longer is garbage, and shouldn't get to allocate that much.
+static MAX_FRAME_LEN: uint = 16 * 1024 * 1024;
+
+pub struct DemoFrame {
+    /// Seconds since the recording started.
+    pub time: f64,
+    /// An encoded ServerToClient message.
+    pub data: Vec<u8>
+}
+
+pub struct DemoWriter<W> {
+    out: W,
+    start: f64
+}
+
+impl<W: Writer> DemoWriter<W> {
+    /// Starts a demo, with frame times measured from `start`.
+    pub fn new(mut out: W, start: f64) -> IoResult<DemoWriter<W>> {
+        try!(out.write(DEMO_MAGIC));
+        try!(out.write_be_u32(PROTOCOL_VERSION));
+        Ok(DemoWriter { out: out, start: start })
+    }
+
+    /// Records an already-encoded message, received at `time`.
+    pub fn write_raw(&mut self, time: f64, data: &[u8]) -> IoResult<()> {
+        try!(self.out.write_be_f64(time - self.start));
+        try!(self.out.write_be_u32(data.len() as u32));
+        self.out.write(data)
+    }
+
+    pub fn write_message(&mut self, time: f64, msg: &ServerToClient) -> IoResult<()> {
+        self.write_raw(time, encode_server_msg(msg).as_slice())
+    }
+
+    pub fn flush(&mut self) -> IoResult<()> {
+        self.out.flush()
+    }
+}
+
+fn bad_demo(desc: &'static str) -> IoError {
+    IoError {
+        kind: InvalidInput,
+        desc: desc,
+        detail: None
+    }
+}
+
+/// Reads a whole demo. Demos from other protocol versions are rejected,
+/// since their messages won't decode.
+pub fn read_demo<R: Reader>(input: &mut R) -> IoResult<Vec<DemoFrame>> {
+    let magic = try!(input.read_exact(DEMO_MAGIC.len()));
+    if magic.as_slice() != DEMO_MAGIC {
+        return Err(bad_demo("Not a demo file"));
+    }
+    if try!(input.read_be_u32()) != PROTOCOL_VERSION {
+        return Err(bad_demo("Demo was recorded with a different protocol version"));
+    }
+
+    let mut frames = Vec::new();
+    loop {
+        let time = match input.read_be_f64() {
+            Ok(time) => time,
+            Err(IoError { kind: EndOfFile, .. }) => return Ok(frames),
+            Err(e) => return Err(e)
+        };
+        // A client that crashed mid-write leaves a partial frame at the end.
+        let len = match input.read_be_u32() {
+            Ok(len) => len as uint,
+            Err(IoError { kind: EndOfFile, .. }) => return Ok(frames),
+            Err(e) => return Err(e)
+        };
+        if len > MAX_FRAME_LEN {
+            return Err(bad_demo("Frame in demo is too big"));
+        }
+        let data = match input.read_exact(len) {
+            Ok(data) => data,
+            Err(IoError { kind: EndOfFile, .. }) => return Ok(frames),
+            Err(e) => return Err(e)
+        };
+        frames.push(DemoFrame { time: time, data: data });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufReader, MemWriter};
+    use network::{decode_server_msg, Disconnected, PROTOCOL_VERSION};
+    use super::{read_demo, DemoWriter, DEMO_MAGIC};
+
+    #[test]
+    fn roundtrip() {
+        let mut writer = DemoWriter::new(MemWriter::new(), 100.).unwrap();
+        writer.write_message(100.5, &Disconnected("bye".to_string())).unwrap();
+        writer.write_raw(101., &[1, 2, 3]).unwrap();
+        let bytes = writer.out.unwrap();
+
+        let frames = read_demo(&mut BufReader::new(bytes.as_slice())).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].time, 0.5);
+        match decode_server_msg(frames[0].data.as_slice()) {
+            Some(Disconnected(reason)) => assert_eq!(reason.as_slice(), "bye"),
+            _ => fail!("Wrong message")
+        }
+        assert_eq!(frames[1].time, 1.);
+        assert_eq!(frames[1].data, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn truncated_frame_dropped() {
+        let mut writer = DemoWriter::new(MemWriter::new(), 0.).unwrap();
+        writer.write_raw(1., &[1, 2, 3]).unwrap();
+        writer.write_raw(2., &[4, 5, 6]).unwrap();
+        let bytes = writer.out.unwrap();
+
+        // cut off in the middle of the last frame's data, then its header
+        for &cut in [2u, 8].iter() {
+            let frames = read_demo(&mut BufReader::new(bytes.slice_to(bytes.len() - cut))).unwrap();
+            assert_eq!(frames.len(), 1);
+            assert_eq!(frames[0].data, vec![1, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn not_a_demo() {
+        assert!(read_demo(&mut BufReader::new(b"definitely not a demo")).is_err());
+    }
+
+    #[test]
+    fn huge_frame_rejected() {
+        let mut out = MemWriter::new();
+        out.write(DEMO_MAGIC).unwrap();
+        out.write_be_u32(PROTOCOL_VERSION).unwrap();
+        out.write_be_f64(0.).unwrap();
+        out.write_be_u32(0xFFFFFFFF).unwrap();
+        let bytes = out.unwrap();
+        assert!(read_demo(&mut BufReader::new(bytes.as_slice())).is_err());
+    }
+}
@@ -0,0 +1,201 @@
+//! Talking to a master server, which keeps a list of servers on the internet.
+//!
+//! Game servers send the master a Heartbeat every so often. The master
+//! doesn't take their word for anything: it sends back an InfoRequest,
+//! just like a client querying the server would, and only lists servers
+//! that answer it. Clients ask the master for the list a page at a time,
+//! so a small request never gets a big reply, and then query the servers
+//! themselves for up to date info and their ping.
+//!
+//! Everything here is out-of-band.
+
+use std::io::{IoResult, TimedOut};
+use std::io::net::ip::SocketAddr;
+use serialize::json;
+use super::channel::{is_out_of_band, recv_out_of_band, send_out_of_band};
+use super::transport::Transport;
+
+/// The port master servers listen on unless told otherwise.
+pub static DEFAULT_MASTER_PORT: u16 = 18290;
+/// Seconds between heartbeats from a game server.
+pub static HEARTBEAT_INTERVAL: f64 = 60.;
+/// The most servers in one ServerList, so it fits in a single datagram.
+pub static LIST_PAGE_SIZE: uint = 32;
+/// Seconds a client waits for each page of the server list.
+static LIST_TIMEOUT: f64 = 1.;
+
+#[deriving(Encodable, Decodable)]
+pub enum ToMaster {
+    /// From a game server, to get (or stay) listed.
+    Heartbeat(HeartbeatPacket),
+    /// From a client, for a page of the server list.
+    ListRequest(ListRequestPacket)
+}
+
+#[deriving(Encodable, Decodable)]
+pub enum FromMaster {
+    ServerList(ServerListPacket)
+}
+
+#[deriving(Encodable, Decodable)]
+pub struct HeartbeatPacket {
+    pub protocol_version: u32
+}
+
+#[deriving(Encodable, Decodable)]
+pub struct ListRequestPacket {
+    /// How many servers into the list to start from.
+    pub start: u32,
+    /// Only list servers running this protocol version, if given.
+    pub protocol_version: Option<u32>
+}
+
+#[deriving(Encodable, Decodable, Clone)]
+pub struct ServerListPacket {
+    /// Addresses, like "1.2.3.4:18295".
+    pub servers: Vec<String>,
+    /// Where the next page starts, if there is one.
+    pub next: Option<u32>
+}
+
+pub fn encode_to_master(msg: &ToMaster) -> Vec<u8> {
+    let encoded = json::encode(msg).into_bytes();
+    ::flate::deflate_bytes_zlib(encoded.as_slice()).expect("Compression failed!").as_slice().to_vec()
+}
+
+pub fn encode_from_master(msg: &FromMaster) -> Vec<u8> {
+    let encoded = json::encode(msg).into_bytes();
+    ::flate::deflate_bytes_zlib(encoded.as_slice()).expect("Compression failed!").as_slice().to_vec()
+}
+
+/// Returns None if the message is garbage.
+pub fn decode_to_master(data: &[u8]) -> Option<ToMaster> {
+    let inflated = match ::flate::inflate_bytes_zlib(data) {
+        Some(inflated) => inflated,
+        None => return None
+    };
+    match ::std::str::from_utf8(inflated.as_slice()) {
+        Some(msg) => json::decode(msg).ok(),
+        None => None
+    }
+}
+
+/// Returns None if the message is garbage.
+pub fn decode_from_master(data: &[u8]) -> Option<FromMaster> {
+    let inflated = match ::flate::inflate_bytes_zlib(data) {
+        Some(inflated) => inflated,
+        None => return None
+    };
+    match ::std::str::from_utf8(inflated.as_slice()) {
+        Some(msg) => json::decode(msg).ok(),
+        None => None
+    }
+}
+
+/// Tells the master at `master` about the server `transport` is bound to.
+pub fn send_heartbeat(transport: &mut Transport, master: SocketAddr) -> IoResult<()> {
+    let msg = Heartbeat(HeartbeatPacket { protocol_version: super::PROTOCOL_VERSION });
+    let datagram = try!(send_out_of_band(encode_to_master(&msg).as_slice()));
+    transport.send_to(datagram.as_slice(), master)
+}
+
+/// Gets the whole server list from the master at `master`, a page at a
+/// time. Each page is asked for up to `retries` times before giving up.
+pub fn fetch_server_list(transport: &mut Transport, master: SocketAddr,
+                         protocol_version: Option<u32>, retries: u32) -> IoResult<Vec<SocketAddr>> {
+    let mut servers = Vec::new();
+    let mut start = 0u32;
+    let mut buf = [0u8, ..2048];
+
+    loop {
+        let mut page = None;
+        for _ in range(0, retries) {
+            let msg = ListRequest(ListRequestPacket { start: start, protocol_version: protocol_version });
+            let datagram = try!(send_out_of_band(encode_to_master(&msg).as_slice()));
+            try!(transport.send_to(datagram.as_slice(), master));
+
+            let deadline = ::time::precise_time_s() + LIST_TIMEOUT;
+            while page.is_none() {
+                let remaining = deadline - ::time::precise_time_s();
+                if remaining <= 0. {
+                    break;
+                }
+                transport.set_read_timeout(Some((remaining * 1000.) as u64));
+                let (len, from) = match transport.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(ref e) if e.kind == TimedOut => break,
+                    Err(e) => return Err(e)
+                };
+                let datagram = buf.slice_to(len);
+                if from != master || !is_out_of_band(datagram) {
+                    continue;
+                }
+                match recv_out_of_band(datagram).ok().and_then(|data| decode_from_master(data.as_slice())) {
+                    Some(ServerList(list)) => page = Some(list),
+                    None => ()
+                }
+            }
+            if page.is_some() {
+                break;
+            }
+        }
+
+        let page = match page {
+            Some(page) => page,
+            None => return Err(::std::io::IoError {
+                kind: TimedOut,
+                desc: "Master server didn't answer",
+                detail: None
+            })
+        };
+        // Bad addresses from the master are its problem, not ours.
+        servers.extend(page.servers.iter().filter_map(|addr| from_str(addr.as_slice())));
+        match page.next {
+            // Make sure a confused master can't keep us here forever.
+            Some(next) if next > start => start = next,
+            _ => return Ok(servers)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::net::ip::SocketAddr;
+    use network::channel::{recv_out_of_band, send_out_of_band};
+    use network::transport::{MemoryNetwork, Transport};
+    use super::{decode_to_master, encode_from_master, fetch_server_list, ListRequest, ServerList, ServerListPacket};
+
+    #[test]
+    fn list_fetched_in_pages() {
+        let network = MemoryNetwork::new();
+        let mut master = network.bind_any();
+        let master_addr = master.local_addr().unwrap();
+        let all: Vec<String> = range(0u, 5).map(|i| format!("10.0.0.{}:18295", i)).collect();
+
+        // The master runs on another task, answering two servers at a time.
+        spawn(proc() {
+            let mut buf = [0u8, ..2048];
+            for _ in range(0u, 3) {
+                let (len, from) = master.recv_from(&mut buf).unwrap();
+                let request = match decode_to_master(recv_out_of_band(buf.slice_to(len)).unwrap().as_slice()) {
+                    Some(ListRequest(request)) => request,
+                    _ => fail!("Expected a list request")
+                };
+                let start = request.start as uint;
+                let end = ::std::cmp::min(start + 2, all.len());
+                let reply = ServerList(ServerListPacket {
+                    servers: all.slice(start, end).to_vec(),
+                    next: if end < all.len() { Some(end as u32) } else { None }
+                });
+                let datagram = send_out_of_band(encode_from_master(&reply).as_slice()).unwrap();
+                master.send_to(datagram.as_slice(), from).unwrap();
+            }
+        });
+
+        let mut client = network.bind_any();
+        let servers = fetch_server_list(&mut client, master_addr, None, 3).unwrap();
+        assert_eq!(servers.len(), 5);
+        let expected: SocketAddr = from_str("10.0.0.4:18295").unwrap();
+        assert_eq!(servers[4], expected);
+    }
+}
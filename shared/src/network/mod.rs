@@ -1,44 +1,222 @@
 pub use playercmd::PlayerCommand;
 use component::{RawComponentHandle};
-use component::components::NoHandleEntityComponent;
+use serialize::json;
 
+pub mod challenge;
 pub mod channel;
 pub mod protocol;
+pub mod query;
 pub mod delta;
+pub mod demo;
+pub mod master;
+pub mod priority;
+pub mod replay;
+pub mod replication;
+pub mod simulator;
+pub mod transport;
+
+/// Bumped whenever the client and server stop being able to talk to each other.
+pub static PROTOCOL_VERSION: u32 = 11;
+
+/// The port servers listen on unless told otherwise, and where LAN
+/// discovery looks for them.
+pub static DEFAULT_PORT: u16 = 18295;
+
+/// How many of the most recent commands each Playercmd packet carries,
+/// so a lost packet doesn't mean lost input.
+pub static COMMANDS_PER_PACKET: uint = 4;
+/// How many bytes per second of updates clients ask for, unless told otherwise.
+pub static DEFAULT_RATE: u32 = 64 * 1024;
+/// Slowest and fastest rates clients can ask for, in bytes per second.
+pub static MIN_RATE: u32 = 4 * 1024;
+pub static MAX_RATE: u32 = 1024 * 1024;
+/// How many updates per second clients ask for, unless told otherwise.
+pub static DEFAULT_SNAPSHOT_RATE: u32 = 128;
+/// How many Playercmd packets per second clients send, unless told otherwise.
+pub static DEFAULT_COMMAND_RATE: u32 = 128;
+
+/// Turns a rate in Hz into the number of ticks between sends,
+/// which is at least 1.
+pub fn rate_to_interval(rate: u32) -> u32 {
+    if rate == 0 {
+        return 1;
+    }
+    let interval = (1. / (rate as f32 * ::TICK_LENGTH)).round() as u32;
+    ::std::cmp::max(interval, 1)
+}
+
+/// The most commands a Playercmd packet should carry when they're sent
+/// every `command_interval` ticks: the ones since the last packet,
+/// plus a few old ones in case that got lost.
+pub fn commands_per_packet(command_interval: u32) -> uint {
+    command_interval as uint + COMMANDS_PER_PACKET - 1
+}
 
 #[deriving(Encodable, Decodable)]
 pub enum ClientToServer {
-    Connect,
-    Playercmd(PlayerCommand),
-    Disconnect
+    /// Sent out-of-band to ask for a challenge.
+    Connect(ConnectPacket),
+    /// Sent out-of-band, echoing the server's challenge.
+    ChallengeResponse(ChallengeResponsePacket),
+    /// The client's most recent commands, oldest first.
+    Playercmd(Vec<PlayerCommand>),
+    /// The client is leaving, and why.
+    Disconnect(String),
+    /// Sent out-of-band to ask about a server without connecting.
+    InfoRequest(InfoRequestPacket)
 }
 
 #[deriving(Encodable, Decodable)]
 pub enum ServerToClient {
+    /// Sent out-of-band in reply to a Connect.
+    Challenge(ChallengePacket),
+    /// Sent out-of-band when the server won't let a client in.
+    Reject(String),
     Signon(SignonPacket),
-    Update(UpdatePacket)
+    Update(UpdatePacket),
+    /// The server has dropped this client, and why.
+    Disconnected(String),
+    PlayerLeft(PlayerLeftPacket),
+    /// Sent out-of-band in reply to an InfoRequest.
+    Info(ServerInfoPacket)
+}
+
+#[deriving(Encodable, Decodable)]
+pub struct ConnectPacket {
+    pub protocol_version: u32,
+    pub name: String
+}
+
+#[deriving(Encodable, Decodable)]
+pub struct ChallengePacket {
+    pub challenge: u64
+}
+
+/// The server keeps no state for a client until it gets one of these,
+/// so everything from the Connect is sent again.
+#[deriving(Encodable, Decodable)]
+pub struct ChallengeResponsePacket {
+    pub challenge: u64,
+    pub protocol_version: u32,
+    pub name: String,
+    /// How many bytes per second the client wants from us.
+    pub rate: u32,
+    /// How many updates per second the client wants.
+    pub snapshot_rate: u32,
+    /// How many Playercmd packets per second the client wants to send.
+    pub command_rate: u32,
+    /// Set to spectate, to the secret the server's configured with.
+    /// Spectators aren't in the world, and get told about everything in it.
+    /// They don't send commands, just empty Playercmds to keep the connection up.
+    pub spectator_secret: Option<String>
 }
 
 #[deriving(Encodable, Decodable)]
 pub struct UpdatePacket {
     pub tick: u64,
-    pub entity_updates: Vec<ComponentUpdate<NoHandleEntityComponent>>
+    /// The number of the last command the server ran for this client.
+    pub last_command: u32,
+    pub component_updates: Vec<ComponentTypeUpdate>,
+    /// Set when this isn't a delta from anything the client has: every
+    /// entity it should have. It can't have heard about the others being
+    /// destroyed, so it drops them.
+    pub all_entities: Option<Vec<RawComponentHandle>>
+}
+
+/// Updates for every component of one replicated type.
+#[deriving(Encodable, Decodable, Clone)]
+pub struct ComponentTypeUpdate {
+    /// Which ComponentReplicator these are for.
+    pub net_id: u16,
+    /// Marshalled components are embedded as JSON by their replicator.
+    pub updates: Vec<ComponentUpdate<json::Json>>
 }
 
+/// Info queries work across protocol versions, so clients can
+/// list servers they'd need to upgrade (or downgrade) to play on.
 #[deriving(Encodable, Decodable)]
-pub struct SignonPacket {
-    pub handle: RawComponentHandle
+pub struct InfoRequestPacket {
+    /// When the query was sent, by the asker's clock. Echoed back to work out the ping.
+    pub time: f64,
+    /// Echoed back, for askers that need to know a reply's to their own
+    /// request, like a master server checking a heartbeat. Others send 0.
+    pub token: u64
+}
+
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct ServerInfoPacket {
+    /// Copied from the InfoRequest.
+    pub time: f64,
+    pub token: u64,
+    pub protocol_version: u32,
+    pub name: String,
+    pub map: String,
+    pub players: u32,
+    pub max_players: u32
+}
+
+/// Tells clients that somebody else has left the game.
+#[deriving(Encodable, Decodable)]
+pub struct PlayerLeftPacket {
+    pub name: String,
+    pub reason: String
 }
 
 #[deriving(Encodable, Decodable)]
+pub struct SignonPacket {
+    /// The tick the server was on when it sent this, to start the client's clock.
+    pub tick: u64,
+    /// The client's own entity. Spectators don't get one.
+    pub handle: Option<RawComponentHandle>,
+    /// Ticks between updates, as agreed on from the client's snapshot rate.
+    pub snapshot_interval: u32,
+    /// Ticks between Playercmd packets, as agreed on from the client's command rate.
+    pub command_interval: u32
+}
+
+#[deriving(Encodable, Decodable, Clone)]
 pub struct ComponentUpdate<MarshalledComponent> {
     target: RawComponentHandle,
     data: ComponentUpdateType<MarshalledComponent>
 }
 
-#[deriving(Encodable, Decodable)]
+#[deriving(Encodable, Decodable, Clone)]
 pub enum ComponentUpdateType<MarshalledComponent> {
     Change(MarshalledComponent),
     Destroy
 }
 
+
+pub fn encode_client_msg(msg: &ClientToServer) -> Vec<u8> {
+    let encoded = json::encode(msg).into_bytes();
+    ::flate::deflate_bytes_zlib(encoded.as_slice()).expect("Compression failed!").as_slice().to_vec()
+}
+
+pub fn encode_server_msg(msg: &ServerToClient) -> Vec<u8> {
+    let encoded = json::encode(msg).into_bytes();
+    ::flate::deflate_bytes_zlib(encoded.as_slice()).expect("Compression failed!").as_slice().to_vec()
+}
+
+/// Returns None if the message is garbage.
+pub fn decode_client_msg(data: &[u8]) -> Option<ClientToServer> {
+    let inflated = match ::flate::inflate_bytes_zlib(data) {
+        Some(inflated) => inflated,
+        None => return None
+    };
+    match ::std::str::from_utf8(inflated.as_slice()) {
+        Some(msg) => json::decode(msg).ok(),
+        None => None
+    }
+}
+
+/// Returns None if the message is garbage.
+pub fn decode_server_msg(data: &[u8]) -> Option<ServerToClient> {
+    let inflated = match ::flate::inflate_bytes_zlib(data) {
+        Some(inflated) => inflated,
+        None => return None
+    };
+    match ::std::str::from_utf8(inflated.as_slice()) {
+        Some(msg) => json::decode(msg).ok(),
+        None => None
+    }
+}
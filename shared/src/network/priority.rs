@@ -0,0 +1,235 @@
+//! Deciding which entity updates fit in each client's packets, for the
+//! server and for relays passing the game on to their viewers.
+//!
+//! Every entity with something to send builds up priority each tick,
+//! faster if it's important or close to the client. When there isn't
+//! room for everything, the highest-priority entities go first and the
+//! rest wait, getting more urgent until they're sent.
+
+use std::collections::{HashMap, HashSet};
+use std::iter::AdditiveIterator;
+use cgmath::{EuclideanVector, Point, Point3};
+use component::{EntityComponent, RawComponentHandle};
+use component::components::{ArchetypeId, PLAYER_ARCHETYPE, PROJECTILE_ARCHETYPE};
+use super::delta::RelevanceHistory;
+use super::replication::{PendingUpdate, ReplicationRegistry};
+
+/// Distance at which an entity's priority grows half as fast, in world units.
+static DISTANCE_SCALE: f32 = 50.;
+/// How much more important a client's own entity is than anything else.
+static SELF_IMPORTANCE: f32 = 10.;
+
+fn archetype_importance(archetype: ArchetypeId) -> f32 {
+    if archetype == PLAYER_ARCHETYPE {
+        2.
+    } else if archetype == PROJECTILE_ARCHETYPE {
+        1.5
+    } else {
+        1.
+    }
+}
+
+/// One client's bandwidth budget and update priorities.
+pub struct UpdateScheduler {
+    /// Bytes per second this client can take.
+    pub rate: u32,
+    priorities: HashMap<RawComponentHandle, f32>,
+    /// Entities the client might be out of date on.
+    /// None if their update got deferred, or the sequence number
+    /// of the packet their full state went out in.
+    pending: HashMap<RawComponentHandle, Option<u32>>
+}
+
+impl UpdateScheduler {
+    pub fn new(rate: u32) -> UpdateScheduler {
+        UpdateScheduler {
+            rate: rate,
+            priorities: HashMap::new(),
+            pending: HashMap::new()
+        }
+    }
+
+    /// Picks which updates go in the packet numbered `sequence`, which
+    /// covers `interval` seconds. `acked` is the newest update the client
+    /// is known to have received, if any. `viewer` is the client's own
+    /// entity; spectators don't have one.
+    ///
+    /// Destroys always go out. The highest-priority entity always goes out
+    /// too, even if it's bigger than the budget, so nothing gets stuck.
+    pub fn schedule(&mut self,
+                    updates: Vec<PendingUpdate>,
+                    registry: &ReplicationRegistry,
+                    relevance: &RelevanceHistory,
+                    viewer: Option<RawComponentHandle>,
+                    entities: &HashMap<RawComponentHandle, EntityComponent>,
+                    sequence: u32,
+                    acked: Option<u32>,
+                    interval: f64) -> Vec<PendingUpdate> {
+        // Anything sent in a packet that's been acked has arrived,
+        // since we keep resending it until then.
+        let delivered: Vec<RawComponentHandle> = self.pending.iter()
+            .filter(|&(_, sent)| match (*sent, acked) {
+                (Some(seq), Some(acked)) => (acked - seq) as i32 >= 0,
+                _ => false
+            })
+            .map(|(&owner, _)| owner)
+            .collect();
+        for owner in delivered.iter() {
+            self.pending.remove(owner);
+        }
+        // Irrelevant entities get destroyed (and resent in full when they come back).
+        let gone: Vec<RawComponentHandle> = self.pending.keys()
+            .filter(|owner| !relevance.is_relevant(0, *owner))
+            .map(|&owner| owner)
+            .collect();
+        for owner in gone.iter() {
+            self.pending.remove(owner);
+        }
+        let gone: Vec<RawComponentHandle> = self.priorities.keys()
+            .filter(|owner| !entities.contains_key(*owner))
+            .map(|&owner| owner)
+            .collect();
+        for owner in gone.iter() {
+            self.priorities.remove(owner);
+        }
+
+        // Whatever the client might be missing has to be resent in full.
+        let resend: HashSet<RawComponentHandle> = self.pending.keys().map(|&owner| owner).collect();
+        let mut seen = HashSet::new();
+        let mut by_owner: HashMap<RawComponentHandle, Vec<PendingUpdate>> = HashMap::new();
+        let mut destroys = Vec::new();
+        for update in updates.into_iter().chain(registry.full_state(&resend).into_iter()) {
+            if !seen.insert((update.net_id, update.target())) {
+                continue;
+            }
+            if update.is_destroy() {
+                destroys.push(update);
+                continue;
+            }
+            if !by_owner.contains_key(&update.owner) {
+                by_owner.insert(update.owner, Vec::new());
+            }
+            by_owner.find_mut(&update.owner).unwrap().push(update);
+        }
+
+        let eye = viewer.and_then(|viewer| entities.find(&viewer)).map(|ent| ent.pos).unwrap_or(Point3::new(0., 0., 0.));
+        let mut candidates: Vec<(f32, RawComponentHandle)> = Vec::new();
+        for (&owner, _) in by_owner.iter() {
+            let importance = if Some(owner) == viewer {
+                SELF_IMPORTANCE
+            } else {
+                match entities.find(&owner) {
+                    Some(ent) => archetype_importance(ent.archetype) / (1. + ent.pos.sub_p(&eye).length() / DISTANCE_SCALE),
+                    None => 1.
+                }
+            };
+            let priority = self.priorities.find_copy(&owner).unwrap_or(0.) + importance;
+            self.priorities.insert(owner, priority);
+            candidates.push((priority, owner));
+        }
+        candidates.sort_by(|&(a, _), &(b, _)| b.partial_cmp(&a).unwrap_or(Equal));
+
+        let mut budget = (self.rate as f64 * interval) as uint;
+        let mut sent = destroys;
+        for (i, &(_, owner)) in candidates.iter().enumerate() {
+            let size = by_owner.find(&owner).unwrap().iter().map(|update| update.size()).sum();
+            if i > 0 && size > budget {
+                // try again next time, with a bit more priority
+                self.pending.insert(owner, None);
+                continue;
+            }
+            budget -= size.min(budget);
+            sent.extend(by_owner.pop(&owner).unwrap().into_iter());
+            self.priorities.insert(owner, 0.);
+            // Deferred updates are only done once their full state's been acked.
+            if self.pending.find_copy(&owner) == Some(None) {
+                self.pending.insert(owner, Some(sequence));
+            }
+        }
+        sent
+    }
+
+    /// The packet numbered `sequence` never went out, so anything whose
+    /// full state was in it still has to be sent.
+    pub fn dropped(&mut self, sequence: u32) {
+        for (_, sent) in self.pending.iter_mut() {
+            if *sent == Some(sequence) {
+                *sent = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use cgmath::{Point3, Quaternion};
+    use component::EntityComponent;
+    use component::components::{BLOCK_ARCHETYPE, PLAYER_ARCHETYPE};
+    use network::delta::RelevanceHistory;
+    use network::replication::{ReplicationRegistry, MAX_STATES};
+    use world::World;
+    use super::UpdateScheduler;
+
+    #[test]
+    fn deferred_entities_not_starved() {
+        let mut world = World::new();
+        let rot = Quaternion::new(1., 0., 0., 0.);
+        let viewer = EntityComponent::new(&mut world.entities, PLAYER_ARCHETYPE, Point3::new(0., 0., 0.), rot);
+        for i in range(0u, 10) {
+            EntityComponent::new(&mut world.entities, BLOCK_ARCHETYPE, Point3::new(i as f32 * 10., 0., 0.), rot);
+        }
+        let entities: HashMap<_, _> = world.entities.iter().map(|(h, e)| (h.to_raw(), e.clone())).collect();
+
+        let mut registry = ReplicationRegistry::with_default_components();
+        let mut relevance = RelevanceHistory::new(MAX_STATES);
+        // only enough room for about one entity per packet
+        let mut scheduler = UpdateScheduler::new(150);
+        let mut received = HashMap::new();
+
+        for seq in range(0u32, 40) {
+            registry.add_state(&world);
+            relevance.push(entities.keys().map(|&h| h).collect());
+            // the client acks everything straight away
+            let updates = registry.collect_updates(1, &relevance);
+            let sent = scheduler.schedule(updates, &registry, &relevance, Some(viewer.to_raw()),
+                                          &entities, seq, if seq > 0 { Some(seq - 1) } else { None }, 1.);
+            if seq == 0 {
+                assert_eq!(sent.iter().next().unwrap().owner, viewer.to_raw());
+            }
+            for update in sent.iter() {
+                received.insert(update.owner, seq);
+            }
+        }
+        // everybody got through eventually
+        assert_eq!(received.len(), entities.len());
+    }
+
+    #[test]
+    fn dropped_packets_not_delivered() {
+        let mut world = World::new();
+        let rot = Quaternion::new(1., 0., 0., 0.);
+        let viewer = EntityComponent::new(&mut world.entities, PLAYER_ARCHETYPE, Point3::new(0., 0., 0.), rot);
+        let block = EntityComponent::new(&mut world.entities, BLOCK_ARCHETYPE, Point3::new(10., 0., 0.), rot);
+        let entities: HashMap<_, _> = world.entities.iter().map(|(h, e)| (h.to_raw(), e.clone())).collect();
+
+        let mut registry = ReplicationRegistry::with_default_components();
+        let mut relevance = RelevanceHistory::new(MAX_STATES);
+        let mut scheduler = UpdateScheduler::new(150);
+        let mut sends_block = |scheduler: &mut UpdateScheduler, seq: u32, acked: Option<u32>| {
+            registry.add_state(&world);
+            relevance.push(entities.keys().map(|&h| h).collect());
+            let updates = registry.collect_updates(1, &relevance);
+            scheduler.schedule(updates, &registry, &relevance, Some(viewer.to_raw()), &entities, seq, acked, 1.)
+                .iter().any(|update| update.owner == block.to_raw())
+        };
+
+        // the block doesn't fit at first, then goes out in packet 1...
+        assert!(!sends_block(&mut scheduler, 0, None));
+        assert!(sends_block(&mut scheduler, 1, Some(0)));
+        // ...which was too big to send
+        scheduler.dropped(1);
+        // so the next packet 1 being acked doesn't mean the block arrived
+        assert!(sends_block(&mut scheduler, 2, Some(1)));
+    }
+}
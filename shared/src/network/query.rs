@@ -0,0 +1,159 @@
+//! Asking servers about themselves, without connecting.
+//!
+//! Queries are out-of-band, so they can go to any number of servers at
+//! once, including everybody on the LAN by broadcasting. Replies echo the
+//! time the query was sent, so the ping can be worked out without keeping
+//! track of which queries are outstanding.
+
+use std::cmp::Equal;
+use std::collections::HashSet;
+use std::io::{IoResult, TimedOut};
+use std::io::net::ip::{Ipv4Addr, SocketAddr};
+use super::{decode_server_msg, encode_client_msg, Info, InfoRequest, InfoRequestPacket, ServerInfoPacket, PROTOCOL_VERSION};
+use super::channel::{is_out_of_band, recv_out_of_band, send_out_of_band};
+use super::transport::Transport;
+
+/// A server that answered a query.
+#[deriving(Clone, Show)]
+pub struct ServerListing {
+    pub addr: SocketAddr,
+    pub info: ServerInfoPacket,
+    /// Round trip time, in seconds.
+    pub ping: f64
+}
+
+impl ServerListing {
+    /// Whether we could actually connect to it.
+    pub fn is_compatible(&self) -> bool {
+        self.info.protocol_version == PROTOCOL_VERSION
+    }
+}
+
+/// Where to send queries to reach every server on the LAN on `port`.
+/// The socket they're sent on has to have broadcast turned on.
+pub fn lan_broadcast_addr(port: u16) -> SocketAddr {
+    SocketAddr { ip: Ipv4Addr(255, 255, 255, 255), port: port }
+}
+
+/// Asks the server (or servers, if it's a broadcast address) at `addr` about itself.
+pub fn send_query(transport: &mut Transport, addr: SocketAddr) -> IoResult<()> {
+    let msg = InfoRequest(InfoRequestPacket { time: ::time::precise_time_s(), token: 0 });
+    let datagram = try!(send_out_of_band(encode_client_msg(&msg).as_slice()));
+    transport.send_to(datagram.as_slice(), addr)
+}
+
+/// Waits `wait` seconds for servers to answer queries, and lists
+/// everybody who did, fastest first.
+pub fn collect_replies(transport: &mut Transport, wait: f64) -> IoResult<Vec<ServerListing>> {
+    let deadline = ::time::precise_time_s() + wait;
+    let mut listings: Vec<ServerListing> = Vec::new();
+    let mut seen = HashSet::new();
+    let mut buf = [0u8, ..2048];
+
+    loop {
+        let remaining = deadline - ::time::precise_time_s();
+        if remaining <= 0. {
+            break;
+        }
+        transport.set_read_timeout(Some((remaining * 1000.) as u64));
+        let (len, from) = match transport.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(ref e) if e.kind == TimedOut => break,
+            Err(e) => return Err(e)
+        };
+
+        let datagram = buf.slice_to(len);
+        if !is_out_of_band(datagram) {
+            continue;
+        }
+        let msg = recv_out_of_band(datagram).ok().and_then(|data| decode_server_msg(data.as_slice()));
+        match msg {
+            // Broadcasts can get the same server's reply more than once.
+            Some(Info(info)) => if seen.insert(from) {
+                listings.push(ServerListing {
+                    addr: from,
+                    ping: ::time::precise_time_s() - info.time,
+                    info: info
+                });
+            },
+            _ => ()
+        }
+    }
+
+    listings.sort_by(|a, b| a.ping.partial_cmp(&b.ping).unwrap_or(Equal));
+    Ok(listings)
+}
+
+#[cfg(test)]
+mod test {
+    use network::{decode_client_msg, encode_server_msg, Info, InfoRequest, ServerInfoPacket, PROTOCOL_VERSION};
+    use network::channel::{recv_out_of_band, send_out_of_band};
+    use network::transport::{MemoryNetwork, Transport};
+    use std::io::net::ip::{Ipv4Addr, SocketAddr};
+    use super::{collect_replies, lan_broadcast_addr, send_query};
+
+    #[test]
+    fn query_roundtrip() {
+        let network = MemoryNetwork::new();
+        let mut client = network.bind_any();
+        let mut server = network.bind_any();
+        server.set_read_timeout(Some(0));
+        let server_addr = server.local_addr().unwrap();
+
+        send_query(&mut client, server_addr).unwrap();
+
+        let mut buf = [0u8, ..2048];
+        let (len, from) = server.recv_from(&mut buf).unwrap();
+        let request = match decode_client_msg(recv_out_of_band(buf.slice_to(len)).unwrap().as_slice()) {
+            Some(InfoRequest(request)) => request,
+            _ => fail!("Expected an info request")
+        };
+        let reply = Info(ServerInfoPacket {
+            time: request.time,
+            token: request.token,
+            protocol_version: PROTOCOL_VERSION,
+            name: "test server".to_string(),
+            map: "nowhere".to_string(),
+            players: 3,
+            max_players: 32
+        });
+        let datagram = send_out_of_band(encode_server_msg(&reply).as_slice()).unwrap();
+        // Answer twice, like a server reached two ways by a broadcast might.
+        server.send_to(datagram.as_slice(), from).unwrap();
+        server.send_to(datagram.as_slice(), from).unwrap();
+
+        let listings = collect_replies(&mut client, 0.05).unwrap();
+        assert_eq!(listings.len(), 1);
+        assert_eq!(listings[0].addr, server_addr);
+        assert_eq!(listings[0].info.name.as_slice(), "test server");
+        assert_eq!(listings[0].info.players, 3);
+        assert!(listings[0].is_compatible());
+        assert!(listings[0].ping >= 0.);
+    }
+
+    #[test]
+    fn broadcast_reaches_the_lan() {
+        let network = MemoryNetwork::new();
+        let mut client = network.bind_any();
+        let mut servers = Vec::from_fn(2, |i| {
+            let mut server = network.bind(SocketAddr { ip: Ipv4Addr(10, 0, 0, i as u8 + 1), port: 18295 }).unwrap();
+            server.set_read_timeout(Some(0));
+            server
+        });
+        let mut elsewhere = network.bind(SocketAddr { ip: Ipv4Addr(10, 0, 0, 3), port: 18296 }).unwrap();
+        elsewhere.set_read_timeout(Some(0));
+
+        client.set_broadcast(true).unwrap();
+        send_query(&mut client, lan_broadcast_addr(18295)).unwrap();
+
+        let mut buf = [0u8, ..2048];
+        for server in servers.iter_mut() {
+            let (len, _) = server.recv_from(&mut buf).unwrap();
+            match decode_client_msg(recv_out_of_band(buf.slice_to(len)).unwrap().as_slice()) {
+                Some(InfoRequest(_)) => (),
+                _ => fail!("Expected an info request")
+            }
+        }
+        assert!(elsewhere.recv_from(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,418 @@
+//! Server replays: the authoritative world on every tick, plus every
+//! command that was run.
+//!
+//! Unlike a demo, a replay isn't limited to what one client was told
+//! about, so it can be watched from anybody's point of view. Each tick
+//! is stored as a delta from the tick before, with a full keyframe
+//! every so often so playback can seek without starting over. Since the
+//! commands are in there too, a tick can be simulated again and checked
+//! against what the server actually got, to chase down desyncs.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{EndOfFile, IoError, IoResult, InvalidInput};
+use serialize::json;
+use cgmath::{EuclideanVector, Point};
+use component::{ComponentHandle, EntityHandle, RawComponentHandle};
+use playercmd::{ControllableComponent, PlayerCommand};
+use world::{tick, System, TickCommand, World};
+use super::{ComponentTypeUpdate, PROTOCOL_VERSION};
+use super::delta::RelevanceHistory;
+use super::replication::{ReplicationRegistry, MAX_STATES};
+
+static REPLAY_MAGIC: &'static [u8] = b"NMIGPREP";
+
+/// No compressed tick comes anywhere near this big. Anything that claims
+/// to is garbage, and shouldn't get to allocate that much.
+static MAX_TICK_LEN: uint = 16 * 1024 * 1024;
+
+/// How far apart simulated and recorded entities can be before
+/// it counts as a desync. Replicated floats aren't exact.
+pub static DIVERGENCE_TOLERANCE: f32 = 0.001;
+
+#[deriving(Encodable, Decodable)]
+pub struct ReplayTick {
+    pub tick: u64,
+    /// Every command run on this tick, by the entity it controlled.
+    pub commands: Vec<(RawComponentHandle, PlayerCommand)>,
+    /// Changes since the previous tick.
+    pub delta: Vec<ComponentTypeUpdate>,
+    /// The whole world, on ticks that are keyframes.
+    pub keyframe: Option<Vec<ComponentTypeUpdate>>
+}
+
+pub struct ReplayWriter<W> {
+    out: W,
+    /// Everything's relevant to a replay; this just says so to the delta encoders.
+    relevance: RelevanceHistory,
+    keyframe_interval: u64,
+    next_keyframe: Option<u64>
+}
+
+impl<W: Writer> ReplayWriter<W> {
+    /// `keyframe_interval` is in ticks.
+    pub fn new(mut out: W, keyframe_interval: u64) -> IoResult<ReplayWriter<W>> {
+        assert!(keyframe_interval > 0);
+        try!(out.write(REPLAY_MAGIC));
+        try!(out.write_be_u32(PROTOCOL_VERSION));
+        Ok(ReplayWriter {
+            out: out,
+            relevance: RelevanceHistory::new(MAX_STATES),
+            keyframe_interval: keyframe_interval,
+            next_keyframe: None
+        })
+    }
+
+    /// Should be called once a tick, after ReplicationRegistry::add_state,
+    /// with the commands that were run that tick.
+    pub fn record_tick(&mut self, tick: u64, replication: &ReplicationRegistry,
+                       world: &World, commands: &[TickCommand]) -> IoResult<()> {
+        let everything: HashSet<RawComponentHandle> = world.entities.iter().map(|(handle, _)| handle.to_raw()).collect();
+
+        let keyframe = match self.next_keyframe {
+            Some(next) if tick < next => None,
+            _ => {
+                self.next_keyframe = Some(tick + self.keyframe_interval);
+                Some(replication.pack_updates(replication.full_state(&everything)))
+            }
+        };
+        self.relevance.push(everything);
+
+        // Commands for controllables that were removed since (i.e. their
+        // player left) don't matter; their entity's gone too.
+        let commands = commands.iter().filter_map(|&(handle, cmd)| {
+            world.controllables.find(handle).map(|controllable| (controllable.entity.to_raw(), cmd))
+        }).collect();
+
+        let frame = ReplayTick {
+            tick: tick,
+            commands: commands,
+            delta: replication.create_update(1, &self.relevance),
+            keyframe: keyframe
+        };
+        let encoded = json::encode(&frame).into_bytes();
+        let compressed = ::flate::deflate_bytes_zlib(encoded.as_slice()).expect("Compression failed!");
+        try!(self.out.write_be_u32(compressed.len() as u32));
+        try!(self.out.write(compressed.as_slice()));
+        // Servers usually get stopped by killing them, so make sure
+        // most of the replay is on disk by then.
+        if frame.keyframe.is_some() {
+            try!(self.out.flush());
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> IoResult<()> {
+        self.out.flush()
+    }
+
+    /// Stops recording, handing back what was written to.
+    pub fn unwrap(self) -> W {
+        self.out
+    }
+}
+
+fn bad_replay(desc: &'static str) -> IoError {
+    IoError {
+        kind: InvalidInput,
+        desc: desc,
+        detail: None
+    }
+}
+
+/// Reads a whole replay. Replays from other protocol versions are rejected.
+pub fn read_replay<R: Reader>(input: &mut R) -> IoResult<Vec<ReplayTick>> {
+    let magic = try!(input.read_exact(REPLAY_MAGIC.len()));
+    if magic.as_slice() != REPLAY_MAGIC {
+        return Err(bad_replay("Not a replay file"));
+    }
+    if try!(input.read_be_u32()) != PROTOCOL_VERSION {
+        return Err(bad_replay("Replay was recorded with a different protocol version"));
+    }
+
+    let mut ticks = Vec::new();
+    loop {
+        let len = match input.read_be_u32() {
+            Ok(len) => len as uint,
+            Err(IoError { kind: EndOfFile, .. }) => return Ok(ticks),
+            Err(e) => return Err(e)
+        };
+        if len > MAX_TICK_LEN {
+            return Err(bad_replay("Tick in replay is too big"));
+        }
+        // A server that got killed mid-write leaves a partial tick at the end.
+        let data = match input.read_exact(len) {
+            Ok(data) => data,
+            Err(IoError { kind: EndOfFile, .. }) => return Ok(ticks),
+            Err(e) => return Err(e)
+        };
+        let inflated = match ::flate::inflate_bytes_zlib(data.as_slice()) {
+            Some(inflated) => inflated,
+            None => return Err(bad_replay("Corrupt tick in replay"))
+        };
+        let frame = match ::std::str::from_utf8(inflated.as_slice()) {
+            Some(frame) => json::decode(frame).ok(),
+            None => None
+        };
+        match frame {
+            Some(frame) => ticks.push(frame),
+            None => return Err(bad_replay("Corrupt tick in replay"))
+        }
+    }
+}
+
+/// Rebuilds the world tick by tick from a replay.
+pub struct ReplayPlayer {
+    ticks: Vec<ReplayTick>,
+    /// The next tick to apply, as an index into `ticks`.
+    next: uint,
+    world: World,
+    replication: ReplicationRegistry,
+    /// Controllables for commanded entities, by the server's entity handle.
+    /// They aren't replicated, so they're made up as commands need them.
+    controllables: HashMap<RawComponentHandle, ComponentHandle<ControllableComponent>>,
+    /// Entities created (or changed archetype) since take_new_entities was last called.
+    new_entities: Vec<EntityHandle>
+}
+
+impl ReplayPlayer {
+    pub fn new(ticks: Vec<ReplayTick>) -> ReplayPlayer {
+        ReplayPlayer {
+            ticks: ticks,
+            next: 0,
+            world: World::new(),
+            replication: ReplicationRegistry::with_default_components(),
+            controllables: HashMap::new(),
+            new_entities: Vec::new()
+        }
+    }
+
+    /// The first and last ticks in the replay, if it has any.
+    pub fn get_range(&self) -> Option<(u64, u64)> {
+        match (self.ticks.iter().next(), self.ticks.iter().last()) {
+            (Some(first), Some(last)) => Some((first.tick, last.tick)),
+            _ => None
+        }
+    }
+
+    /// Entities that need local components made for them, like the ones
+    /// ReplicationRegistry::apply_update returns. Seeking rebuilds the whole
+    /// world, so afterwards this is every entity, and any old ones are gone.
+    pub fn take_new_entities(&mut self) -> Vec<EntityHandle> {
+        let new_entities = ::std::mem::replace(&mut self.new_entities, Vec::new());
+        new_entities.into_iter().filter(|&handle| self.world.entities.find(handle).is_some()).collect()
+    }
+
+    pub fn get_world(&self) -> &World {
+        &self.world
+    }
+
+    /// The tick the world is currently at, if any have been applied.
+    pub fn current_tick(&self) -> Option<u64> {
+        if self.next == 0 {
+            None
+        } else {
+            Some(self.ticks[self.next - 1].tick)
+        }
+    }
+
+    /// Our handle for an entity the server knew as `server_handle`,
+    /// e.g. a player's, to watch from their point of view.
+    pub fn find_entity(&mut self, server_handle: RawComponentHandle) -> Option<EntityHandle> {
+        self.replication.entity_handles().find_copy(&server_handle)
+    }
+
+    /// Applies the next recorded tick. Returns false at the end of the replay.
+    pub fn step(&mut self) -> bool {
+        if self.next >= self.ticks.len() {
+            return false;
+        }
+        let delta = self.ticks[self.next].delta.clone();
+        let new_entities = self.replication.apply_update(delta, &mut self.world);
+        self.new_entities.extend(new_entities.into_iter());
+        self.next += 1;
+        self.forget_dead();
+        true
+    }
+
+    /// Jumps to the last tick at or before `tick`, starting from the
+    /// nearest keyframe.
+    pub fn seek(&mut self, tick: u64) {
+        let keyframe = self.ticks.iter()
+            .rposition(|frame| frame.tick <= tick && frame.keyframe.is_some());
+        let idx = match keyframe {
+            Some(idx) => idx,
+            None => return
+        };
+
+        self.world = World::new();
+        self.replication = ReplicationRegistry::with_default_components();
+        self.controllables.clear();
+        let state = self.ticks[idx].keyframe.clone().unwrap();
+        self.new_entities = self.replication.apply_update(state, &mut self.world);
+        self.next = idx + 1;
+
+        while self.next < self.ticks.len() && self.ticks[self.next].tick <= tick {
+            self.step();
+        }
+    }
+
+    /// Runs the next tick's commands through `systems` instead of taking
+    /// the recorded result. Compare it with what `step` gives using
+    /// `find_divergence`.
+    pub fn simulate_next(&mut self, systems: &mut [Box<System + 'static>]) -> Option<World> {
+        if self.next >= self.ticks.len() {
+            return None;
+        }
+
+        let mut commands = Vec::new();
+        for &(server_handle, cmd) in self.ticks[self.next].commands.iter() {
+            let entity = match self.replication.entity_handles().find_copy(&server_handle) {
+                Some(entity) => entity,
+                None => continue
+            };
+            if !self.controllables.contains_key(&server_handle) {
+                let controllable = self.world.controllables.add(ControllableComponent::new(entity));
+                self.controllables.insert(server_handle, controllable);
+            }
+            commands.push((*self.controllables.find(&server_handle).unwrap(), cmd));
+        }
+
+        let mut world = self.world.clone();
+        tick(&mut world, systems, commands.as_slice());
+        Some(world)
+    }
+
+    /// Entities that are somewhere else in `simulated` than in the current
+    /// world. Entities only one of them has are ignored, since players
+    /// joining and leaving aren't simulated.
+    pub fn find_divergence(&self, simulated: &World) -> Vec<EntityHandle> {
+        let mut diverged = Vec::new();
+        for (handle, ent) in self.world.entities.iter() {
+            match simulated.entities.find(handle) {
+                Some(other) if ent.pos.sub_p(&other.pos).length() > DIVERGENCE_TOLERANCE => diverged.push(handle),
+                _ => ()
+            }
+        }
+        diverged
+    }
+
+    /// Drops controllables whose entity has been destroyed.
+    fn forget_dead(&mut self) {
+        let dead: Vec<RawComponentHandle> = self.controllables.iter()
+            .filter(|&(_, &handle)| {
+                let entity = self.world.controllables.find(handle).unwrap().entity;
+                self.world.entities.find(entity).is_none()
+            })
+            .map(|(&server_handle, _)| server_handle)
+            .collect();
+        for server_handle in dead.iter() {
+            let handle = self.controllables.pop(server_handle).unwrap();
+            self.world.controllables.remove(handle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufReader, MemWriter};
+    use cgmath::{EuclideanVector, Point, Point3, Quaternion, Vector3};
+    use component::EntityComponent;
+    use component::components::{BLOCK_ARCHETYPE, PLAYER_ARCHETYPE};
+    use network::replication::ReplicationRegistry;
+    use physics::PhysicsComponent;
+    use playercmd::{ControllableComponent, PlayerCommand};
+    use world::{default_systems, tick, World};
+    use network::PROTOCOL_VERSION;
+    use super::{read_replay, ReplayPlayer, ReplayWriter, REPLAY_MAGIC};
+
+    fn record(ticks: u64, keyframe_interval: u64) -> (World, Vec<u8>) {
+        let mut world = World::new();
+        let mut systems = default_systems();
+        let mut replication = ReplicationRegistry::with_default_components();
+        let mut writer = ReplayWriter::new(MemWriter::new(), keyframe_interval).unwrap();
+
+        let player = EntityComponent::new(&mut world.entities, PLAYER_ARCHETYPE, Point3::new(0., 0., 0.), Quaternion::new(1., 0., 0., 0.));
+        let controllable = world.controllables.add(ControllableComponent::new(player));
+        let block = EntityComponent::new(&mut world.entities, BLOCK_ARCHETYPE, Point3::new(5., 0., 0.), Quaternion::new(1., 0., 0., 0.));
+        let mut physical = PhysicsComponent::new(block);
+        physical.velocity = Vector3::new(0., 0., -1.);
+        world.physicals.add(physical);
+
+        for t in range(1, ticks + 1) {
+            let cmd = PlayerCommand {
+                number: t as u32,
+                tick: t,
+                angles: Quaternion::new(1., 0., 0., 0.),
+                movement: Vector3::new(0.05, 0., 0.)
+            };
+            let commands = [(controllable, cmd)];
+            tick(&mut world, systems.as_mut_slice(), commands.as_slice());
+            replication.add_state(&world);
+            writer.record_tick(t, &replication, &world, commands.as_slice()).unwrap();
+        }
+        (world, writer.out.unwrap())
+    }
+
+    fn positions(world: &World) -> Vec<Point3<f32>> {
+        let mut positions: Vec<Point3<f32>> = world.entities.iter().map(|(_, ent)| ent.pos).collect();
+        positions.sort_by(|a, b| a.x.partial_cmp(&b.x).unwrap());
+        positions
+    }
+
+    #[test]
+    fn playback_matches_server() {
+        let (world, bytes) = record(20, 8);
+        let ticks = read_replay(&mut BufReader::new(bytes.as_slice())).unwrap();
+        assert_eq!(ticks.len(), 20);
+
+        let mut player = ReplayPlayer::new(ticks);
+        assert_eq!(player.get_range(), Some((1, 20)));
+        while player.step() {}
+        assert_eq!(player.current_tick(), Some(20));
+        assert_eq!(player.take_new_entities().len(), world.entities.iter().count());
+        assert!(player.take_new_entities().is_empty());
+        assert!(positions(player.get_world()).iter().zip(positions(&world).iter())
+            .all(|(a, b)| a.sub_p(b).length() < 0.001));
+
+        // Seeking backwards starts from a keyframe, and ends up in the same place.
+        player.seek(10);
+        assert_eq!(player.current_tick(), Some(10));
+        // and everything in the rebuilt world is new
+        assert_eq!(player.take_new_entities().len(), player.get_world().entities.iter().count());
+        while player.step() {}
+        assert!(positions(player.get_world()).iter().zip(positions(&world).iter())
+            .all(|(a, b)| a.sub_p(b).length() < 0.001));
+    }
+
+    #[test]
+    fn resimulation_matches_recording() {
+        let (_, bytes) = record(10, 4);
+        let mut player = ReplayPlayer::new(read_replay(&mut BufReader::new(bytes.as_slice())).unwrap());
+        let mut systems = default_systems();
+
+        player.step();
+        loop {
+            let simulated = match player.simulate_next(systems.as_mut_slice()) {
+                Some(world) => world,
+                None => break
+            };
+            player.step();
+            assert!(player.find_divergence(&simulated).is_empty());
+        }
+    }
+
+    #[test]
+    fn not_a_replay() {
+        assert!(read_replay(&mut BufReader::new(b"definitely not a replay")).is_err());
+    }
+
+    #[test]
+    fn huge_tick_rejected() {
+        let mut out = MemWriter::new();
+        out.write(REPLAY_MAGIC).unwrap();
+        out.write_be_u32(PROTOCOL_VERSION).unwrap();
+        out.write_be_u32(0xFFFFFFFF).unwrap();
+        let bytes = out.unwrap();
+        assert!(read_replay(&mut BufReader::new(bytes.as_slice())).is_err());
+    }
+}
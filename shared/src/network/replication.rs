@@ -0,0 +1,502 @@
+//! Replication of arbitrary component types.
+//!
+//! Each replicated component type gets a ComponentReplicator with its own
+//! network ID and DeltaEncoder. To replicate a new type, implement
+//! ComponentReplicator for it and register it on both ends; the packet
+//! format doesn't change.
+
+use std::collections::{HashMap, HashSet};
+use std::io::IoError;
+use serialize::{json, Decodable, Encodable};
+use component::{ComponentHandle, RawComponentHandle, EntityComponent, EntityHandle};
+use component::components::NoHandleEntityComponent;
+use physics::{NoHandlePhysicsComponent, PhysicsComponent};
+use world::World;
+use super::{Change, ComponentTypeUpdate, ComponentUpdate, Destroy};
+use super::delta::{DeltaEncoder, RelevanceHistory};
+use super::protocol::apply_update;
+
+/// How many states each replicator remembers.
+/// Relevance histories should be the same length.
+pub static MAX_STATES: uint = 64;
+
+/// Rough size of an update on the wire, not counting the component itself.
+static UPDATE_OVERHEAD: uint = 32;
+
+/// Maps the server's entity handles to the client's.
+pub type EntityHandleMap = HashMap<RawComponentHandle, EntityHandle>;
+
+/// Server: an update that hasn't been packed into a packet yet,
+/// so it can be prioritized along with the rest of its entity.
+pub struct PendingUpdate {
+    /// The entity this update's component belongs to.
+    pub owner: RawComponentHandle,
+    pub net_id: u16,
+    update: ComponentUpdate<json::Json>
+}
+
+impl PendingUpdate {
+    pub fn is_destroy(&self) -> bool {
+        match self.update.data {
+            Destroy => true,
+            Change(_) => false
+        }
+    }
+
+    /// Which component this update is for.
+    pub fn target(&self) -> RawComponentHandle {
+        self.update.target
+    }
+
+    /// About how many bytes this will take up.
+    pub fn size(&self) -> uint {
+        match self.update.data {
+            Change(ref data) => data.to_string().len() + UPDATE_OVERHEAD,
+            Destroy => UPDATE_OVERHEAD
+        }
+    }
+}
+
+/// Replicates one component type.
+pub trait ComponentReplicator {
+    /// Identifies this component type on the wire.
+    fn net_id(&self) -> u16;
+
+    /// Server: remembers the current state of every component of this type.
+    fn add_state(&mut self, world: &World);
+    /// Server: encodes changes over the last `length` states,
+    /// for components whose entity is relevant to the client.
+    /// Each update comes with the entity it belongs to.
+    fn create_delta(&self, length: u64, relevance: &RelevanceHistory) -> Vec<(RawComponentHandle, ComponentUpdate<json::Json>)>;
+    /// Server: encodes the current state of every component belonging to one of `owners`.
+    fn full_state(&self, owners: &HashSet<RawComponentHandle>) -> Vec<(RawComponentHandle, ComponentUpdate<json::Json>)>;
+
+    /// Client: applies changes from the server.
+    /// Entities created by this, or whose archetype changed, are added to `new_entities`.
+    fn apply(&mut self,
+             updates: Vec<ComponentUpdate<json::Json>>,
+             world: &mut World,
+             entity_handles: &mut EntityHandleMap,
+             new_entities: &mut Vec<EntityHandle>);
+    /// Client: removes replicated components whose entity isn't one of
+    /// `keep`, which are our handles, not the server's.
+    fn retain(&mut self,
+              keep: &HashSet<RawComponentHandle>,
+              world: &mut World,
+              entity_handles: &mut EntityHandleMap);
+}
+
+/// Marshals a component into JSON, to be embedded in an update as it is.
+fn marshal<'a, Marshalled: Encodable<json::Encoder<'a>, IoError>>(comp: &Marshalled) -> json::Json {
+    json::from_str(json::encode(comp).as_slice()).unwrap()
+}
+
+/// Decodes the marshalled components in a set of updates, dropping any that are garbage.
+fn decode_updates<Marshalled: Decodable<json::Decoder, json::DecoderError>>(
+    updates: Vec<ComponentUpdate<json::Json>>) -> Vec<ComponentUpdate<Marshalled>> {
+    updates.into_iter().filter_map(|update| match update.data {
+        Change(data) => match Decodable::decode(&mut json::Decoder::new(data)) {
+            Ok(comp) => Some(ComponentUpdate { target: update.target, data: Change(comp) }),
+            Err(_) => None
+        },
+        Destroy => Some(ComponentUpdate { target: update.target, data: Destroy })
+    }).collect()
+}
+
+pub struct EntityReplicator {
+    encoder: DeltaEncoder<EntityComponent, NoHandleEntityComponent>
+}
+
+impl EntityReplicator {
+    pub fn new() -> EntityReplicator {
+        EntityReplicator { encoder: DeltaEncoder::new(MAX_STATES) }
+    }
+}
+
+impl ComponentReplicator for EntityReplicator {
+    fn net_id(&self) -> u16 { 0 }
+
+    fn add_state(&mut self, world: &World) {
+        self.encoder.add_state(&world.entities, |ent| ent.to_nohandle(), |ent| ent.handle.to_raw());
+    }
+
+    fn create_delta(&self, length: u64, relevance: &RelevanceHistory) -> Vec<(RawComponentHandle, ComponentUpdate<json::Json>)> {
+        self.encoder.create_delta(length, relevance).into_iter().map(|(owner, update)| (owner, ComponentUpdate {
+            target: update.target,
+            data: match update.data {
+                Change(ent) => Change(marshal(&ent)),
+                Destroy => Destroy
+            }
+        })).collect()
+    }
+
+    fn full_state(&self, owners: &HashSet<RawComponentHandle>) -> Vec<(RawComponentHandle, ComponentUpdate<json::Json>)> {
+        self.encoder.full_state(|owner| owners.contains(owner)).into_iter().map(|(owner, update)| (owner, ComponentUpdate {
+            target: update.target,
+            data: match update.data {
+                Change(ent) => Change(marshal(&ent)),
+                Destroy => Destroy
+            }
+        })).collect()
+    }
+
+    fn apply(&mut self,
+             updates: Vec<ComponentUpdate<json::Json>>,
+             world: &mut World,
+             entity_handles: &mut EntityHandleMap,
+             new_entities: &mut Vec<EntityHandle>) {
+        let updates: Vec<ComponentUpdate<NoHandleEntityComponent>> = decode_updates(updates);
+        // Entities that turn into something else need new local components too.
+        for update in updates.iter() {
+            match (&update.data, entity_handles.find_copy(&update.target)) {
+                (&Change(ref e), Some(handle)) => match world.entities.find(handle) {
+                    Some(old) if old.archetype != e.archetype => new_entities.push(handle),
+                    _ => ()
+                },
+                _ => ()
+            }
+        }
+        apply_update(updates.into_iter(), entity_handles, &mut world.entities,
+                     |e, h| EntityComponent::from_nohandle(&e, h),
+                     |e, store| {
+                         let handle = store.add_with_handle(|handle| EntityComponent::from_nohandle(&e, handle));
+                         new_entities.push(handle);
+                         handle
+                     });
+    }
+
+    fn retain(&mut self,
+              keep: &HashSet<RawComponentHandle>,
+              world: &mut World,
+              entity_handles: &mut EntityHandleMap) {
+        let gone: Vec<RawComponentHandle> = entity_handles.iter()
+            .filter(|&(_, handle)| !keep.contains(&handle.to_raw()))
+            .map(|(&target, _)| target)
+            .collect();
+        for target in gone.iter() {
+            let handle = entity_handles.pop(target).unwrap();
+            world.entities.remove(handle);
+        }
+    }
+}
+
+pub struct PhysicsReplicator {
+    encoder: DeltaEncoder<PhysicsComponent, NoHandlePhysicsComponent>,
+    handles: HashMap<RawComponentHandle, ComponentHandle<PhysicsComponent>>
+}
+
+impl PhysicsReplicator {
+    pub fn new() -> PhysicsReplicator {
+        PhysicsReplicator {
+            encoder: DeltaEncoder::new(MAX_STATES),
+            handles: HashMap::new()
+        }
+    }
+}
+
+impl ComponentReplicator for PhysicsReplicator {
+    fn net_id(&self) -> u16 { 1 }
+
+    fn add_state(&mut self, world: &World) {
+        self.encoder.add_state(&world.physicals, |phys| phys.to_nohandle(), |phys| phys.get_entity().to_raw());
+    }
+
+    fn create_delta(&self, length: u64, relevance: &RelevanceHistory) -> Vec<(RawComponentHandle, ComponentUpdate<json::Json>)> {
+        self.encoder.create_delta(length, relevance).into_iter().map(|(owner, update)| (owner, ComponentUpdate {
+            target: update.target,
+            data: match update.data {
+                Change(phys) => Change(marshal(&phys)),
+                Destroy => Destroy
+            }
+        })).collect()
+    }
+
+    fn full_state(&self, owners: &HashSet<RawComponentHandle>) -> Vec<(RawComponentHandle, ComponentUpdate<json::Json>)> {
+        self.encoder.full_state(|owner| owners.contains(owner)).into_iter().map(|(owner, update)| (owner, ComponentUpdate {
+            target: update.target,
+            data: match update.data {
+                Change(phys) => Change(marshal(&phys)),
+                Destroy => Destroy
+            }
+        })).collect()
+    }
+
+    fn apply(&mut self,
+             updates: Vec<ComponentUpdate<json::Json>>,
+             world: &mut World,
+             entity_handles: &mut EntityHandleMap,
+             _: &mut Vec<EntityHandle>) {
+        let updates: Vec<ComponentUpdate<NoHandlePhysicsComponent>> = decode_updates(updates);
+
+        // Physics components for entities we don't know about can't be applied yet.
+        let updates: Vec<ComponentUpdate<NoHandlePhysicsComponent>> = updates.into_iter().filter(|update| match update.data {
+            Change(ref phys) => entity_handles.contains_key(&phys.entity),
+            Destroy => true
+        }).collect();
+
+        apply_update(updates.into_iter(), &mut self.handles, &mut world.physicals,
+                     |p, _| PhysicsComponent::from_nohandle(&p, entity_handles.find_copy(&p.entity).unwrap()),
+                     |p, store| store.add(PhysicsComponent::from_nohandle(&p, entity_handles.find_copy(&p.entity).unwrap())));
+    }
+
+    fn retain(&mut self,
+              keep: &HashSet<RawComponentHandle>,
+              world: &mut World,
+              _: &mut EntityHandleMap) {
+        let gone: Vec<RawComponentHandle> = self.handles.iter()
+            .filter(|&(_, &handle)| match world.physicals.find(handle) {
+                Some(phys) => !keep.contains(&phys.get_entity().to_raw()),
+                None => true
+            })
+            .map(|(&target, _)| target)
+            .collect();
+        for target in gone.iter() {
+            let handle = self.handles.pop(target).unwrap();
+            world.physicals.remove(handle);
+        }
+    }
+}
+
+/// All the component types that get replicated, in the order they're applied.
+/// Entities should come first, since other components refer to them.
+pub struct ReplicationRegistry {
+    replicators: Vec<Box<ComponentReplicator + 'static>>,
+    entity_handles: EntityHandleMap,
+    /// How many states the replicators remember, up to MAX_STATES.
+    states: uint
+}
+
+impl ReplicationRegistry {
+    pub fn new() -> ReplicationRegistry {
+        ReplicationRegistry {
+            replicators: Vec::new(),
+            entity_handles: HashMap::new(),
+            states: 0
+        }
+    }
+
+    /// A registry with every component type the game replicates.
+    pub fn with_default_components() -> ReplicationRegistry {
+        let mut registry = ReplicationRegistry::new();
+        registry.register(box EntityReplicator::new());
+        registry.register(box PhysicsReplicator::new());
+        registry
+    }
+
+    pub fn register(&mut self, replicator: Box<ComponentReplicator + 'static>) {
+        assert!(self.replicators.iter().all(|r| r.net_id() != replicator.net_id()),
+                "Component network ID {} registered twice!", replicator.net_id());
+        self.replicators.push(replicator);
+    }
+
+    /// Client: the mapping from server entity handles to ours.
+    pub fn entity_handles(&mut self) -> &mut EntityHandleMap {
+        &mut self.entity_handles
+    }
+
+    /// Server: snapshots the world.
+    pub fn add_state(&mut self, world: &World) {
+        for replicator in self.replicators.iter_mut() {
+            replicator.add_state(world);
+        }
+        self.states = ::std::cmp::min(self.states + 1, MAX_STATES);
+    }
+
+    /// Server: for updates going back further than we remember, which are
+    /// the full state rather than a delta, every entity the client should
+    /// have. Goes in the UpdatePacket, for the client's retain_entities.
+    pub fn all_entities(&self, length: u64, relevance: &RelevanceHistory) -> Option<Vec<RawComponentHandle>> {
+        if length < self.states as u64 {
+            return None;
+        }
+        Some(relevance.newest().map(|relevant| relevant.iter().map(|&owner| owner).collect()).unwrap_or(Vec::new()))
+    }
+
+    /// Server: the changes over the last `length` snapshots
+    /// that a client with the given relevance history should see.
+    pub fn collect_updates(&self, length: u64, relevance: &RelevanceHistory) -> Vec<PendingUpdate> {
+        let mut pending = Vec::new();
+        for replicator in self.replicators.iter() {
+            let net_id = replicator.net_id();
+            pending.extend(replicator.create_delta(length, relevance).into_iter().map(|(owner, update)| {
+                PendingUpdate { owner: owner, net_id: net_id, update: update }
+            }));
+        }
+        pending
+    }
+
+    /// Server: the current state of every component of the given entities.
+    pub fn full_state(&self, owners: &HashSet<RawComponentHandle>) -> Vec<PendingUpdate> {
+        let mut pending = Vec::new();
+        for replicator in self.replicators.iter() {
+            let net_id = replicator.net_id();
+            pending.extend(replicator.full_state(owners).into_iter().map(|(owner, update)| {
+                PendingUpdate { owner: owner, net_id: net_id, update: update }
+            }));
+        }
+        pending
+    }
+
+    /// Server: groups updates by component type, ready to go in an UpdatePacket.
+    pub fn pack_updates(&self, pending: Vec<PendingUpdate>) -> Vec<ComponentTypeUpdate> {
+        let mut by_type: HashMap<u16, Vec<ComponentUpdate<json::Json>>> = HashMap::new();
+        for update in pending.into_iter() {
+            if !by_type.contains_key(&update.net_id) {
+                by_type.insert(update.net_id, Vec::new());
+            }
+            by_type.find_mut(&update.net_id).unwrap().push(update.update);
+        }
+        self.replicators.iter().filter_map(|replicator| {
+            by_type.pop(&replicator.net_id()).map(|updates| {
+                ComponentTypeUpdate { net_id: replicator.net_id(), updates: updates }
+            })
+        }).collect()
+    }
+
+    /// Server: encodes the changes over the last `length` snapshots
+    /// that a client with the given relevance history should see.
+    pub fn create_update(&self, length: u64, relevance: &RelevanceHistory) -> Vec<ComponentTypeUpdate> {
+        self.pack_updates(self.collect_updates(length, relevance))
+    }
+
+    /// Client: drops every replicated entity, and its components, that isn't
+    /// in `owners` (by the server's handles). Call before applying a full
+    /// update, since there's no telling what's been destroyed since the last one.
+    pub fn retain_entities(&mut self, owners: &[RawComponentHandle], world: &mut World) {
+        let keep: HashSet<RawComponentHandle> = owners.iter()
+            .filter_map(|owner| self.entity_handles.find_copy(owner))
+            .map(|handle| handle.to_raw())
+            .collect();
+        // Entities last, so components can still find theirs.
+        for replicator in self.replicators.iter_mut().rev() {
+            replicator.retain(&keep, world, &mut self.entity_handles);
+        }
+    }
+
+    /// Client: applies changes from the server, returning any entities that
+    /// were created or changed archetype, which need their local components (re)built.
+    pub fn apply_update(&mut self, mut updates: Vec<ComponentTypeUpdate>, world: &mut World) -> Vec<EntityHandle> {
+        let mut new_entities = Vec::new();
+        for replicator in self.replicators.iter_mut() {
+            let net_id = replicator.net_id();
+            match updates.iter().position(|update| update.net_id == net_id) {
+                Some(idx) => {
+                    let update = updates.swap_remove(idx).unwrap();
+                    replicator.apply(update.updates, world, &mut self.entity_handles, &mut new_entities);
+                },
+                None => ()
+            }
+        }
+        for update in updates.iter() {
+            println!("Got updates for unknown component type {}", update.net_id);
+        }
+        new_entities
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cgmath::{Point3, Quaternion, Vector3};
+    use component::EntityComponent;
+    use component::components::{BLOCK_ARCHETYPE, PLAYER_ARCHETYPE};
+    use physics::PhysicsComponent;
+    use network::delta::RelevanceHistory;
+    use world::World;
+    use super::{ReplicationRegistry, MAX_STATES};
+
+    #[test]
+    fn replicates_entities_and_physics() {
+        let mut server_world = World::new();
+        let ent = EntityComponent::new(&mut server_world.entities, BLOCK_ARCHETYPE, Point3::new(1., 2., 3.), Quaternion::new(1., 0., 0., 0.));
+        let mut phys = PhysicsComponent::new(ent);
+        phys.velocity = Vector3::new(0., 0., -9.8);
+        server_world.physicals.add(phys);
+
+        let mut server = ReplicationRegistry::with_default_components();
+        server.add_state(&server_world);
+        let mut relevance = RelevanceHistory::new(1);
+        relevance.push(vec![ent.to_raw()].into_iter().collect());
+        let update = server.create_update(1, &relevance);
+
+        let mut client_world = World::new();
+        let mut client = ReplicationRegistry::with_default_components();
+        let new_entities = client.apply_update(update, &mut client_world);
+
+        assert_eq!(new_entities.len(), 1);
+        let client_ent = client_world.entities.find(new_entities[0]).unwrap();
+        assert_eq!(client_ent.pos, Point3::new(1., 2., 3.));
+        assert_eq!(client_ent.archetype, BLOCK_ARCHETYPE);
+        let (_, client_phys) = client_world.physicals.iter().next().unwrap();
+        assert_eq!(client_phys.velocity, Vector3::new(0., 0., -9.8));
+        assert!(client_phys.get_entity() == new_entities[0]);
+    }
+
+    #[test]
+    fn archetype_changes_reported() {
+        let mut server_world = World::new();
+        let ent = EntityComponent::new(&mut server_world.entities, BLOCK_ARCHETYPE, Point3::new(1., 2., 3.), Quaternion::new(1., 0., 0., 0.));
+        let mut server = ReplicationRegistry::with_default_components();
+        let mut relevance = RelevanceHistory::new(MAX_STATES);
+        let mut client_world = World::new();
+        let mut client = ReplicationRegistry::with_default_components();
+
+        server.add_state(&server_world);
+        relevance.push(vec![ent.to_raw()].into_iter().collect());
+        let created = client.apply_update(server.create_update(1, &relevance), &mut client_world);
+        assert_eq!(created.len(), 1);
+
+        // just moving isn't news
+        server_world.entities.find_mut(ent).unwrap().pos = Point3::new(4., 5., 6.);
+        server.add_state(&server_world);
+        relevance.push(vec![ent.to_raw()].into_iter().collect());
+        assert!(client.apply_update(server.create_update(1, &relevance), &mut client_world).is_empty());
+
+        // but turning into something else is
+        server_world.entities.find_mut(ent).unwrap().archetype = PLAYER_ARCHETYPE;
+        server.add_state(&server_world);
+        relevance.push(vec![ent.to_raw()].into_iter().collect());
+        let changed = client.apply_update(server.create_update(1, &relevance), &mut client_world);
+        assert!(changed == created);
+        assert_eq!(client_world.entities.find(changed[0]).unwrap().archetype, PLAYER_ARCHETYPE);
+    }
+
+    #[test]
+    fn full_updates_drop_what_was_destroyed() {
+        let mut server_world = World::new();
+        let rot = Quaternion::new(1., 0., 0., 0.);
+        let stays = EntityComponent::new(&mut server_world.entities, BLOCK_ARCHETYPE, Point3::new(0., 0., 0.), rot);
+        let goes = EntityComponent::new(&mut server_world.entities, BLOCK_ARCHETYPE, Point3::new(5., 0., 0.), rot);
+        server_world.physicals.add(PhysicsComponent::new(goes));
+        let mut server = ReplicationRegistry::with_default_components();
+        let mut relevance = RelevanceHistory::new(MAX_STATES);
+        let mut client_world = World::new();
+        let mut client = ReplicationRegistry::with_default_components();
+
+        server.add_state(&server_world);
+        relevance.push(server_world.entities.iter().map(|(h, _)| h.to_raw()).collect());
+        client.apply_update(server.create_update(1, &relevance), &mut client_world);
+        assert_eq!(client_world.entities.iter().count(), 2);
+        assert_eq!(client_world.physicals.iter().count(), 1);
+        // a delta from just now isn't full
+        assert!(server.all_entities(1, &relevance).is_none());
+
+        // One gets destroyed while the client's baseline falls out of the window.
+        let phys: Vec<_> = server_world.physicals.iter().map(|(h, _)| h).collect();
+        server_world.physicals.remove(phys[0]);
+        server_world.entities.remove(goes);
+        for _ in range(0u, MAX_STATES + 10) {
+            server.add_state(&server_world);
+            relevance.push(server_world.entities.iter().map(|(h, _)| h.to_raw()).collect());
+        }
+        let length = (MAX_STATES + 11) as u64;
+        let all = server.all_entities(length, &relevance).unwrap();
+        assert!(all == vec![stays.to_raw()]);
+
+        client.retain_entities(all.as_slice(), &mut client_world);
+        client.apply_update(server.create_update(length, &relevance), &mut client_world);
+        assert_eq!(client_world.entities.iter().count(), 1);
+        let (_, left) = client_world.entities.iter().next().unwrap();
+        assert_eq!(left.pos, Point3::new(0., 0., 0.));
+        assert_eq!(client_world.physicals.iter().count(), 0);
+    }
+}
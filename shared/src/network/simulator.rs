@@ -0,0 +1,477 @@
+//! Makes a good connection bad, for testing.
+//!
+//! SimulatedTransport wraps another Transport and holds on to outgoing
+//! datagrams, delivering them late, out of order, twice, or never.
+//! Wrap both ends to make the conditions symmetrical.
+
+use std::collections::HashMap;
+use std::io::IoResult;
+use std::io::net::ip::SocketAddr;
+use std::rand::{Rng, SeedableRng, XorShiftRng};
+use super::transport::Transport;
+
+/// Datagrams that would sit in the bandwidth queue longer than this are dropped,
+/// like a router with a full buffer.
+static MAX_QUEUE_DELAY: f64 = 1.0;
+
+/// How bad the connection is. Times are in seconds, probabilities from 0 to 1.
+#[deriving(Clone, PartialEq, Show)]
+pub struct NetConditions {
+    pub latency: f64,
+    /// Extra random delay, up to this much.
+    pub jitter: f64,
+    pub loss: f64,
+    pub duplicate: f64,
+    /// Chance a datagram gets held back long enough to arrive after later ones.
+    pub reorder: f64,
+    /// Bytes per second to each destination, or 0 for unlimited.
+    /// A server wrapping its socket gives every client a link this fast.
+    pub bandwidth: u32
+}
+
+impl NetConditions {
+    /// A perfect connection.
+    pub fn perfect() -> NetConditions {
+        NetConditions {
+            latency: 0.,
+            jitter: 0.,
+            loss: 0.,
+            duplicate: 0.,
+            reorder: 0.,
+            bandwidth: 0
+        }
+    }
+
+    /// Parses something like "latency=0.1,jitter=0.02,loss=0.05,bandwidth=16384".
+    /// Anything left out is perfect.
+    pub fn parse(spec: &str) -> Option<NetConditions> {
+        let mut conditions = NetConditions::perfect();
+        for setting in spec.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            let parts: Vec<&str> = setting.splitn(1, '=').collect();
+            if parts.len() != 2 {
+                return None;
+            }
+            let (key, value) = (parts[0].trim(), parts[1].trim());
+            match key {
+                "bandwidth" => conditions.bandwidth = match from_str(value) {
+                    Some(value) => value,
+                    None => return None
+                },
+                _ => {
+                    let value: f64 = match from_str(value) {
+                        Some(value) => value,
+                        None => return None
+                    };
+                    match key {
+                        "latency" => conditions.latency = value,
+                        "jitter" => conditions.jitter = value,
+                        "loss" => conditions.loss = value,
+                        "duplicate" => conditions.duplicate = value,
+                        "reorder" => conditions.reorder = value,
+                        _ => return None
+                    }
+                }
+            }
+        }
+        Some(conditions)
+    }
+}
+
+/// Where the simulator gets the time from.
+pub enum SimClock {
+    RealTime,
+    /// Only moves when told to, so tests are repeatable.
+    Manual(f64)
+}
+
+struct Delayed {
+    deliver_at: f64,
+    data: Vec<u8>,
+    addr: SocketAddr
+}
+
+pub struct SimulatedTransport<T> {
+    inner: T,
+    pub conditions: NetConditions,
+    clock: SimClock,
+    rng: XorShiftRng,
+    queue: Vec<Delayed>,
+    /// When the simulated link to each destination will be done
+    /// sending what's already queued for it.
+    link_free_at: HashMap<SocketAddr, f64>,
+    dropped: uint
+}
+
+impl<T: Transport> SimulatedTransport<T> {
+    pub fn new(inner: T, conditions: NetConditions) -> SimulatedTransport<T> {
+        SimulatedTransport::with_clock(inner, conditions, RealTime, ::std::rand::random())
+    }
+
+    /// Uses `clock` for time and `seed` for randomness, so runs can be repeated.
+    pub fn with_clock(inner: T, conditions: NetConditions, clock: SimClock, seed: u32) -> SimulatedTransport<T> {
+        SimulatedTransport {
+            inner: inner,
+            conditions: conditions,
+            clock: clock,
+            // XorShift doesn't like all-zero seeds.
+            rng: SeedableRng::from_seed([seed, 0x193a6754, 0xa8a7d469, 0x97830e05]),
+            queue: Vec::new(),
+            link_free_at: HashMap::new(),
+            dropped: 0
+        }
+    }
+
+    pub fn now(&self) -> f64 {
+        match self.clock {
+            RealTime => ::time::precise_time_s(),
+            Manual(now) => now
+        }
+    }
+
+    /// Moves a manual clock forward. Does nothing to a real one.
+    pub fn advance(&mut self, dt: f64) {
+        match self.clock {
+            Manual(ref mut now) => *now += dt,
+            RealTime => ()
+        }
+    }
+
+    /// How many datagrams we've thrown away, through loss or full queues.
+    pub fn get_dropped(&self) -> uint {
+        self.dropped
+    }
+
+    /// How many datagrams are waiting to be delivered.
+    pub fn get_queued(&self) -> uint {
+        self.queue.len()
+    }
+
+    pub fn get_inner(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Actually sends everything that's due.
+    pub fn flush(&mut self) -> IoResult<()> {
+        let now = self.now();
+        let mut i = 0;
+        let mut due = Vec::new();
+        while i < self.queue.len() {
+            if self.queue[i].deliver_at <= now {
+                due.push(self.queue.swap_remove(i).unwrap());
+            } else {
+                i += 1;
+            }
+        }
+        due.sort_by(|a, b| a.deliver_at.partial_cmp(&b.deliver_at).unwrap_or(Equal));
+        for datagram in due.iter() {
+            try!(self.inner.send_to(datagram.data.as_slice(), datagram.addr));
+        }
+        Ok(())
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0. && self.rng.gen::<f64>() < probability
+    }
+
+    fn enqueue(&mut self, data: &[u8], addr: SocketAddr) {
+        let now = self.now();
+        let mut deliver_at = now + self.conditions.latency;
+
+        if self.conditions.bandwidth > 0 {
+            let free_at = self.link_free_at.find_copy(&addr).unwrap_or(now);
+            let start = if free_at > now { free_at } else { now };
+            if start - now > MAX_QUEUE_DELAY {
+                self.dropped += 1;
+                return;
+            }
+            let free_at = start + data.len() as f64 / self.conditions.bandwidth as f64;
+            self.link_free_at.insert(addr, free_at);
+            deliver_at += free_at - now;
+        }
+        if self.conditions.jitter > 0. {
+            deliver_at += self.rng.gen::<f64>() * self.conditions.jitter;
+        }
+        if self.chance(self.conditions.reorder) {
+            // late enough to land behind whatever's sent next
+            deliver_at += self.conditions.jitter + self.conditions.latency.max(0.01);
+        }
+
+        self.queue.push(Delayed { deliver_at: deliver_at, data: data.to_vec(), addr: addr });
+    }
+}
+
+impl<T: Transport> Transport for SimulatedTransport<T> {
+    fn send_to(&mut self, data: &[u8], addr: SocketAddr) -> IoResult<()> {
+        if self.chance(self.conditions.loss) {
+            self.dropped += 1;
+        } else {
+            self.enqueue(data, addr);
+            if self.chance(self.conditions.duplicate) {
+                self.enqueue(data, addr);
+            }
+        }
+        self.flush()
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> IoResult<(uint, SocketAddr)> {
+        try!(self.flush());
+        self.inner.recv_from(buf)
+    }
+
+    fn set_read_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.inner.set_read_timeout(timeout_ms)
+    }
+
+    fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn set_broadcast(&mut self, broadcast: bool) -> IoResult<()> {
+        self.inner.set_broadcast(broadcast)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::str;
+    use cgmath::{Point3, Quaternion};
+    use serialize::json;
+    use component::EntityComponent;
+    use component::components::BLOCK_ARCHETYPE;
+    use network::ComponentTypeUpdate;
+    use network::channel::NetChannel;
+    use network::delta::RelevanceHistory;
+    use network::replication::{ReplicationRegistry, MAX_STATES};
+    use network::transport::{MemoryNetwork, MemoryTransport, Transport};
+    use world::World;
+    use TICK_LENGTH;
+    use super::{Manual, NetConditions, SimulatedTransport};
+
+    fn bad_link(network: &MemoryNetwork, seed: u32) -> SimulatedTransport<MemoryTransport> {
+        let mut transport = network.bind_any();
+        transport.set_read_timeout(Some(0));
+        SimulatedTransport::with_clock(transport, NetConditions {
+            latency: 0.05,
+            jitter: 0.03,
+            loss: 0.1,
+            duplicate: 0.05,
+            reorder: 0.05,
+            bandwidth: 0
+        }, Manual(0.), seed)
+    }
+
+    fn recv_all<T: Transport>(transport: &mut T) -> Vec<Vec<u8>> {
+        let mut buf = [0u8, ..8192];
+        let mut received = Vec::new();
+        loop {
+            match transport.recv_from(&mut buf) {
+                Ok((len, _)) => received.push(buf.slice_to(len).to_vec()),
+                Err(_) => return received
+            }
+        }
+    }
+
+    #[test]
+    fn parse_conditions() {
+        let conditions = NetConditions::parse("latency=0.1, loss=0.05,bandwidth=16384").unwrap();
+        assert_eq!(conditions.latency, 0.1);
+        assert_eq!(conditions.loss, 0.05);
+        assert_eq!(conditions.bandwidth, 16384);
+        assert_eq!(conditions.jitter, 0.);
+        assert!(NetConditions::parse("latency=slow").is_none());
+        assert!(NetConditions::parse("warp=9").is_none());
+    }
+
+    #[test]
+    fn latency_delays_delivery() {
+        let network = MemoryNetwork::new();
+        let mut tx = SimulatedTransport::with_clock(network.bind_any(), NetConditions {
+            latency: 0.1,
+            .. NetConditions::perfect()
+        }, Manual(0.), 1);
+        let mut rx = network.bind_any();
+        rx.set_read_timeout(Some(0));
+        let addr = rx.local_addr().unwrap();
+
+        tx.send_to(&[1], addr).unwrap();
+        tx.advance(0.05);
+        tx.flush().unwrap();
+        assert_eq!(recv_all(&mut rx).len(), 0);
+        tx.advance(0.06);
+        tx.flush().unwrap();
+        assert_eq!(recv_all(&mut rx).len(), 1);
+    }
+
+    #[test]
+    fn bandwidth_cap() {
+        let network = MemoryNetwork::new();
+        let mut tx = SimulatedTransport::with_clock(network.bind_any(), NetConditions {
+            bandwidth: 1000,
+            .. NetConditions::perfect()
+        }, Manual(0.), 1);
+        let mut rx = network.bind_any();
+        rx.set_read_timeout(Some(0));
+        let addr = rx.local_addr().unwrap();
+
+        // 10 seconds' worth; most of it won't fit in the queue
+        for _ in range(0u, 100) {
+            tx.send_to(Vec::from_elem(100, 0u8).as_slice(), addr).unwrap();
+        }
+        assert!(tx.get_dropped() > 80);
+        tx.advance(0.5);
+        tx.flush().unwrap();
+        let delivered = recv_all(&mut rx).len();
+        assert!(delivered >= 4 && delivered <= 6);
+    }
+
+    #[test]
+    fn bandwidth_per_destination() {
+        let network = MemoryNetwork::new();
+        let mut tx = SimulatedTransport::with_clock(network.bind_any(), NetConditions {
+            bandwidth: 1000,
+            .. NetConditions::perfect()
+        }, Manual(0.), 1);
+        let mut rx1 = network.bind_any();
+        let mut rx2 = network.bind_any();
+        rx1.set_read_timeout(Some(0));
+        rx2.set_read_timeout(Some(0));
+        let (addr1, addr2) = (rx1.local_addr().unwrap(), rx2.local_addr().unwrap());
+
+        // half a second's worth to each; one shared link would take a whole second
+        for _ in range(0u, 5) {
+            tx.send_to(Vec::from_elem(100, 0u8).as_slice(), addr1).unwrap();
+            tx.send_to(Vec::from_elem(100, 0u8).as_slice(), addr2).unwrap();
+        }
+        tx.advance(0.51);
+        tx.flush().unwrap();
+        assert_eq!(recv_all(&mut rx1).len(), 5);
+        assert_eq!(recv_all(&mut rx2).len(), 5);
+    }
+
+    #[test]
+    fn channel_survives_bad_link() {
+        let network = MemoryNetwork::new();
+        let mut a = bad_link(&network, 1);
+        let mut b = bad_link(&network, 2);
+        let b_addr = b.local_addr().unwrap();
+        let mut tx = NetChannel::new();
+        let mut rx = NetChannel::new();
+
+        let mut last_seen = 0u;
+        let mut received = HashSet::new();
+        for i in range(0u, 1000) {
+            let payload = vec![(i % 256) as u8, (i / 256) as u8];
+            for datagram in tx.send_unreliable(payload.as_slice()).unwrap().iter() {
+                a.send_to(datagram.as_slice(), b_addr).unwrap();
+            }
+            a.advance(0.01);
+            b.advance(0.01);
+            a.flush().unwrap();
+            for datagram in recv_all(&mut b).iter() {
+                match rx.recv_unreliable(datagram.as_slice()) {
+                    Ok(Some(packet)) => {
+                        let n = packet[0] as uint + packet[1] as uint * 256;
+                        // never goes backwards, never twice
+                        assert!(received.is_empty() || n > last_seen);
+                        last_seen = n;
+                        received.insert(n);
+                    },
+                    _ => ()
+                }
+            }
+        }
+        // about 10% lost, and plenty more discarded for arriving late
+        assert!(received.len() > 500 && received.len() < 950);
+        assert!(rx.get_counters().out_of_order > 0);
+    }
+
+    /// Both ends of a simulated connection, with a NetChannel each.
+    struct Link {
+        server: SimulatedTransport<MemoryTransport>,
+        client: SimulatedTransport<MemoryTransport>,
+        server_chan: NetChannel,
+        client_chan: NetChannel
+    }
+
+    impl Link {
+        /// Sends the server's next update, and the client's acks.
+        fn step(&mut self, server: &ReplicationRegistry, relevance: &RelevanceHistory,
+                client: &mut ReplicationRegistry, client_world: &mut World) {
+            let client_addr = self.client.local_addr().unwrap();
+            let server_addr = self.server.local_addr().unwrap();
+
+            let length = (self.server_chan.get_outgoing_sequencenr() + 1 - self.server_chan.get_acked_outgoing_sequencenr()) as u64;
+            let packet = json::encode(&server.create_update(length, relevance));
+            for datagram in self.server_chan.send_unreliable(packet.as_bytes()).unwrap().iter() {
+                self.server.send_to(datagram.as_slice(), client_addr).unwrap();
+            }
+
+            for datagram in recv_all(&mut self.client).iter() {
+                match self.client_chan.recv_unreliable(datagram.as_slice()) {
+                    Ok(Some(packet)) => {
+                        let update: Vec<ComponentTypeUpdate> = json::decode(str::from_utf8(packet.as_slice()).unwrap()).unwrap();
+                        client.apply_update(update, client_world);
+                    },
+                    _ => ()
+                }
+            }
+            // something for the server to hear acks from
+            for datagram in self.client_chan.send_unreliable(&[]).unwrap().iter() {
+                self.client.send_to(datagram.as_slice(), server_addr).unwrap();
+            }
+            for datagram in recv_all(&mut self.server).iter() {
+                let _ = self.server_chan.recv_unreliable(datagram.as_slice());
+            }
+
+            self.server.advance(TICK_LENGTH as f64);
+            self.client.advance(TICK_LENGTH as f64);
+        }
+    }
+
+    fn sorted_positions(world: &World) -> Vec<f32> {
+        let mut positions: Vec<f32> = world.entities.iter().map(|(_, e)| e.pos.x * 1000. + e.pos.y).collect();
+        positions.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        positions
+    }
+
+    #[test]
+    fn deltas_converge_over_bad_link() {
+        let network = MemoryNetwork::new();
+        let mut link = Link {
+            server: bad_link(&network, 3),
+            client: bad_link(&network, 4),
+            server_chan: NetChannel::new(),
+            client_chan: NetChannel::new()
+        };
+
+        let mut server_world = World::new();
+        let rot = Quaternion::new(1., 0., 0., 0.);
+        let ents: Vec<_> = range(0u, 5).map(|i| {
+            EntityComponent::new(&mut server_world.entities, BLOCK_ARCHETYPE, Point3::new(i as f32, 0., 0.), rot)
+        }).collect();
+        let mut server = ReplicationRegistry::with_default_components();
+        let mut relevance = RelevanceHistory::new(MAX_STATES);
+        let mut client_world = World::new();
+        let mut client = ReplicationRegistry::with_default_components();
+
+        for tick in range(0u, 600) {
+            // move things around for a while, destroying one halfway through,
+            // then let everything in flight land
+            if tick < 500 {
+                match server_world.entities.find_mut(ents[tick % 5]) {
+                    Some(ent) => ent.pos.y += 1.,
+                    None => ()
+                }
+            }
+            if tick == 250 {
+                server_world.entities.remove(ents[4]);
+            }
+            server.add_state(&server_world);
+            relevance.push(server_world.entities.iter().map(|(h, _)| h.to_raw()).collect());
+            link.step(&server, &relevance, &mut client, &mut client_world);
+        }
+
+        assert_eq!(sorted_positions(&server_world), sorted_positions(&client_world));
+    }
+}
@@ -0,0 +1,259 @@
+//! Ways of getting datagrams from one place to another.
+//!
+//! The game loops only talk to a Transport, so the same code can run
+//! over real UDP, or over in-process channels for tests and listen servers.
+
+use std::collections::HashMap;
+use std::io::{IoError, IoResult, ConnectionRefused, TimedOut};
+use std::io::net::ip::{Ipv4Addr, SocketAddr};
+use std::io::net::udp::UdpSocket;
+use std::sync::{Arc, Mutex};
+
+/// Sends and receives datagrams, like a UDP socket.
+pub trait Transport {
+    fn send_to(&mut self, data: &[u8], addr: SocketAddr) -> IoResult<()>;
+    /// Fails with TimedOut if nothing arrives within the read timeout.
+    fn recv_from(&mut self, buf: &mut [u8]) -> IoResult<(uint, SocketAddr)>;
+    /// In milliseconds. None blocks forever; Some(0) doesn't block at all.
+    fn set_read_timeout(&mut self, timeout_ms: Option<u64>);
+    fn local_addr(&mut self) -> IoResult<SocketAddr>;
+    /// Has to be turned on before sending to a broadcast address.
+    fn set_broadcast(&mut self, broadcast: bool) -> IoResult<()>;
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&mut self, data: &[u8], addr: SocketAddr) -> IoResult<()> {
+        self.send_to(data, addr)
+    }
+    fn recv_from(&mut self, buf: &mut [u8]) -> IoResult<(uint, SocketAddr)> {
+        self.recv_from(buf)
+    }
+    fn set_read_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.set_read_timeout(timeout_ms)
+    }
+    fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        self.socket_name()
+    }
+    fn set_broadcast(&mut self, broadcast: bool) -> IoResult<()> {
+        self.set_broadcast(broadcast)
+    }
+}
+
+fn timed_out() -> IoError {
+    IoError {
+        kind: TimedOut,
+        desc: "Nothing received before the timeout",
+        detail: None
+    }
+}
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+struct Routes {
+    endpoints: HashMap<SocketAddr, Sender<Datagram>>,
+    next_port: u16
+}
+
+/// An in-process network. Every MemoryTransport bound on it can send to the others.
+///
+/// Like UDP, datagrams sent to nobody just disappear, and datagrams sent
+/// to 255.255.255.255 go to everybody bound on that port.
+#[deriving(Clone)]
+pub struct MemoryNetwork {
+    routes: Arc<Mutex<Routes>>
+}
+
+impl MemoryNetwork {
+    pub fn new() -> MemoryNetwork {
+        MemoryNetwork {
+            routes: Arc::new(Mutex::new(Routes {
+                endpoints: HashMap::new(),
+                next_port: 1
+            }))
+        }
+    }
+
+    /// Binds a transport to a specific address.
+    pub fn bind(&self, addr: SocketAddr) -> IoResult<MemoryTransport> {
+        let mut routes = self.routes.lock();
+        if routes.endpoints.contains_key(&addr) {
+            return Err(IoError {
+                kind: ConnectionRefused,
+                desc: "Address already in use",
+                detail: None
+            });
+        }
+        let (tx, rx) = channel();
+        routes.endpoints.insert(addr, tx);
+        Ok(MemoryTransport {
+            addr: addr,
+            incoming: rx,
+            network: self.clone(),
+            read_timeout: None
+        })
+    }
+
+    /// Binds a transport to a fresh address.
+    pub fn bind_any(&self) -> MemoryTransport {
+        loop {
+            let port = {
+                let mut routes = self.routes.lock();
+                let port = routes.next_port;
+                routes.next_port += 1;
+                port
+            };
+            match self.bind(SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: port }) {
+                Ok(transport) => return transport,
+                Err(_) => continue
+            }
+        }
+    }
+}
+
+/// One end of a MemoryNetwork.
+pub struct MemoryTransport {
+    addr: SocketAddr,
+    incoming: Receiver<Datagram>,
+    network: MemoryNetwork,
+    read_timeout: Option<u64>
+}
+
+impl Transport for MemoryTransport {
+    fn send_to(&mut self, data: &[u8], addr: SocketAddr) -> IoResult<()> {
+        let routes = self.network.routes.lock();
+        if addr.ip == Ipv4Addr(255, 255, 255, 255) {
+            for (to, endpoint) in routes.endpoints.iter() {
+                if to.port == addr.port {
+                    let _ = endpoint.send_opt((data.to_vec(), self.addr));
+                }
+            }
+            return Ok(());
+        }
+        match routes.endpoints.find(&addr) {
+            Some(endpoint) => { let _ = endpoint.send_opt((data.to_vec(), self.addr)); },
+            None => ()
+        }
+        Ok(())
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> IoResult<(uint, SocketAddr)> {
+        let (data, from) = match self.read_timeout {
+            None => match self.incoming.recv_opt() {
+                Ok(datagram) => datagram,
+                Err(()) => return Err(timed_out())
+            },
+            Some(timeout) => {
+                let deadline = ::time::precise_time_s() + timeout as f64 / 1000.;
+                let mut received = None;
+                while received.is_none() {
+                    match self.incoming.try_recv() {
+                        Ok(datagram) => received = Some(datagram),
+                        Err(_) if ::time::precise_time_s() >= deadline => return Err(timed_out()),
+                        Err(_) => ::std::io::timer::sleep(::std::time::Duration::milliseconds(1))
+                    }
+                }
+                received.unwrap()
+            }
+        };
+        // Like UDP, whatever doesn't fit is lost.
+        let len = ::std::cmp::min(data.len(), buf.len());
+        ::std::slice::bytes::copy_memory(buf, data.slice_to(len));
+        Ok((len, from))
+    }
+
+    fn set_read_timeout(&mut self, timeout_ms: Option<u64>) {
+        self.read_timeout = timeout_ms;
+    }
+
+    fn local_addr(&mut self) -> IoResult<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn set_broadcast(&mut self, _broadcast: bool) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        self.network.routes.lock().endpoints.remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::TimedOut;
+    use network::channel::NetChannel;
+    use super::{MemoryNetwork, Transport};
+
+    #[test]
+    fn memory_roundtrip() {
+        let network = MemoryNetwork::new();
+        let mut a = network.bind_any();
+        let mut b = network.bind_any();
+        a.set_read_timeout(Some(0));
+        b.set_read_timeout(Some(0));
+        let b_addr = b.local_addr().unwrap();
+
+        a.send_to(&[1, 2, 3], b_addr).unwrap();
+        let mut buf = [0u8, ..16];
+        let (len, from) = b.recv_from(&mut buf).unwrap();
+        assert_eq!(buf.slice_to(len).to_vec(), vec![1u8, 2, 3]);
+        assert_eq!(from, a.local_addr().unwrap());
+
+        match a.recv_from(&mut buf) {
+            Err(e) => assert_eq!(e.kind, TimedOut),
+            Ok(_) => fail!("Got a datagram from nowhere")
+        }
+    }
+
+    #[test]
+    fn channel_over_memory() {
+        let network = MemoryNetwork::new();
+        let mut a = network.bind_any();
+        let mut b = network.bind_any();
+        b.set_read_timeout(Some(0));
+        let b_addr = b.local_addr().unwrap();
+        let mut tx = NetChannel::new();
+        let mut rx = NetChannel::new();
+
+        let payload = Vec::from_fn(5000, |i| i as u8);
+        for datagram in tx.send_unreliable(payload.as_slice()).unwrap().iter() {
+            a.send_to(datagram.as_slice(), b_addr).unwrap();
+        }
+
+        let mut buf = [0u8, ..8192];
+        let mut received = None;
+        loop {
+            match b.recv_from(&mut buf) {
+                Ok((len, _)) => match rx.recv_unreliable(buf.slice_to(len)).unwrap() {
+                    Some(packet) => received = Some(packet),
+                    None => ()
+                },
+                Err(_) => break
+            }
+        }
+        assert_eq!(received, Some(payload));
+    }
+
+    #[test]
+    fn unbound_addresses_dropped() {
+        let network = MemoryNetwork::new();
+        let mut a = network.bind_any();
+        let addr = {
+            let mut b = network.bind_any();
+            b.local_addr().unwrap()
+        };
+        // sending to nobody isn't an error, like UDP
+        assert!(a.send_to(&[1], addr).is_ok());
+        // and the address is free for someone else
+        let mut c = network.bind(addr).unwrap();
+        c.set_read_timeout(Some(0));
+        let mut buf = [0u8, ..16];
+        // which doesn't get anything sent to the old one
+        match c.recv_from(&mut buf) {
+            Err(e) => assert_eq!(e.kind, TimedOut),
+            Ok(_) => fail!("Got a datagram sent before binding")
+        }
+    }
+}
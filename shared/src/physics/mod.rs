@@ -1,9 +1,11 @@
 use cgmath::{Vector, Vector3, Point};
+use component::RawComponentHandle;
 use {ComponentStore, EntityHandle, EntityComponent};
 use TICK_LENGTH;
 
 pub mod collision;
 
+#[deriving(Clone)]
 pub struct PhysicsComponent {
     pub velocity: Vector3<f32>,
     entity: EntityHandle
@@ -15,6 +17,25 @@ impl PhysicsComponent {
             entity: entity
         }
     }
+    pub fn get_entity(&self) -> EntityHandle {
+        self.entity
+    }
+    pub fn to_nohandle(&self) -> NoHandlePhysicsComponent {
+        NoHandlePhysicsComponent { velocity: self.velocity, entity: self.entity.to_raw() }
+    }
+    /// `entity` is the local handle for the entity `p` refers to.
+    pub fn from_nohandle(p: &NoHandlePhysicsComponent, entity: EntityHandle) -> PhysicsComponent {
+        PhysicsComponent {
+            velocity: p.velocity,
+            entity: entity
+        }
+    }
+}
+/// A PhysicsComponent as sent over the network.
+#[deriving(Encodable, Decodable, Clone, PartialEq)]
+pub struct NoHandlePhysicsComponent {
+    pub velocity: Vector3<f32>,
+    pub entity: RawComponentHandle
 }
 
 /// Runs one tick of simulation.
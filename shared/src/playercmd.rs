@@ -1,6 +1,11 @@
 use component::{ComponentStore, EntityComponent, EntityHandle};
-use cgmath::{Point, Vector, Vector3, Quaternion};
+use cgmath::{EuclideanVector, Point, Vector, Vector3, Quaternion};
+use std::num::Float;
 
+/// The furthest a single command may move a player.
+pub static MAX_MOVE_PER_TICK: f32 = 0.1;
+
+#[deriving(Clone)]
 pub struct ControllableComponent {
     pub entity: EntityHandle
     
@@ -20,6 +25,8 @@ impl ControllableComponent {
 /// during the course of a single game tick.
 #[deriving(Encodable, Decodable)]
 pub struct PlayerCommand {
+    /// Goes up by one for every command a client sends.
+    pub number: u32,
     /// The tick the player command is for.
     pub tick: u64,
     pub angles: Quaternion<f32>,
@@ -27,7 +34,56 @@ pub struct PlayerCommand {
     pub movement: Vector3<f32>,
 }
 
+/// Something wrong with a command from a client.
+#[deriving(Clone, PartialEq, Show)]
+pub enum CommandViolation {
+    /// NaN or infinity somewhere in the command.
+    NotFinite,
+    /// Angles that aren't anywhere near a unit quaternion.
+    BadRotation,
+    /// Movement longer than MAX_MOVE_PER_TICK; holds the length.
+    TooFast(f32),
+    /// More commands in one second than there are ticks; holds the count.
+    TooManyCommands(uint)
+}
+
+pub enum SanitizedCommand {
+    /// Nothing wrong with it.
+    Clean(PlayerCommand),
+    /// Something was wrong, but we fixed it.
+    Fixed(PlayerCommand, CommandViolation),
+    /// Unsalvageable.
+    Rejected(CommandViolation)
+}
+
+/// Checks a command from an untrusted client, clamping its movement
+/// and normalizing its angles.
+pub fn sanitize_command(cmd: PlayerCommand) -> SanitizedCommand {
+    let numbers = [cmd.angles.s, cmd.angles.v.x, cmd.angles.v.y, cmd.angles.v.z,
+                   cmd.movement.x, cmd.movement.y, cmd.movement.z];
+    if numbers.iter().any(|n| !n.is_finite()) {
+        return Rejected(NotFinite);
+    }
+
+    let rot_length = cmd.angles.magnitude();
+    if rot_length < 0.5 || rot_length > 1.5 {
+        return Rejected(BadRotation);
+    }
+
+    let mut cmd = cmd;
+    cmd.angles = cmd.angles.normalize();
+
+    let move_length = cmd.movement.length();
+    if move_length > MAX_MOVE_PER_TICK {
+        cmd.movement = cmd.movement.normalize_to(MAX_MOVE_PER_TICK);
+        Fixed(cmd, TooFast(move_length))
+    } else {
+        Clean(cmd)
+    }
+}
+
 /// Runs a player's command for a single game tick.
+/// Commands from clients should go through sanitize_command first.
 pub fn run_command(cmd: PlayerCommand,
                    controllable: &mut ControllableComponent,
                    entities: &mut ComponentStore<EntityComponent>) {
@@ -35,9 +91,63 @@ pub fn run_command(cmd: PlayerCommand,
 
     let ent = entities.find_mut(controllable.entity).unwrap();
 
-    // TODO: validate angles and movoment
     ent.rot = cmd.angles;
     // TODO: collision check. there should be a function in physics::
     // for trying to move by a vector, with collisions.
     ent.pos = ent.pos.add_v(&cmd.angles.rotate_vector(&cmd.movement));
 }
+
+#[cfg(test)]
+mod test {
+    use cgmath::{Quaternion, Vector3};
+    use std::f32;
+    use super::{sanitize_command, PlayerCommand, Clean, Fixed, Rejected};
+    use super::{BadRotation, NotFinite, TooFast, MAX_MOVE_PER_TICK};
+
+    fn cmd(angles: Quaternion<f32>, movement: Vector3<f32>) -> PlayerCommand {
+        PlayerCommand { number: 1, tick: 0, angles: angles, movement: movement }
+    }
+
+    #[test]
+    fn clean_command() {
+        match sanitize_command(cmd(Quaternion::new(1., 0., 0., 0.), Vector3::new(0.05, 0., 0.))) {
+            Clean(_) => (),
+            _ => fail!("Valid command was changed")
+        }
+    }
+
+    #[test]
+    fn movement_clamped() {
+        match sanitize_command(cmd(Quaternion::new(1., 0., 0., 0.), Vector3::new(100., 0., 0.))) {
+            Fixed(fixed, TooFast(length)) => {
+                assert_eq!(length, 100.);
+                assert!(fixed.movement.x <= MAX_MOVE_PER_TICK + 0.0001);
+            },
+            _ => fail!("Speedhack wasn't caught")
+        }
+    }
+
+    #[test]
+    fn rotation_normalized() {
+        match sanitize_command(cmd(Quaternion::new(1.2, 0., 0., 0.), Vector3::new(0., 0., 0.))) {
+            Clean(fixed) => assert!((fixed.angles.s - 1.).abs() < 0.0001),
+            _ => fail!("Slightly-off rotation wasn't normalized")
+        }
+        match sanitize_command(cmd(Quaternion::new(0., 0., 0., 0.), Vector3::new(0., 0., 0.))) {
+            Rejected(BadRotation) => (),
+            _ => fail!("Zero quaternion wasn't rejected")
+        }
+    }
+
+    #[test]
+    fn nans_rejected() {
+        match sanitize_command(cmd(Quaternion::new(1., 0., 0., 0.), Vector3::new(f32::NAN, 0., 0.))) {
+            Rejected(NotFinite) => (),
+            _ => fail!("NaN movement wasn't rejected")
+        }
+        match sanitize_command(cmd(Quaternion::new(1., f32::INFINITY, 0., 0.), Vector3::new(0., 0., 0.))) {
+            Rejected(NotFinite) => (),
+            _ => fail!("Infinite angles weren't rejected")
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! The world, and the systems that simulate it.
+//!
+//! Everything that runs here runs identically on the server and, through
+//! prediction, on the client. So anything the local player can affect
+//! should be a System.
+
+use component::{ComponentHandle, ComponentStore, EntityComponent};
+use physics::{simulate_tick, PhysicsComponent};
+use playercmd::{run_command, ControllableComponent, PlayerCommand};
+
+/// All the simulated state of a game.
+pub struct World {
+    pub entities: ComponentStore<EntityComponent>,
+    pub controllables: ComponentStore<ControllableComponent>,
+    pub physicals: ComponentStore<PhysicsComponent>
+}
+
+impl World {
+    pub fn new() -> World {
+        World {
+            entities: ComponentStore::new(),
+            controllables: ComponentStore::new(),
+            physicals: ComponentStore::new()
+        }
+    }
+}
+
+impl Clone for World {
+    fn clone(&self) -> World {
+        World {
+            entities: self.entities.clone(),
+            controllables: self.controllables.clone(),
+            physicals: self.physicals.clone()
+        }
+    }
+
+    fn clone_from(&mut self, source: &World) {
+        self.entities.clone_from(&source.entities);
+        self.controllables.clone_from(&source.controllables);
+        self.physicals.clone_from(&source.physicals);
+    }
+}
+
+/// A command, and what it's controlling.
+pub type TickCommand = (ComponentHandle<ControllableComponent>, PlayerCommand);
+
+/// A piece of the simulation that runs once per tick.
+pub trait System {
+    fn run(&mut self, world: &mut World, commands: &[TickCommand]);
+}
+
+/// Moves controllable entities according to players' commands.
+pub struct MovementSystem;
+
+impl System for MovementSystem {
+    fn run(&mut self, world: &mut World, commands: &[TickCommand]) {
+        for &(handle, cmd) in commands.iter() {
+            match world.controllables.find_mut(handle) {
+                Some(controllable) => run_command(cmd, controllable, &mut world.entities),
+                None => ()
+            }
+        }
+    }
+}
+
+/// Moves physical entities according to their velocity.
+pub struct PhysicsSystem;
+
+impl System for PhysicsSystem {
+    fn run(&mut self, world: &mut World, _: &[TickCommand]) {
+        simulate_tick(&mut world.physicals, &mut world.entities);
+    }
+}
+
+/// The systems every game runs, in the order they run in.
+pub fn default_systems() -> Vec<Box<System + 'static>> {
+    vec![box MovementSystem as Box<System>, box PhysicsSystem as Box<System>]
+}
+
+/// Runs one tick of every system.
+pub fn tick(world: &mut World, systems: &mut [Box<System + 'static>], commands: &[TickCommand]) {
+    for system in systems.iter_mut() {
+        system.run(world, commands);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cgmath::{Point3, Quaternion, Vector3};
+    use component::EntityComponent;
+    use component::components::{BLOCK_ARCHETYPE, PLAYER_ARCHETYPE};
+    use physics::PhysicsComponent;
+    use playercmd::{ControllableComponent, PlayerCommand};
+    use super::{default_systems, tick, World};
+
+    #[test]
+    fn replay_is_deterministic() {
+        let mut world = World::new();
+        let player = EntityComponent::new(&mut world.entities, PLAYER_ARCHETYPE, Point3::new(0., 0., 0.), Quaternion::new(1., 0., 0., 0.));
+        let controllable = world.controllables.add(ControllableComponent::new(player));
+        let crate_ent = EntityComponent::new(&mut world.entities, BLOCK_ARCHETYPE, Point3::new(5., 0., 0.), Quaternion::new(1., 0., 0., 0.));
+        let mut physical = PhysicsComponent::new(crate_ent);
+        physical.velocity = Vector3::new(0., 0., -1.);
+        world.physicals.add(physical);
+
+        let snapshot = world.clone();
+        let mut systems = default_systems();
+
+        let cmd = PlayerCommand {
+            number: 1,
+            tick: 0,
+            angles: Quaternion::new(1., 0., 0., 0.),
+            movement: Vector3::new(0.05, 0., 0.)
+        };
+        for _ in range(0u, 10) {
+            tick(&mut world, systems.as_mut_slice(), &[(controllable, cmd)]);
+        }
+
+        let mut replayed = snapshot.clone();
+        for _ in range(0u, 10) {
+            tick(&mut replayed, systems.as_mut_slice(), &[(controllable, cmd)]);
+        }
+
+        for &ent in [player, crate_ent].iter() {
+            assert_eq!(world.entities.find(ent).unwrap().pos, replayed.entities.find(ent).unwrap().pos);
+        }
+        assert!(world.entities.find(crate_ent).unwrap().pos.z < 0.);
+    }
+}